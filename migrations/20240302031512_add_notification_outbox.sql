@@ -0,0 +1,22 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS notification_outbox (
+    id VARCHAR NOT NULL PRIMARY KEY,
+    user_id VARCHAR NOT NULL,
+    title VARCHAR NOT NULL,
+    body VARCHAR NOT NULL,
+    data JSONB NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'Pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error VARCHAR,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_notification_outbox_due
+    ON notification_outbox (next_attempt_at) WHERE status = 'Pending';
+
+CREATE OR REPLACE VIEW notification_dead_letters AS
+    SELECT id, user_id, title, body, data, attempts, last_error, created_at
+    FROM notification_outbox
+    WHERE status = 'Dead';
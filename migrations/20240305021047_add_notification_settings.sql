@@ -0,0 +1,17 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS notification_settings (
+    user_id VARCHAR NOT NULL PRIMARY KEY,
+    muted BOOLEAN NOT NULL DEFAULT false,
+    dnd_start TIME,
+    dnd_end TIME,
+    time_zone VARCHAR NOT NULL DEFAULT 'UTC',
+    dnd_allow_favorites BOOLEAN NOT NULL DEFAULT true
+);
+
+CREATE TABLE IF NOT EXISTS peer_notification_settings (
+    user_id VARCHAR NOT NULL,
+    peer_id VARCHAR NOT NULL,
+    muted_until TIMESTAMP WITH TIME ZONE,
+    favorite BOOLEAN NOT NULL DEFAULT false,
+    PRIMARY KEY (user_id, peer_id)
+);
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE notification_settings ADD COLUMN IF NOT EXISTS locale VARCHAR NOT NULL DEFAULT 'en';
+ALTER TABLE notification_settings ADD COLUMN IF NOT EXISTS hide_previews BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE notification_outbox ADD COLUMN IF NOT EXISTS collapse_key VARCHAR;
+ALTER TABLE notification_outbox ADD COLUMN IF NOT EXISTS thread_id VARCHAR;
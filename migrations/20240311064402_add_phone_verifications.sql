@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS phone_verifications (
+    phone VARCHAR NOT NULL,
+    purpose VARCHAR NOT NULL,
+    code_hash VARCHAR NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    sent_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (phone, purpose)
+);
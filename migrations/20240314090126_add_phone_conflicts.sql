@@ -0,0 +1,11 @@
+-- Add migration script here
+-- Phones that could not be normalized to E.164 on startup, either because
+-- they are invalid or because another account already has the normalized
+-- number. These accounts keep their old phone until resolved by hand.
+CREATE TABLE IF NOT EXISTS phone_conflicts (
+    user_id VARCHAR NOT NULL PRIMARY KEY,
+    phone VARCHAR NOT NULL,
+    normalized VARCHAR,
+    conflicting_user_id VARCHAR,
+    detected_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS login_sessions (
+    id VARCHAR NOT NULL PRIMARY KEY,
+    user_id VARCHAR NOT NULL,
+    key VARCHAR NOT NULL UNIQUE,
+    token_hash VARCHAR UNIQUE,
+    device_name VARCHAR,
+    platform VARCHAR,
+    ip VARCHAR,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_sessions_user_id ON login_sessions (user_id);
+
+INSERT INTO login_sessions (id, user_id, key)
+    SELECT uuid_generate_v4()::VARCHAR, id, session_key FROM users WHERE session_key IS NOT NULL;
+
+ALTER TABLE users DROP COLUMN session_key;
@@ -0,0 +1,18 @@
+-- Add migration script here
+ALTER TABLE login_sessions ADD COLUMN key_expires_at TIMESTAMP WITH TIME ZONE;
+
+-- sessions from before have no refresh token, give them a day before they
+-- have to log in again
+UPDATE login_sessions SET key_expires_at = now() + INTERVAL '1 day';
+
+ALTER TABLE login_sessions ALTER COLUMN key_expires_at SET NOT NULL;
+
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token_hash VARCHAR NOT NULL PRIMARY KEY,
+    session_id VARCHAR NOT NULL REFERENCES login_sessions (id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_session_id ON refresh_tokens (session_id);
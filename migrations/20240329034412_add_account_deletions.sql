@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS account_deletions (
+    user_id VARCHAR NOT NULL PRIMARY KEY,
+    requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    delete_after TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_account_deletions_delete_after ON account_deletions (delete_after);
@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS data_exports (
+    id VARCHAR NOT NULL PRIMARY KEY,
+    user_id VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'Pending',
+    requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    claimed_at TIMESTAMP WITH TIME ZONE,
+    finished_at TIMESTAMP WITH TIME ZONE,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    archive VARCHAR,
+    error VARCHAR
+);
+
+-- at most one queued or running export per user
+CREATE UNIQUE INDEX IF NOT EXISTS idx_data_exports_active ON data_exports (user_id) WHERE status IN ('Pending', 'Running');
+CREATE INDEX IF NOT EXISTS idx_data_exports_user_id ON data_exports (user_id);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS friend_metadata (
+    owner_id VARCHAR NOT NULL,
+    friend_id VARCHAR NOT NULL,
+    nickname VARCHAR,
+    labels VARCHAR[] NOT NULL DEFAULT '{}',
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    PRIMARY KEY (owner_id, friend_id)
+);
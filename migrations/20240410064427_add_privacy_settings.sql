@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS privacy_settings (
+    user_id VARCHAR NOT NULL PRIMARY KEY,
+    contact_policy VARCHAR NOT NULL DEFAULT 'FriendsOnly'
+);
+
+CREATE TABLE IF NOT EXISTS message_requests (
+    user_id VARCHAR NOT NULL,
+    peer_id VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'Pending',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, peer_id)
+);
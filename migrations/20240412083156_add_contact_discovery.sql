@@ -0,0 +1,22 @@
+-- Add migration script here
+ALTER TABLE privacy_settings ADD COLUMN IF NOT EXISTS discoverable_by_phone BOOLEAN NOT NULL DEFAULT true;
+
+CREATE TABLE IF NOT EXISTS contact_salts (
+    id VARCHAR NOT NULL PRIMARY KEY,
+    salt VARCHAR NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- the hashes of the phones as they were when the salt was created
+CREATE TABLE IF NOT EXISTS phone_hashes (
+    salt_id VARCHAR NOT NULL REFERENCES contact_salts (id) ON DELETE CASCADE,
+    hash VARCHAR NOT NULL,
+    user_id VARCHAR NOT NULL,
+    PRIMARY KEY (salt_id, hash, user_id)
+);
+
+-- users who signed up or changed their phone since a salt was created are
+-- hashed on the fly
+CREATE INDEX IF NOT EXISTS idx_users_updated_at ON users (updated_at);
+CREATE INDEX IF NOT EXISTS idx_phone_hashes_user_id ON phone_hashes (user_id);
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- the requests and friendships of a user in either direction
+CREATE INDEX IF NOT EXISTS idx_friend_requests_from_status ON friend_requests ("from", status, "to");
+CREATE INDEX IF NOT EXISTS idx_friend_requests_to_status ON friend_requests ("to", status, "from");
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- the latest message between two users, for sorting friends by recency
+CREATE INDEX IF NOT EXISTS idx_messages_from_to_sent_at ON messages ("from", "to", sent_at);
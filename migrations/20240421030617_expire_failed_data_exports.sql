@@ -0,0 +1,4 @@
+-- Add migration script here
+-- failed exports used to be kept forever, they now expire like ready ones
+UPDATE data_exports SET expires_at = finished_at + INTERVAL '7 days'
+WHERE status = 'Failed' AND expires_at IS NULL;
@@ -0,0 +1,22 @@
+use crate::core::error::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockedUser {
+    pub id: String,
+    pub phone: String,
+    pub display_name: Option<String>,
+    pub blocked_at: DateTime<Utc>,
+}
+
+pub trait BlockStore {
+    /// Blocks `blocked` for `blocker` and drops the friend requests
+    /// `blocked` sent them. Blocking twice is a no-op.
+    async fn block(&self, blocker: &str, blocked: &str) -> Result<()>;
+    /// Returns whether `blocked` was blocked.
+    async fn unblock(&self, blocker: &str, blocked: &str) -> Result<bool>;
+    async fn blocked_users(&self, blocker: &str) -> Result<Vec<BlockedUser>>;
+    /// Whether either user blocked the other.
+    async fn is_blocked(&self, user_id: &str, other_id: &str) -> Result<bool>;
+}
@@ -0,0 +1,71 @@
+use crate::core::{error::Result, repository::User};
+use crate::utils::sha256_hex;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// Number of hex digits of the SHA-256 digest clients send for a phone.
+pub const CONTACT_HASH_LEN: usize = 16;
+
+/// What clients hash the phones of their contacts with. Salts are rotated,
+/// so that uploaded hashes can't be matched against the users for long.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContactSalt {
+    pub id: String,
+    pub salt: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A registered user among the uploaded contacts.
+#[derive(Clone, Serialize)]
+pub(crate) struct DiscoveredContact {
+    pub hash: String,
+    #[serde(flatten)]
+    pub user: User,
+}
+
+/// The first `CONTACT_HASH_LEN` hex digits of the SHA-256 digest of the salt
+/// followed by the normalized phone.
+pub fn contact_hash(salt: &str, phone: &str) -> String {
+    let mut hash = sha256_hex(&format!("{}{}", salt, phone));
+    hash.truncate(CONTACT_HASH_LEN);
+    hash
+}
+
+pub trait ContactStore {
+    /// The latest salt, which clients should hash with.
+    async fn current_contact_salt(&self) -> Result<Option<ContactSalt>>;
+    /// The salt unless it expired.
+    async fn contact_salt(&self, id: &str) -> Result<Option<ContactSalt>>;
+    /// Adds a salt valid for `ttl` and hashes the phones of all users with
+    /// it.
+    async fn add_contact_salt(
+        &self,
+        salt: &str,
+        ttl: Duration,
+    ) -> Result<ContactSalt>;
+    /// Drops the expired salts along with their hashes.
+    async fn expire_contact_salts(&self) -> Result<()>;
+    /// The users whose phone hashes to one of `hashes` with `salt`, as seen
+    /// by the user. Users who aren't discoverable by phone only show up for
+    /// friends and those they have a friend request with, users who blocked
+    /// the user don't show up at all.
+    async fn discover_contacts(
+        &self,
+        user_id: &str,
+        salt: &ContactSalt,
+        hashes: &[String],
+    ) -> Result<Vec<DiscoveredContact>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contact_hash() {
+        let hash = contact_hash("salt", "+8613800138000");
+        assert_eq!(hash.len(), CONTACT_HASH_LEN);
+        assert_eq!(hash, sha256_hex("salt+8613800138000")[..CONTACT_HASH_LEN]);
+    }
+}
@@ -0,0 +1,16 @@
+use crate::core::error::Result;
+
+pub trait CredentialStore {
+    async fn update_password(
+        &self,
+        phone: &str,
+        password: &str,
+        password_salt: &str,
+    ) -> Result<()>;
+}
+
+/// Tells whether a password stored with `salt` should be hashed again, like
+/// when it was made by an outdated algorithm or with outdated parameters.
+pub trait RehashPolicy {
+    fn needs_rehash(&self, salt: &str) -> bool;
+}
@@ -0,0 +1,31 @@
+use crate::core::error::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountDeletion {
+    pub requested_at: DateTime<Utc>,
+    /// Until then the deletion can be cancelled.
+    pub delete_after: DateTime<Utc>,
+}
+
+pub trait AccountDeletionStore {
+    /// Schedules the account for deletion after `grace`. Scheduling it again
+    /// keeps the original schedule.
+    async fn schedule_account_deletion(
+        &self,
+        user_id: &str,
+        grace: Duration,
+    ) -> Result<AccountDeletion>;
+    async fn account_deletion(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<AccountDeletion>>;
+    async fn cancel_account_deletion(&self, user_id: &str) -> Result<()>;
+    /// Ids of accounts whose grace period is over.
+    async fn due_account_deletions(&self, limit: i64) -> Result<Vec<String>>;
+    /// Removes the user and everything they left behind, and returns the
+    /// paths of the files they uploaded and of their data exports, which are
+    /// up to the caller.
+    async fn delete_account(&self, user_id: &str) -> Result<Vec<String>>;
+}
@@ -22,6 +22,11 @@ impl Error {
             cause: Some(Box::new(cause)),
         }
     }
+
+    /// Whether retrying the failed operation later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.status_code, 429 | 502 | 503 | 504)
+    }
 }
 
 impl Display for Error {
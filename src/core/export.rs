@@ -0,0 +1,113 @@
+use crate::core::{
+    error::Result,
+    repository::{ChatMessage, User},
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum DataExportStatus {
+    Pending,
+    Running,
+    Ready,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DataExport {
+    pub id: String,
+    pub status: DataExportStatus,
+    pub requested_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The archive is removed afterwards.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClaimedDataExport {
+    pub id: String,
+    pub user_id: String,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ExportedProfile {
+    pub id: String,
+    pub phone: String,
+    pub avatar: Option<String>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ExportedFriendRequest {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ExportedUpload {
+    pub id: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub uploaded_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub filepath: String,
+}
+
+/// Everything the service keeps about a user.
+#[derive(Clone)]
+pub(crate) struct UserData {
+    pub(crate) profile: ExportedProfile,
+    pub(crate) friends: Vec<User>,
+    /// Both sent and received.
+    pub(crate) friend_requests: Vec<ExportedFriendRequest>,
+    /// Both sent and received.
+    pub(crate) messages: Vec<ChatMessage>,
+    pub(crate) uploads: Vec<ExportedUpload>,
+}
+
+pub trait DataExportStore {
+    /// Queues an export of the user's data. While an export is queued or
+    /// running it is returned instead of queueing another one.
+    async fn request_data_export(&self, user_id: &str) -> Result<DataExport>;
+    async fn data_export(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<Option<DataExport>>;
+    /// Path of the archive of a ready export.
+    async fn data_export_archive(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<Option<String>>;
+    /// Claims the oldest queued export. Exports claimed more than `lease`
+    /// ago are claimed again, their worker is assumed to have crashed.
+    async fn claim_data_export(
+        &self,
+        lease: Duration,
+    ) -> Result<Option<ClaimedDataExport>>;
+    async fn complete_data_export(
+        &self,
+        id: &str,
+        archive: &str,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Marks the export failed. It is kept for `ttl` so the user can see
+    /// what happened, and then forgotten like a ready one.
+    async fn fail_data_export(
+        &self,
+        id: &str,
+        error: &str,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Forgets the exports whose archive expired and returns the paths of
+    /// the archives, which are up to the caller.
+    async fn expire_data_exports(&self) -> Result<Vec<String>>;
+    async fn user_data(&self, user_id: &str) -> Result<UserData>;
+}
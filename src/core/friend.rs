@@ -0,0 +1,38 @@
+use crate::core::error::Result;
+use serde::Serialize;
+
+/// What a user keeps about a friend for themselves only.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FriendMetadata {
+    pub friend_id: String,
+    /// Shown instead of the friend's display name.
+    pub nickname: Option<String>,
+    pub labels: Vec<String>,
+    /// The same flag that lets calls ring through do-not-disturb.
+    pub favorite: bool,
+}
+
+pub trait FriendMetadataStore {
+    async fn all_friend_metadata(
+        &self,
+        owner_id: &str,
+    ) -> Result<Vec<FriendMetadata>>;
+    /// The metadata of a friend, or the defaults if none was set.
+    async fn friend_metadata(
+        &self,
+        owner_id: &str,
+        friend_id: &str,
+    ) -> Result<FriendMetadata>;
+    async fn update_friend_metadata(
+        &self,
+        owner_id: &str,
+        metadata: &FriendMetadata,
+    ) -> Result<()>;
+    async fn delete_friend_metadata(
+        &self,
+        owner_id: &str,
+        friend_id: &str,
+    ) -> Result<()>;
+    /// The labels in use, sorted.
+    async fn friend_labels(&self, owner_id: &str) -> Result<Vec<String>>;
+}
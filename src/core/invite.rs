@@ -0,0 +1,84 @@
+use crate::core::{error::Result, outbox::EnqueueNotification};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// A link anyone can redeem to become friends with the user who created it.
+#[derive(Debug, Clone, Serialize)]
+pub struct FriendInvite {
+    pub id: String,
+    pub user_id: String,
+    pub token: String,
+    /// Unlimited if `None`.
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    /// Whether redeeming makes friends right away, otherwise it sends a
+    /// friend request to the inviter.
+    pub auto_accept: bool,
+    pub created_at: DateTime<Utc>,
+    /// Never expires if `None`.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewFriendInvite {
+    pub token: String,
+    pub max_uses: Option<i32>,
+    pub auto_accept: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Turns invite tokens into the links clients share and render as QR codes.
+#[derive(Debug, Clone)]
+pub struct InviteLinks {
+    prefix: String,
+}
+
+impl InviteLinks {
+    /// `prefix` is followed by the token, like "https://example.com/invite/".
+    pub fn new(prefix: String) -> Self {
+        Self { prefix }
+    }
+
+    pub fn link(&self, token: &str) -> String {
+        format!("{}{}", self.prefix, token)
+    }
+}
+
+pub trait FriendInviteStore {
+    async fn create_friend_invite(
+        &self,
+        user_id: &str,
+        invite: &NewFriendInvite,
+    ) -> Result<FriendInvite>;
+    /// All invites of the user, latest first.
+    async fn friend_invites(&self, user_id: &str) -> Result<Vec<FriendInvite>>;
+    async fn friend_invite(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<Option<FriendInvite>>;
+    /// Returns whether there was an invite to revoke.
+    async fn revoke_friend_invite(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<bool>;
+    /// The invite with the token unless it was revoked, expired or used up.
+    async fn usable_friend_invite(
+        &self,
+        token: &str,
+    ) -> Result<Option<FriendInvite>>;
+    /// Counts a use of the invite and, in the same transaction, makes the
+    /// friend request of `user_id` to the inviter, accepted already if
+    /// `accept`. `push` is written to the outbox along with it, the id of
+    /// the request is added to its data as `id`. Returns the id of the
+    /// request, or `None` if the invite became unusable in the meantime.
+    async fn redeem_friend_invite(
+        &self,
+        id: &str,
+        user_id: &str,
+        accept: bool,
+        push: Option<&EnqueueNotification>,
+    ) -> Result<Option<String>>;
+}
@@ -1,4 +1,5 @@
 use actix::Message as ActixMessage;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +32,41 @@ pub(crate) enum SystemMessage {
     FriendAccept {
         id: String,
     },
+    /// The sender withdrew the request.
+    FriendRequestCancelled {
+        id: String,
+    },
+    /// The request went unanswered for too long, sent to both sides.
+    FriendRequestExpired {
+        id: String,
+    },
+    /// Someone redeemed an invite of the user and they are friends now.
+    FriendInviteRedeemed {
+        id: String,
+        invite_id: String,
+        from: String,
+        phone: String,
+        avatar: Option<String>,
+    },
+    /// The user with the id is no longer a friend.
+    FriendRemoved {
+        id: String,
+    },
+    /// A stranger's message went to the message requests.
+    MessageRequest {
+        from: String,
+        phone: String,
+    },
+    /// A friend changed their profile.
+    ProfileUpdated {
+        id: String,
+        avatar: Option<String>,
+        display_name: Option<String>,
+        bio: Option<String>,
+        updated_at: DateTime<Utc>,
+    },
+    /// The account was deleted, the connection closes after this.
+    AccountDeleted,
 }
 
 #[derive(Debug, Clone, ActixMessage, Serialize)]
@@ -1,4 +1,19 @@
+pub mod block;
+pub mod contact;
+pub mod credential;
+pub mod deletion;
 pub mod error;
+pub mod export;
+pub mod friend;
+pub mod invite;
 pub mod message;
 pub mod notifier;
+pub mod outbox;
+pub mod preference;
+pub mod privacy;
+pub mod ratelimit;
 pub mod repository;
+pub mod session;
+pub mod sms;
+pub mod twofactor;
+pub mod verification;
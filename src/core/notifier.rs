@@ -1,14 +1,30 @@
 use crate::core::error::Result;
 use serde::Serialize;
 
+/// Status code of the error returned by `Notifier::send_notification` when
+/// the provider reported the token as dead. The token has already been
+/// removed by the time the error is returned.
+pub const INVALID_TOKEN_STATUS: u16 = 410;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushNotification {
+    pub title: String,
+    pub body: String,
+    /// Undelivered notifications with the same key are replaced by the
+    /// newest one.
+    pub collapse_key: Option<String>,
+    /// Delivered notifications with the same thread are grouped together.
+    pub thread_id: Option<String>,
+}
+
 pub trait Notifier {
     async fn update_token(&self, uid: &str, token: &str) -> Result<()>;
     async fn get_token(&self, uid: &str) -> Result<Option<String>>;
+    async fn remove_token(&self, token: &str) -> Result<()>;
     async fn send_notification<T>(
         &self,
         to: &str,
-        title: &str,
-        body: &str,
+        notification: &PushNotification,
         data: T,
     ) -> Result<()>
     where
@@ -0,0 +1,76 @@
+use crate::core::error::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Debug, Clone)]
+pub struct EnqueueNotification {
+    pub user_id: String,
+    pub title: String,
+    pub body: String,
+    pub collapse_key: Option<String>,
+    pub thread_id: Option<String>,
+    pub data: Value,
+    /// How long the notification is worth delivering, it is discarded
+    /// afterwards.
+    pub ttl: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxNotification {
+    pub id: String,
+    pub user_id: String,
+    pub title: String,
+    pub body: String,
+    pub collapse_key: Option<String>,
+    pub thread_id: Option<String>,
+    pub data: Value,
+    /// Number of delivery attempts including the current one.
+    pub attempts: i32,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub user_id: String,
+    pub title: String,
+    pub body: String,
+    pub data: Value,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub trait NotificationOutbox {
+    async fn enqueue(
+        &self,
+        notification: &EnqueueNotification,
+    ) -> Result<String>;
+    /// Claims up to `limit` due notifications. Claimed notifications are
+    /// hidden from other workers for `lease` so that a crashed worker does
+    /// not lose them.
+    async fn claim_due(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<OutboxNotification>>;
+    /// Removes notifications whose TTL has passed and returns how many were
+    /// removed.
+    async fn discard_expired(&self) -> Result<u64>;
+    async fn remove(&self, id: &str) -> Result<()>;
+    async fn reschedule(
+        &self,
+        id: &str,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()>;
+    async fn mark_dead(&self, id: &str, error: &str) -> Result<()>;
+    /// Latest first.
+    async fn dead_letters(
+        &self,
+        user_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<DeadLetter>>;
+}
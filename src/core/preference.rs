@@ -0,0 +1,78 @@
+use crate::core::error::Result;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationKind {
+    Chat,
+    Call,
+    FriendRequest,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DoNotDisturb {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// IANA name of the time zone `start` and `end` are in.
+    pub time_zone: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NotificationSettings {
+    pub muted: bool,
+    pub do_not_disturb: Option<DoNotDisturb>,
+    /// Whether calls from favorite peers ring during do-not-disturb.
+    pub dnd_allow_favorites: bool,
+    /// Locale notifications are rendered in, like "en" or "zh-CN".
+    pub locale: String,
+    /// Leave message contents out of notifications.
+    pub hide_previews: bool,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            muted: false,
+            do_not_disturb: None,
+            dnd_allow_favorites: true,
+            locale: "en".into(),
+            hide_previews: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerNotificationSettings {
+    pub peer_id: String,
+    pub muted_until: Option<DateTime<Utc>>,
+    pub favorite: bool,
+}
+
+pub trait NotificationPreferences {
+    async fn notification_settings(
+        &self,
+        user_id: &str,
+    ) -> Result<NotificationSettings>;
+    async fn update_notification_settings(
+        &self,
+        user_id: &str,
+        settings: &NotificationSettings,
+    ) -> Result<()>;
+    async fn peer_notification_settings(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<PeerNotificationSettings>>;
+    async fn update_peer_notification_settings(
+        &self,
+        user_id: &str,
+        settings: &PeerNotificationSettings,
+    ) -> Result<()>;
+    /// Whether `user_id` wants to be notified of a `kind` event from `from`
+    /// right now.
+    async fn should_notify(
+        &self,
+        user_id: &str,
+        from: &str,
+        kind: NotificationKind,
+    ) -> Result<bool>;
+}
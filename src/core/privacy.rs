@@ -0,0 +1,114 @@
+use crate::core::error::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Who may message and call a user.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ContactPolicy {
+    #[default]
+    FriendsOnly,
+    Anyone,
+    /// Messages of strangers wait in the message requests until the user
+    /// accepts them, strangers can't call.
+    MessageRequests,
+}
+
+/// How a message or call to a user is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Contact {
+    Allowed,
+    /// The message goes to the message requests, a call is refused.
+    Request,
+    Denied,
+}
+
+impl ContactPolicy {
+    /// `conversing` is whether the two talk already: the user accepted the
+    /// message request of the sender, or has one waiting with the sender.
+    pub fn contact(self, friends: bool, conversing: bool) -> Contact {
+        match self {
+            _ if friends || conversing => Contact::Allowed,
+            ContactPolicy::Anyone => Contact::Allowed,
+            ContactPolicy::MessageRequests => Contact::Request,
+            ContactPolicy::FriendsOnly => Contact::Denied,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrivacySettings {
+    pub contact_policy: ContactPolicy,
+    /// Whether strangers find the user by phone, in a search or a contact
+    /// sync.
+    pub discoverable_by_phone: bool,
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            contact_policy: ContactPolicy::default(),
+            discoverable_by_phone: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageRequest {
+    pub peer_id: String,
+    pub peer_phone: String,
+    pub peer_display_name: Option<String>,
+    pub unread_count: i64,
+    pub latest_mime_type: Option<String>,
+    pub latest_content: Option<String>,
+    pub requested_at: DateTime<Utc>,
+}
+
+pub trait PrivacyStore {
+    async fn privacy_settings(&self, user_id: &str) -> Result<PrivacySettings>;
+    async fn update_privacy_settings(
+        &self,
+        user_id: &str,
+        settings: &PrivacySettings,
+    ) -> Result<()>;
+    /// How a message or call from `from` to `to` is handled under the
+    /// contact policy of `to`.
+    async fn contact(&self, from: &str, to: &str) -> Result<Contact>;
+    /// Puts `from` into the message requests of `to` unless it's there.
+    async fn add_message_request(&self, from: &str, to: &str) -> Result<()>;
+    /// The pending message requests of the user, latest first.
+    async fn message_requests(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<MessageRequest>>;
+    /// Lets `peer_id` message the user from now on. Returns whether there
+    /// was a pending request.
+    async fn accept_message_request(
+        &self,
+        user_id: &str,
+        peer_id: &str,
+    ) -> Result<bool>;
+    /// Drops the request along with the messages it holds. Returns whether
+    /// there was a pending request.
+    async fn decline_message_request(
+        &self,
+        user_id: &str,
+        peer_id: &str,
+    ) -> Result<bool>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contact() {
+        use ContactPolicy::*;
+        assert_eq!(FriendsOnly.contact(true, false), Contact::Allowed);
+        assert_eq!(FriendsOnly.contact(false, false), Contact::Denied);
+        assert_eq!(Anyone.contact(false, false), Contact::Allowed);
+        assert_eq!(MessageRequests.contact(false, false), Contact::Request);
+        assert_eq!(MessageRequests.contact(false, true), Contact::Allowed);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::core::error::Result;
+use chrono::{DateTime, Duration, Utc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counted {
+    /// Failed requests count, a successful one clears the counters of the
+    /// account but not of the address. For credential checks.
+    Failures,
+    /// Every request counts. For endpoints that can be scraped.
+    Requests,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitPolicy {
+    /// Attempts allowed within `window` before the key is locked out.
+    pub max_attempts: i32,
+    pub window: Duration,
+    /// The first lockout lasts `base_lockout`, every one after that twice
+    /// as long as the one before, up to `max_lockout`.
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+    pub counted: Counted,
+}
+
+impl RateLimitPolicy {
+    /// How long the key is locked out after `lockouts` earlier lockouts.
+    pub fn lockout(&self, lockouts: i32) -> Duration {
+        (0..lockouts.clamp(0, 20))
+            .fold(self.base_lockout, |d, _| d * 2)
+            .min(self.max_lockout)
+    }
+}
+
+/// Attempt counters keyed by things like "login:ip:203.0.113.7".
+pub trait RateLimitStore {
+    /// The latest time any of `keys` is locked out until, if one is.
+    async fn locked_until(
+        &self,
+        keys: &[String],
+    ) -> Result<Option<DateTime<Utc>>>;
+    /// Counts an attempt against `key` and locks it out if that was one too
+    /// many. Returns the end of the lockout if it started.
+    async fn record_attempt(
+        &self,
+        key: &str,
+        policy: &RateLimitPolicy,
+    ) -> Result<Option<DateTime<Utc>>>;
+    async fn reset(&self, keys: &[String]) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_doubles_up_to_max() {
+        let policy = RateLimitPolicy {
+            max_attempts: 5,
+            window: Duration::minutes(15),
+            base_lockout: Duration::minutes(1),
+            max_lockout: Duration::minutes(10),
+            counted: Counted::Failures,
+        };
+        assert_eq!(policy.lockout(0), Duration::minutes(1));
+        assert_eq!(policy.lockout(2), Duration::minutes(4));
+        assert_eq!(policy.lockout(4), Duration::minutes(10));
+        assert_eq!(policy.lockout(1000), Duration::minutes(10));
+    }
+}
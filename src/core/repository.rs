@@ -1,7 +1,9 @@
-use crate::core::{error::Result, message::Message};
+use crate::core::{
+    error::Result, message::Message, outbox::EnqueueNotification,
+};
 use actix::Recipient;
-use chrono::{DateTime, Utc};
-use serde::Serialize;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, PartialEq, Serialize)]
 pub enum FriendRequestStatus {
@@ -33,13 +35,65 @@ pub(crate) struct User {
     pub id: String,
     pub phone: String,
     pub avatar: Option<String>,
+    pub display_name: Option<String>,
+    /// Free text like a status line.
+    pub bio: Option<String>,
+    pub updated_at: DateTime<Utc>,
     pub typ: Option<UserType>,
 }
 
+/// How friends and friend requests are listed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum FriendSort {
+    /// By the name shown for the user, case insensitive.
+    #[default]
+    Name,
+    /// The latest message either way first, or the request for friends
+    /// who haven't talked yet.
+    Recent,
+}
+
+/// Where a page continues, the sort key and id of the last item of the
+/// page before.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum FriendCursor {
+    Name { name: String, id: String },
+    Recent { at: DateTime<Utc>, id: String },
+}
+
+#[derive(Clone)]
+pub(crate) struct Page<T> {
+    pub items: Vec<T>,
+    /// `None` on the last page.
+    pub next: Option<FriendCursor>,
+}
+
+/// A user the user may know, with the number of friends they share. The
+/// phone stays out, a stranger is only shown by name and avatar.
+#[derive(Clone, Serialize)]
+pub(crate) struct FriendSuggestion {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+    pub mutual_friends: i64,
+}
+
+/// Changes to the profile, `None` leaves a field as it is and `Some(None)`
+/// clears it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProfileUpdate {
+    pub(crate) display_name: Option<Option<String>>,
+    pub(crate) bio: Option<Option<String>>,
+}
+
 #[derive(Clone, Serialize)]
 pub(crate) struct Session {
     pub(crate) peer_id: String,
     pub(crate) peer_phone: String,
+    pub(crate) peer_avatar: Option<String>,
+    pub(crate) peer_display_name: Option<String>,
+    pub(crate) peer_bio: Option<String>,
+    pub(crate) peer_updated_at: DateTime<Utc>,
     pub(crate) unread_count: i64,
     pub(crate) latest_mime_type: Option<String>,
     pub(crate) latest_content: Option<String>,
@@ -65,17 +119,86 @@ pub struct InsertChatMessage {
 }
 
 pub trait Repository {
-    async fn add_friend_request(&self, from: &str, to: &str) -> Result<String>;
+    /// `push` is written to the outbox along with the request, the id of
+    /// the request is added to its data as `id`.
+    async fn add_friend_request(
+        &self,
+        from: &str,
+        to: &str,
+        push: Option<&EnqueueNotification>,
+    ) -> Result<String>;
     async fn get_friend_request(&self, id: &str) -> Result<FriendRequest>;
-    async fn accept_friend_request(&self, id: &str) -> Result<()>;
+    /// `push` is written to the outbox along with the acceptance.
+    async fn accept_friend_request(
+        &self,
+        id: &str,
+        push: Option<&EnqueueNotification>,
+    ) -> Result<()>;
     async fn reject_friend_request(&self, id: &str) -> Result<()>;
+    /// A page of the pending requests to the user, `after` is the cursor
+    /// of the page before. Sorting by name goes by the sender's name, by
+    /// recency by when the request was sent.
     async fn pending_friend_requests(
         &self,
         to: &str,
+        sort: FriendSort,
+        after: Option<&FriendCursor>,
+        limit: i64,
+    ) -> Result<Page<FriendRequest>>;
+    async fn count_pending_friend_requests(&self, to: &str) -> Result<i64>;
+    /// The pending requests `from` sent, `phone` is the recipient's.
+    async fn outgoing_friend_requests(
+        &self,
+        from: &str,
+    ) -> Result<Vec<FriendRequest>>;
+    /// Withdraws a pending request `from` sent and returns its recipient, or
+    /// `None` if there is no such request.
+    async fn cancel_friend_request(
+        &self,
+        from: &str,
+        id: &str,
+    ) -> Result<Option<String>>;
+    /// Removes up to `limit` requests pending for longer than `ttl` and
+    /// returns them.
+    async fn expire_friend_requests(
+        &self,
+        ttl: Duration,
+        limit: i64,
     ) -> Result<Vec<FriendRequest>>;
+    /// Ends the friendship and forgets the message requests between the two,
+    /// returns whether there was a friendship.
+    async fn unfriend(&self, user_id: &str, friend_id: &str) -> Result<bool>;
+    /// The friends of the user, nicknames the user set replace the display
+    /// names.
     async fn friends(&self, user_id: &str) -> Result<Vec<User>>;
+    /// A page of the friends of the user, see `friends` and
+    /// `pending_friend_requests`.
+    async fn friends_page(
+        &self,
+        user_id: &str,
+        label: Option<&str>,
+        sort: FriendSort,
+        after: Option<&FriendCursor>,
+        limit: i64,
+    ) -> Result<Page<User>>;
+    async fn count_friends(
+        &self,
+        user_id: &str,
+        label: Option<&str>,
+    ) -> Result<i64>;
+    /// Friends of the user's friends, most mutual friends first. Users the
+    /// user has a request with or a block either way are left out, so are
+    /// those who aren't discoverable by phone.
+    async fn friend_suggestions(
+        &self,
+        user_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<FriendSuggestion>>;
     async fn sessions(&self, user_id: &str) -> Result<Vec<Session>>;
     async fn is_friend(&self, user_id: &str, friend_id: &str) -> Result<bool>;
+    /// Users who aren't discoverable by phone are only found by friends and
+    /// those they have a friend request with.
     async fn search_user(
         &self,
         user_id: &str,
@@ -90,9 +213,11 @@ pub trait Repository {
         before: Option<&str>,
     ) -> Result<Vec<ChatMessage>>;
 
+    /// `push` is written to the outbox along with the message.
     async fn insert_chat_message(
         &self,
         create: &InsertChatMessage,
+        push: Option<&EnqueueNotification>,
     ) -> Result<ChatMessage>;
 
     async fn update_avatar(&self, self_id: &str, upload_id: &str)
@@ -100,6 +225,11 @@ pub trait Repository {
     async fn get_avatar(&self, self_id: &str) -> Result<Option<String>>;
     async fn mark_as_read(&self, user_id: &str, msg_id: &str) -> Result<()>;
     async fn get_user(&self, id: &str) -> Result<User>;
+    async fn update_profile(
+        &self,
+        id: &str,
+        update: &ProfileUpdate,
+    ) -> Result<User>;
 }
 
 pub trait AddrStore {
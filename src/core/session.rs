@@ -0,0 +1,104 @@
+use crate::core::error::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// The login in progress. `AuthRepository::set_key` can't be handed more
+/// than the phone and the key, so the login handler provides the rest
+/// through `LOGIN`.
+#[derive(Debug, Clone)]
+pub struct LoginContext {
+    pub session_id: String,
+    pub key_expires_at: DateTime<Utc>,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub ip: Option<String>,
+}
+
+tokio::task_local! {
+    pub static LOGIN: LoginContext;
+}
+
+/// How long the tokens handed out at login and refresh stay valid.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLifetimes {
+    pub access: Duration,
+    pub refresh: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginSession {
+    pub id: String,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    /// Whether this is the session of the caller.
+    pub current: bool,
+}
+
+/// Login sessions, one per device. Sessions are identified to their owner
+/// by the hash of the token issued for them.
+pub trait LoginSessionStore {
+    async fn bind_token(
+        &self,
+        session_id: &str,
+        token_hash: &str,
+    ) -> Result<()>;
+    async fn issue_refresh_token(
+        &self,
+        session_id: &str,
+        refresh_token_hash: &str,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Trades a refresh token for a new one and moves the session to `key`.
+    /// Refresh tokens of a session form a family: presenting one that was
+    /// already traded in means it leaked, so the whole session is revoked.
+    /// Returns the id of the session.
+    async fn rotate_refresh_token(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        key: &str,
+        lifetimes: TokenLifetimes,
+    ) -> Result<String>;
+    async fn login_sessions(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+    ) -> Result<Vec<LoginSession>>;
+    async fn revoke_login_session(
+        &self,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<()>;
+    async fn revoke_current_login_session(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+    ) -> Result<()>;
+    /// Revokes every session of `user_id` but the current one and returns
+    /// how many were revoked.
+    async fn revoke_other_login_sessions(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+    ) -> Result<u64>;
+}
+
+/// One-time tickets that open a websocket for browsers, which can't set
+/// headers on the upgrade request.
+pub trait WsTicketStore {
+    async fn issue_ws_ticket(
+        &self,
+        user_id: &str,
+        ticket_hash: &str,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Consumes the ticket and returns the id of the user it was issued to,
+    /// or `None` if the ticket is unknown, used or expired.
+    async fn redeem_ws_ticket(
+        &self,
+        ticket_hash: &str,
+    ) -> Result<Option<String>>;
+}
@@ -0,0 +1,5 @@
+use crate::core::error::Result;
+
+pub trait SmsSender {
+    async fn send_sms(&self, phone: &str, text: &str) -> Result<()>;
+}
@@ -0,0 +1,83 @@
+use crate::core::error::Result;
+use chrono::Duration;
+use serde::Serialize;
+
+#[derive(Debug, Clone)]
+pub struct TwoFactor {
+    /// The TOTP secret in base32.
+    pub secret: String,
+    /// Whether enrollment was confirmed. Unconfirmed secrets are never asked
+    /// for at login.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+}
+
+/// A login that got the password right and still has to pass the second
+/// factor.
+#[derive(Debug, Clone)]
+pub struct TwoFactorChallenge {
+    pub user_id: String,
+    pub phone: String,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+}
+
+pub trait TwoFactorStore {
+    async fn two_factor(&self, user_id: &str) -> Result<Option<TwoFactor>>;
+    /// The id of the user with `phone` if they have 2FA enabled.
+    async fn two_factor_user(&self, phone: &str) -> Result<Option<String>>;
+    async fn two_factor_status(&self, user_id: &str)
+        -> Result<TwoFactorStatus>;
+    /// Starts enrollment with a new secret. Fails with 409 if 2FA is already
+    /// enabled.
+    async fn save_pending_two_factor(
+        &self,
+        user_id: &str,
+        secret: &str,
+    ) -> Result<()>;
+    async fn enable_two_factor(
+        &self,
+        user_id: &str,
+        recovery_code_hashes: &[String],
+    ) -> Result<()>;
+    async fn disable_two_factor(&self, user_id: &str) -> Result<()>;
+    /// Marks the TOTP time step as used. Returns false if it, or a later
+    /// one, was used before, so that a code can't be replayed.
+    async fn use_totp_step(&self, user_id: &str, step: i64) -> Result<bool>;
+    /// The hashes of the recovery codes the user has left.
+    async fn unused_recovery_codes(&self, user_id: &str)
+        -> Result<Vec<String>>;
+    /// Returns false if there is no such unused recovery code.
+    async fn use_recovery_code(
+        &self,
+        user_id: &str,
+        code_hash: &str,
+    ) -> Result<bool>;
+    async fn replace_recovery_codes(
+        &self,
+        user_id: &str,
+        code_hashes: &[String],
+    ) -> Result<()>;
+    async fn create_two_factor_challenge(
+        &self,
+        challenge_hash: &str,
+        challenge: &TwoFactorChallenge,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Counts an attempt at the challenge. Fails with 401 if it's unknown or
+    /// expired, and with 429 after `max_attempts`.
+    async fn attempt_two_factor_challenge(
+        &self,
+        challenge_hash: &str,
+        max_attempts: i32,
+    ) -> Result<TwoFactorChallenge>;
+    async fn remove_two_factor_challenge(
+        &self,
+        challenge_hash: &str,
+    ) -> Result<()>;
+}
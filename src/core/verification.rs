@@ -0,0 +1,40 @@
+use crate::core::error::Result;
+use chrono::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationPurpose {
+    Signup,
+    ResetPassword,
+}
+
+impl VerificationPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Signup => "Signup",
+            Self::ResetPassword => "ResetPassword",
+        }
+    }
+}
+
+pub trait VerificationStore {
+    /// Saves a new code for `phone`, replacing the previous one. Fails with
+    /// 429 while the previous code was sent less than `cooldown` ago.
+    async fn save_code(
+        &self,
+        phone: &str,
+        purpose: VerificationPurpose,
+        code_hash: &str,
+        ttl: Duration,
+        cooldown: Duration,
+    ) -> Result<()>;
+    /// Consumes the code of `phone` if it matches `code_hash`. Fails with
+    /// 422 on a wrong or expired code and with 429 once `max_attempts`
+    /// wrong codes were tried.
+    async fn consume_code(
+        &self,
+        phone: &str,
+        purpose: VerificationPurpose,
+        code_hash: &str,
+        max_attempts: i32,
+    ) -> Result<()>;
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Cursor};
 
 use crate::{
     core::{
@@ -11,288 +11,1734 @@ use crate::{
     ws::actor::WS,
 };
 use actix::{Actor, ActorContext, Context, Handler};
+use actix_files::NamedFile;
 use actix_multipart::Multipart;
 use actix_web::{
     error::{
-        ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized,
-        ErrorUnprocessableEntity,
+        ErrorConflict, ErrorForbidden, ErrorInternalServerError, ErrorNotFound,
+        ErrorServiceUnavailable, ErrorUnauthorized, ErrorUnprocessableEntity,
+        InternalError,
+    },
+    http::{
+        header::{ContentDisposition, DispositionParam, DispositionType},
+        StatusCode,
     },
-    http::StatusCode,
     web::{Data, Json, Path, Query},
-    HttpResponse, Result,
+    HttpRequest, HttpResponse, Result,
 };
 use auth_service::core::{
     hasher::Hasher, repository::Repository as AuthRepository,
     service::Service as AuthService, token_manager::TokenManager,
 };
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Duration, NaiveTime, Utc};
 use futures_util::{StreamExt, TryStreamExt};
+use image::{ImageFormat, Luma};
+use log::error;
+use qrcode::QrCode;
 use serde::{Deserialize, Serialize};
+use serde_json::to_value;
+use subtle::{Choice, ConstantTimeEq};
 use upload_service::core::{
     repository::Repository as UploadRepository,
     service::Service as UploadService, store::Store as UploadStore,
 };
+use uuid::Uuid;
 
 use crate::{
     core::{
-        notifier::Notifier,
-        repository::{AddrStore, Repository, Session, User},
+        block::{BlockStore, BlockedUser},
+        contact::{
+            ContactSalt, ContactStore, DiscoveredContact, CONTACT_HASH_LEN,
+        },
+        credential::{CredentialStore, RehashPolicy},
+        deletion::{AccountDeletion, AccountDeletionStore},
+        export::{DataExport, DataExportStore},
+        friend::{FriendMetadata, FriendMetadataStore},
+        invite::{
+            FriendInvite, FriendInviteStore, InviteLinks, NewFriendInvite,
+        },
+        notifier::{Notifier, PushNotification},
+        outbox::{DeadLetter, EnqueueNotification, NotificationOutbox},
+        preference::{
+            DoNotDisturb, NotificationPreferences, NotificationSettings,
+            PeerNotificationSettings,
+        },
+        privacy::{
+            Contact, ContactPolicy, MessageRequest, PrivacySettings,
+            PrivacyStore,
+        },
+        repository::{
+            AddrStore, FriendCursor, FriendSort, FriendSuggestion, Page,
+            ProfileUpdate, Repository, Session, User,
+        },
+        session::{
+            LoginContext, LoginSession, LoginSessionStore, TokenLifetimes,
+            WsTicketStore, LOGIN,
+        },
+        sms::SmsSender,
+        twofactor::{TwoFactorChallenge, TwoFactorStatus, TwoFactorStore},
+        verification::{VerificationPurpose, VerificationStore},
     },
+    middlewares::ratelimit::client_ip,
+    notifiers::content::NotificationEvent,
+    phone::PhoneNormalizer,
     stores::postgres::PostgresRepository,
-    utils::UserID,
+    totp::{generate_challenge_id, generate_recovery_code, Totp},
+    utils::{sha256_hex, AuthToken, UserID},
     AddrMap,
 };
 
+/// Lists that change while they are paged through take the `next_cursor`
+/// of the page before as `cursor` instead of an `offset`.
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub(crate) struct Pagination {
     pub(crate) limit: i64,
     pub(crate) offset: i64,
+    pub(crate) cursor: Option<String>,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            limit: 20,
+            offset: 0,
+            cursor: None,
+        }
+    }
+}
+
+const PAGE_MAX_LIMIT: i64 = 100;
+
+impl Pagination {
+    fn validate(&self) -> Result<()> {
+        if !(1..=PAGE_MAX_LIMIT).contains(&self.limit) {
+            return Err(ErrorUnprocessableEntity(format!(
+                "limit must be between 1 and {}",
+                PAGE_MAX_LIMIT
+            )));
+        }
+        if self.offset < 0 {
+            return Err(ErrorUnprocessableEntity(
+                "offset must not be negative",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Validates the page and decodes the cursor, which has to come from a
+    /// page sorted by `sort`.
+    fn after(&self, sort: FriendSort) -> Result<Option<FriendCursor>> {
+        self.validate()?;
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        let cursor = general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<FriendCursor>(&json).ok())
+            .ok_or_else(|| ErrorUnprocessableEntity("invalid cursor"))?;
+        match (&cursor, sort) {
+            (FriendCursor::Name { .. }, FriendSort::Name)
+            | (FriendCursor::Recent { .. }, FriendSort::Recent) => {
+                Ok(Some(cursor))
+            }
+            _ => Err(ErrorUnprocessableEntity("cursor is for another sort")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct PageResp<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+    /// Across all pages.
+    total: i64,
+}
+
+impl<T> PageResp<T> {
+    fn new(page: Page<T>, total: i64) -> Result<Self> {
+        let next_cursor = page
+            .next
+            .map(|cursor| {
+                serde_json::to_vec(&cursor)
+                    .map(|json| general_purpose::URL_SAFE_NO_PAD.encode(json))
+            })
+            .transpose()
+            .map_err(ErrorInternalServerError)?;
+        Ok(Self {
+            items: page.items,
+            next_cursor,
+            total,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SortQuery {
+    #[serde(default)]
+    sort: FriendSort,
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct Login {
     phone: String,
     password: String,
+    /// Shown in the list of login sessions, like "Alice's iPhone".
+    device_name: Option<String>,
+    platform: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct LoginResp {
     token: String,
+    refresh_token: String,
+    /// Seconds until `token` expires.
+    expires_in: i64,
 }
 
-pub(crate) async fn login<R, H, T>(
-    auth_service: Data<AuthService<R, H, T>>,
-    Json(Login { phone, password }): Json<Login>,
-) -> Result<Json<LoginResp>>
+fn generate_refresh_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Hashes the password again if it was stored with an outdated algorithm or
+/// parameters. The login goes ahead when that fails.
+async fn upgrade_password_hash<R, H, C>(
+    auth_repo: &R,
+    hasher: &H,
+    credentials: &C,
+    phone: &str,
+    password: &str,
+) where
+    R: AuthRepository,
+    H: Hasher + RehashPolicy,
+    C: CredentialStore,
+{
+    let salt = match auth_repo.get_password_salt(phone).await {
+        Ok(Some(salt)) => salt,
+        Ok(None) => return,
+        Err(e) => {
+            error!("failed to get password salt: {}", e);
+            return;
+        }
+    };
+    if !hasher.needs_rehash(&salt) {
+        return;
+    }
+    if let Err(e) = set_password(hasher, credentials, phone, password).await {
+        error!("failed to upgrade password hash: {}", e);
+    }
+}
+
+/// Stores `password` with a fresh salt, hashed by the hasher the auth
+/// service was built with. The auth service itself can only set a password
+/// at signup.
+async fn set_password<H, C>(
+    hasher: &H,
+    credentials: &C,
+    phone: &str,
+    password: &str,
+) -> std::result::Result<(), Error>
 where
-    R: AuthRepository + Clone,
-    H: Hasher + Clone,
-    T: TokenManager + Clone,
+    H: Hasher,
+    C: CredentialStore,
+{
+    let salt = hasher.generate_salt().map_err(|e| {
+        Error::wrap("failed to generate password salt".into(), 500, e)
+    })?;
+    let hashed = hasher
+        .hash(password, &salt)
+        .map_err(|e| Error::wrap("failed to hash password".into(), 500, e))?;
+    credentials.update_password(phone, &hashed, &salt).await
+}
+
+fn login_context(
+    req: &HttpRequest,
+    lifetimes: &TokenLifetimes,
+    device_name: Option<String>,
+    platform: Option<String>,
+) -> LoginContext {
+    LoginContext {
+        session_id: Uuid::new_v4().to_string(),
+        key_expires_at: Utc::now() + lifetimes.access,
+        device_name,
+        platform,
+        ip: client_ip(req).map(|ip| ip.to_string()),
+    }
+}
+
+/// Binds `token` to the session and hands out its first refresh token.
+async fn start_session<S>(
+    sessions: &S,
+    lifetimes: &TokenLifetimes,
+    session_id: &str,
+    token: String,
+) -> Result<LoginResp>
+where
+    S: LoginSessionStore,
 {
-    let token = auth_service
-        .login(&phone, &password)
+    sessions
+        .bind_token(session_id, &sha256_hex(&token))
         .await
-        .map_err(ErrorForbidden)?;
+        .map_err(ErrorInternalServerError)?;
+    let refresh_token = generate_refresh_token();
+    sessions
+        .issue_refresh_token(
+            session_id,
+            &sha256_hex(&refresh_token),
+            lifetimes.refresh,
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(LoginResp {
+        token,
+        refresh_token,
+        expires_in: lifetimes.access.num_seconds(),
+    })
+}
 
-    Ok(Json(LoginResp { token }))
+/// Checks the password the way the auth service does, without letting it
+/// issue a token.
+async fn check_password<R, H>(
+    auth_repo: &R,
+    hasher: &H,
+    phone: &str,
+    password: &str,
+) -> Result<bool>
+where
+    R: AuthRepository,
+    H: Hasher,
+{
+    let Some(salt) = auth_repo
+        .get_password_salt(phone)
+        .await
+        .map_err(ErrorInternalServerError)?
+    else {
+        return Ok(false);
+    };
+    let hashed = hasher
+        .hash(password, &salt)
+        .map_err(ErrorInternalServerError)?;
+    auth_repo
+        .exists_credential(phone, &hashed)
+        .await
+        .map_err(ErrorInternalServerError)
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct Signup {
-    phone: String,
-    password: String,
+const TWO_FACTOR_CHALLENGE_TTL_MINUTES: i64 = 5;
+const TWO_FACTOR_MAX_ATTEMPTS: i32 = 5;
+
+/// Tells clients in `status` whether they are logged in. The fields of
+/// `LoginResp` sit next to it, so clients that only read those keep working.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum LoginOutcome {
+    Authenticated(LoginResp),
+    /// The user has 2FA enabled, the login goes on at `/login/two_factor`.
+    TwoFactorRequired {
+        challenge: String,
+        expires_in: i64,
+    },
 }
 
-pub(crate) async fn signup<R, H, T>(
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn login<R, H, T, S, C, F>(
     auth_service: Data<AuthService<R, H, T>>,
-    Json(Signup { phone, password }): Json<Signup>,
-) -> Result<HttpResponse>
+    auth_repo: Data<R>,
+    hasher: Data<H>,
+    credentials: Data<C>,
+    sessions: Data<S>,
+    two_factor: Data<F>,
+    lifetimes: Data<TokenLifetimes>,
+    phones: Data<PhoneNormalizer>,
+    req: HttpRequest,
+    Json(Login {
+        phone,
+        password,
+        device_name,
+        platform,
+    }): Json<Login>,
+) -> Result<Json<LoginOutcome>>
 where
-    R: AuthRepository + Clone,
-    H: Hasher + Clone,
+    R: AuthRepository + Clone + 'static,
+    H: Hasher + RehashPolicy + Clone + 'static,
     T: TokenManager + Clone,
+    S: LoginSessionStore,
+    C: CredentialStore + 'static,
+    F: TwoFactorStore,
 {
-    auth_service
-        .signup(&phone, &password)
+    // don't tell a wrong password from an unknown phone
+    let invalid = || ErrorForbidden("invalid phone or password");
+    let phone = phones.normalize(&phone).map_err(|_| invalid())?;
+    if let Some(user_id) = two_factor
+        .two_factor_user(&phone)
         .await
-        .map_err(ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().finish())
+        .map_err(http_error)?
+    {
+        if !check_password(
+            auth_repo.as_ref(),
+            hasher.as_ref(),
+            &phone,
+            &password,
+        )
+        .await?
+        {
+            return Err(invalid());
+        }
+        upgrade_password_hash(
+            auth_repo.as_ref(),
+            hasher.as_ref(),
+            credentials.as_ref(),
+            &phone,
+            &password,
+        )
+        .await;
+        let challenge = generate_challenge_id();
+        two_factor
+            .create_two_factor_challenge(
+                &sha256_hex(&challenge),
+                &TwoFactorChallenge {
+                    user_id,
+                    phone,
+                    device_name,
+                    platform,
+                },
+                Duration::minutes(TWO_FACTOR_CHALLENGE_TTL_MINUTES),
+            )
+            .await
+            .map_err(http_error)?;
+        return Ok(Json(LoginOutcome::TwoFactorRequired {
+            challenge,
+            expires_in: TWO_FACTOR_CHALLENGE_TTL_MINUTES * 60,
+        }));
+    }
+    let login = login_context(&req, &lifetimes, device_name, platform);
+    let session_id = login.session_id.clone();
+    let token = LOGIN
+        .scope(login, auth_service.login(&phone, &password))
+        .await
+        .map_err(|_| invalid())?;
+    upgrade_password_hash(
+        auth_repo.as_ref(),
+        hasher.as_ref(),
+        credentials.as_ref(),
+        &phone,
+        &password,
+    )
+    .await;
+    Ok(Json(LoginOutcome::Authenticated(
+        start_session(sessions.as_ref(), &lifetimes, &session_id, token)
+            .await?,
+    )))
 }
 
-pub(crate) async fn my_friends<R>(
-    UserID(uid): UserID,
-    repo: Data<R>,
-) -> Result<Json<Vec<User>>>
+#[derive(Debug, Deserialize)]
+pub(crate) struct TwoFactorLogin {
+    challenge: String,
+    /// A code from the authenticator app or a recovery code.
+    code: String,
+}
+
+pub(crate) async fn login_two_factor<R, T, S, F>(
+    auth_repo: Data<R>,
+    token_manager: Data<T>,
+    sessions: Data<S>,
+    two_factor: Data<F>,
+    lifetimes: Data<TokenLifetimes>,
+    req: HttpRequest,
+    Json(TwoFactorLogin { challenge, code }): Json<TwoFactorLogin>,
+) -> Result<Json<LoginResp>>
 where
-    R: Repository,
+    R: AuthRepository,
+    T: TokenManager,
+    S: LoginSessionStore,
+    F: TwoFactorStore,
 {
+    let challenge_hash = sha256_hex(&challenge);
+    let pending = two_factor
+        .attempt_two_factor_challenge(&challenge_hash, TWO_FACTOR_MAX_ATTEMPTS)
+        .await
+        .map_err(http_error)?;
+    if !verify_second_factor(two_factor.as_ref(), &pending.user_id, &code)
+        .await?
+    {
+        return Err(ErrorForbidden("invalid 2fa code"));
+    }
+    two_factor
+        .remove_two_factor_challenge(&challenge_hash)
+        .await
+        .map_err(http_error)?;
+    let login =
+        login_context(&req, &lifetimes, pending.device_name, pending.platform);
+    let session_id = login.session_id.clone();
+    let key = Uuid::new_v4().to_string();
+    LOGIN
+        .scope(login, auth_repo.set_key(&pending.phone, &key))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let token = token_manager
+        .generate_token(&key)
+        .map_err(ErrorInternalServerError)?;
     Ok(Json(
-        repo.friends(&uid).await.map_err(ErrorInternalServerError)?,
+        start_session(sessions.as_ref(), &lifetimes, &session_id, token)
+            .await?,
     ))
 }
 
-#[derive(Debug, Serialize)]
-pub enum UserType {
-    Stranger,
-    Friend,
-    Myself,
+/// Checks a code from the authenticator app, or else a recovery code, and
+/// uses it up.
+async fn verify_second_factor<F>(
+    two_factor: &F,
+    user_id: &str,
+    code: &str,
+) -> Result<bool>
+where
+    F: TwoFactorStore,
+{
+    let Some(enrollment) =
+        two_factor.two_factor(user_id).await.map_err(http_error)?
+    else {
+        return Ok(false);
+    };
+    let code = code.trim();
+    if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        let hash = hash_recovery_code(code);
+        // compared here rather than looked up, so that it takes as long
+        // whichever code it matches
+        let unused = two_factor
+            .unused_recovery_codes(user_id)
+            .await
+            .map_err(http_error)?;
+        let matches = unused.iter().fold(Choice::from(0), |found, unused| {
+            found | unused.as_bytes().ct_eq(hash.as_bytes())
+        });
+        if !bool::from(matches) {
+            return Ok(false);
+        }
+        return two_factor
+            .use_recovery_code(user_id, &hash)
+            .await
+            .map_err(http_error);
+    }
+    let totp = Totp::from_encoded(&enrollment.secret)
+        .ok_or_else(|| ErrorInternalServerError("malformed 2fa secret"))?;
+    match totp.verify(code, Utc::now().timestamp()) {
+        Some(step) => two_factor
+            .use_totp_step(user_id, step)
+            .await
+            .map_err(http_error),
+        None => Ok(false),
+    }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct SearchUser {
-    phone: String,
+fn hash_recovery_code(code: &str) -> String {
+    sha256_hex(&code.replace('-', "").to_lowercase())
 }
 
-pub(crate) async fn search_user<F>(
-    friends_store: Data<F>,
+const RECOVERY_CODES: usize = 10;
+const TOTP_ISSUER: &str = "WebRTC Signal";
+
+/// Makes a fresh set of recovery codes and returns them with their hashes.
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = generate_recovery_code();
+            let hash = hash_recovery_code(&code);
+            (code, hash)
+        })
+        .unzip()
+}
+
+pub(crate) async fn my_two_factor<F>(
+    two_factor: Data<F>,
     UserID(uid): UserID,
-    Query(SearchUser { phone }): Query<SearchUser>,
-) -> Result<Json<Option<User>>>
+) -> Result<Json<TwoFactorStatus>>
 where
-    F: Repository,
+    F: TwoFactorStore,
 {
     Ok(Json(
-        friends_store
-            .search_user(&uid, &phone)
+        two_factor
+            .two_factor_status(&uid)
             .await
-            .map_err(ErrorInternalServerError)?,
+            .map_err(http_error)?,
     ))
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct AddFriend {
-    friend_id: String,
-}
-
 #[derive(Debug, Serialize)]
-pub(crate) struct AddFriendResp {
-    id: String,
+pub(crate) struct TwoFactorEnrollment {
+    secret: String,
+    otpauth_uri: String,
 }
 
-pub(crate) async fn add_friend<R, N, S>(
+/// Starts 2FA enrollment. It only takes effect once a code from the
+/// authenticator app is confirmed.
+pub(crate) async fn enroll_two_factor<R, F>(
     repo: Data<R>,
-    addrs: Data<S>,
+    two_factor: Data<F>,
     UserID(uid): UserID,
-    Json(AddFriend { friend_id }): Json<AddFriend>,
-) -> Result<Json<AddFriendResp>>
+) -> Result<Json<TwoFactorEnrollment>>
 where
-    R: Repository + Clone + Unpin + 'static,
-    N: Notifier + Clone + Unpin + 'static,
-    S: AddrStore + Clone + Unpin + 'static,
+    R: Repository,
+    F: TwoFactorStore,
 {
-    let id = repo
-        .add_friend_request(&uid, &friend_id)
-        .await
-        .map_err(ErrorInternalServerError)?;
-    let user = repo
+    let me = repo
         .get_user(&uid)
         .await
         .map_err(ErrorInternalServerError)?;
-    if let Some(addr) = addrs
-        .get_addr(&friend_id)
+    let totp = Totp::generate();
+    let secret = totp.encoded();
+    two_factor
+        .save_pending_two_factor(&uid, &secret)
         .await
-        .map_err(ErrorInternalServerError)?
-    {
-        addr.do_send(Message::System(SystemMessage::FriendRequest {
-            id: id.clone(),
-            phone: user.phone,
-            avatar: user.avatar,
-        }))
-    }
-    Ok(Json(AddFriendResp { id }))
+        .map_err(http_error)?;
+    Ok(Json(TwoFactorEnrollment {
+        otpauth_uri: totp.uri(TOTP_ISSUER, &me.phone),
+        secret,
+    }))
 }
 
-pub(crate) async fn accept_request<R, N, S>(
-    friends_store: Data<R>,
-    addrs: Data<S>,
+#[derive(Debug, Deserialize)]
+pub(crate) struct TwoFactorCode {
+    code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RecoveryCodes {
+    /// Shown once, only their hashes are kept.
+    recovery_codes: Vec<String>,
+}
+
+pub(crate) async fn confirm_two_factor<F>(
+    two_factor: Data<F>,
     UserID(uid): UserID,
-    id: Path<(String,)>,
-) -> Result<HttpResponse>
+    Json(TwoFactorCode { code }): Json<TwoFactorCode>,
+) -> Result<Json<RecoveryCodes>>
 where
-    R: Repository + Clone + Unpin + 'static,
-    N: Notifier + Clone + Unpin + 'static,
-    S: AddrStore + Clone + Unpin + 'static,
+    F: TwoFactorStore,
 {
-    let req = friends_store
-        .get_friend_request(&id.0)
-        .await
-        .map_err(ErrorInternalServerError)?;
-    if req.to != uid {
-        return Err(ErrorForbidden("not your request"));
+    if !verify_second_factor(two_factor.as_ref(), &uid, &code).await? {
+        return Err(ErrorForbidden("invalid 2fa code"));
     }
-    friends_store
-        .accept_friend_request(&id.0)
+    let (recovery_codes, hashes) = generate_recovery_codes();
+    two_factor
+        .enable_two_factor(&uid, &hashes)
         .await
-        .map_err(ErrorInternalServerError)?;
-    if let Some(addr) = addrs
-        .get_addr(&req.from)
+        .map_err(http_error)?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+async fn require_two_factor_code<F>(
+    two_factor: &F,
+    uid: &str,
+    code: &str,
+) -> Result<()>
+where
+    F: TwoFactorStore,
+{
+    if !two_factor
+        .two_factor_status(uid)
         .await
-        .map_err(ErrorInternalServerError)?
+        .map_err(http_error)?
+        .enabled
     {
-        addr.do_send(Message::System(SystemMessage::FriendAccept {
-            id: id.0.to_owned(),
-        }));
+        return Err(ErrorNotFound("2fa is not enabled"));
     }
-    Ok(HttpResponse::new(StatusCode::OK))
+    if !verify_second_factor(two_factor, uid, code).await? {
+        return Err(ErrorForbidden("invalid 2fa code"));
+    }
+    Ok(())
 }
 
-pub(crate) async fn reject_request<F>(
-    friends_store: Data<F>,
+pub(crate) async fn disable_two_factor<F>(
+    two_factor: Data<F>,
     UserID(uid): UserID,
-    id: Path<(String,)>,
+    Json(TwoFactorCode { code }): Json<TwoFactorCode>,
 ) -> Result<HttpResponse>
 where
-    F: Repository,
+    F: TwoFactorStore,
 {
-    let req = friends_store
-        .get_friend_request(&id.0)
-        .await
-        .map_err(ErrorInternalServerError)?;
-    if req.to != uid {
-        return Err(ErrorForbidden("not your request"));
-    }
-    friends_store
-        .reject_friend_request(&id.0)
+    require_two_factor_code(two_factor.as_ref(), &uid, &code).await?;
+    two_factor
+        .disable_two_factor(&uid)
         .await
-        .map_err(ErrorInternalServerError)?;
-    Ok(HttpResponse::new(StatusCode::OK))
+        .map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
 }
 
-pub(crate) async fn my_requests<F>(
-    friends_store: Data<F>,
+pub(crate) async fn regenerate_recovery_codes<F>(
+    two_factor: Data<F>,
     UserID(uid): UserID,
-) -> Result<Json<Vec<crate::core::repository::FriendRequest>>>
+    Json(TwoFactorCode { code }): Json<TwoFactorCode>,
+) -> Result<Json<RecoveryCodes>>
 where
-    F: Repository,
+    F: TwoFactorStore,
 {
-    let reqs = friends_store
-        .pending_friend_requests(&uid)
+    require_two_factor_code(two_factor.as_ref(), &uid, &code).await?;
+    let (recovery_codes, hashes) = generate_recovery_codes();
+    two_factor
+        .replace_recovery_codes(&uid, &hashes)
         .await
-        .map_err(ErrorInternalServerError)?;
-    Ok(Json(reqs))
+        .map_err(http_error)?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
 }
 
-#[derive(Debug, Serialize)]
-pub(crate) struct NumOfFriendRequestsResp {
-    count: usize,
+#[derive(Debug, Deserialize)]
+pub(crate) struct RefreshToken {
+    refresh_token: String,
 }
 
-pub(crate) async fn num_of_friend_requests<F>(
-    friends_store: Data<F>,
-    UserID(uid): UserID,
-) -> Result<Json<NumOfFriendRequestsResp>>
+pub(crate) async fn refresh_token<T, S>(
+    token_manager: Data<T>,
+    sessions: Data<S>,
+    lifetimes: Data<TokenLifetimes>,
+    Json(RefreshToken { refresh_token }): Json<RefreshToken>,
+) -> Result<Json<LoginResp>>
 where
-    F: Repository,
+    T: TokenManager,
+    S: LoginSessionStore,
 {
-    let count = friends_store
-        .pending_friend_requests(&uid)
+    let key = Uuid::new_v4().to_string();
+    let new_refresh_token = generate_refresh_token();
+    let session_id = sessions
+        .rotate_refresh_token(
+            &sha256_hex(&refresh_token),
+            &sha256_hex(&new_refresh_token),
+            &key,
+            **lifetimes,
+        )
         .await
-        .map_err(ErrorInternalServerError)?
-        .len();
-    Ok(Json(NumOfFriendRequestsResp { count }))
+        .map_err(http_error)?;
+    let token = token_manager
+        .generate_token(&key)
+        .map_err(ErrorInternalServerError)?;
+    sessions
+        .bind_token(&session_id, &sha256_hex(&token))
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(Json(LoginResp {
+        token,
+        refresh_token: new_refresh_token,
+        expires_in: lifetimes.access.num_seconds(),
+    }))
+}
+
+/// Turns `e` into a response with the status code it carries.
+fn http_error(e: Error) -> actix_web::Error {
+    let status = StatusCode::from_u16(e.status_code)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    InternalError::new(e, status).into()
+}
+
+const VERIFICATION_CODE_TTL_MINUTES: i64 = 5;
+const VERIFICATION_CODE_COOLDOWN_SECONDS: i64 = 60;
+const VERIFICATION_MAX_ATTEMPTS: i32 = 5;
+
+fn generate_verification_code() -> String {
+    // v4 uuids come from the os random number generator
+    format!("{:06}", Uuid::new_v4().as_u128() % 1_000_000)
+}
+
+fn hash_verification_code(phone: &str, code: &str) -> String {
+    sha256_hex(&format!("{}:{}", phone, code))
 }
 
 #[derive(Debug, Deserialize)]
-pub(crate) struct ChatMessageHistory {
-    to: String,
-    before: Option<String>,
+pub(crate) struct RequestVerificationCode {
+    phone: String,
 }
 
-pub(crate) async fn chat_message_history<F>(
-    repository: Data<F>,
-    UserID(uid): UserID,
-    Query(ChatMessageHistory { to, before }): Query<ChatMessageHistory>,
-) -> Result<Json<Vec<RepoChatMessage>>>
+pub(crate) async fn request_signup_code<V, M>(
+    verifications: Data<V>,
+    sms_sender: Data<M>,
+    phones: Data<PhoneNormalizer>,
+    Json(RequestVerificationCode { phone }): Json<RequestVerificationCode>,
+) -> Result<HttpResponse>
 where
-    F: Repository,
+    V: VerificationStore + Clone + 'static,
+    M: SmsSender + Clone + 'static,
 {
-    let messages = repository
-        .chat_message_history(&uid, &to, 20, before.as_deref())
-        .await
-        .map_err(ErrorInternalServerError)?;
-    Ok(Json(messages))
+    let phone = phones.normalize(&phone).map_err(http_error)?;
+    send_verification_code(
+        verifications.as_ref(),
+        sms_sender.as_ref(),
+        &phone,
+        VerificationPurpose::Signup,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[derive(Debug, Serialize)]
+async fn send_verification_code<V, M>(
+    verifications: &V,
+    sms_sender: &M,
+    phone: &str,
+    purpose: VerificationPurpose,
+) -> Result<()>
+where
+    V: VerificationStore,
+    M: SmsSender,
+{
+    let code = generate_verification_code();
+    verifications
+        .save_code(
+            phone,
+            purpose,
+            &hash_verification_code(phone, &code),
+            Duration::minutes(VERIFICATION_CODE_TTL_MINUTES),
+            Duration::seconds(VERIFICATION_CODE_COOLDOWN_SECONDS),
+        )
+        .await
+        .map_err(http_error)?;
+    sms_sender
+        .send_sms(
+            phone,
+            &format!(
+                "Your verification code is {}, it expires in {} minutes.",
+                code, VERIFICATION_CODE_TTL_MINUTES
+            ),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Signup {
+    phone: String,
+    password: String,
+    code: String,
+}
+
+pub(crate) async fn signup<R, H, T, V>(
+    auth_service: Data<AuthService<R, H, T>>,
+    auth_repo: Data<R>,
+    verifications: Data<V>,
+    phones: Data<PhoneNormalizer>,
+    Json(Signup {
+        phone,
+        password,
+        code,
+    }): Json<Signup>,
+) -> Result<HttpResponse>
+where
+    R: AuthRepository + Clone + 'static,
+    H: Hasher + Clone,
+    T: TokenManager + Clone,
+    V: VerificationStore + Clone + 'static,
+{
+    let phone = phones.normalize(&phone).map_err(http_error)?;
+    // before the code is used up, so it still works for another try
+    if auth_repo
+        .exists_user(&phone)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorConflict("phone already registered"));
+    }
+    verifications
+        .consume_code(
+            &phone,
+            VerificationPurpose::Signup,
+            &hash_verification_code(&phone, &code),
+            VERIFICATION_MAX_ATTEMPTS,
+        )
+        .await
+        .map_err(http_error)?;
+    auth_service
+        .signup(&phone, &password)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn request_password_reset_code<R, V, M>(
+    auth_repo: Data<R>,
+    verifications: Data<V>,
+    sms_sender: Data<M>,
+    phones: Data<PhoneNormalizer>,
+    Json(RequestVerificationCode { phone }): Json<RequestVerificationCode>,
+) -> Result<HttpResponse>
+where
+    R: AuthRepository + Clone + 'static,
+    V: VerificationStore + Clone + 'static,
+    M: SmsSender + Clone + 'static,
+{
+    let phone = phones.normalize(&phone).map_err(http_error)?;
+    // answer the same either way so that nobody can probe for accounts
+    if auth_repo
+        .exists_user(&phone)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        send_verification_code(
+            verifications.as_ref(),
+            sms_sender.as_ref(),
+            &phone,
+            VerificationPurpose::ResetPassword,
+        )
+        .await?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ResetPassword {
+    phone: String,
+    code: String,
+    password: String,
+}
+
+pub(crate) async fn reset_password<R, H, T, V, C>(
+    auth_service: Data<AuthService<R, H, T>>,
+    hasher: Data<H>,
+    verifications: Data<V>,
+    credentials: Data<C>,
+    phones: Data<PhoneNormalizer>,
+    Json(ResetPassword {
+        phone,
+        code,
+        password,
+    }): Json<ResetPassword>,
+) -> Result<HttpResponse>
+where
+    R: AuthRepository + Clone,
+    H: Hasher + Clone + 'static,
+    T: TokenManager + Clone,
+    V: VerificationStore + Clone + 'static,
+    C: CredentialStore + Clone + 'static,
+{
+    let phone = phones.normalize(&phone).map_err(http_error)?;
+    verifications
+        .consume_code(
+            &phone,
+            VerificationPurpose::ResetPassword,
+            &hash_verification_code(&phone, &code),
+            VERIFICATION_MAX_ATTEMPTS,
+        )
+        .await
+        .map_err(http_error)?;
+    set_password(hasher.as_ref(), credentials.as_ref(), &phone, &password)
+        .await
+        .map_err(http_error)?;
+    // tokens issued with the old password stop working
+    auth_service
+        .logout(&phone)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct FriendsQuery {
+    label: Option<String>,
+    #[serde(default)]
+    sort: FriendSort,
+}
+
+pub(crate) async fn my_friends<R>(
+    UserID(uid): UserID,
+    repo: Data<R>,
+    Query(FriendsQuery { label, sort }): Query<FriendsQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<PageResp<User>>>
+where
+    R: Repository,
+{
+    let after = page.after(sort)?;
+    let friends = repo
+        .friends_page(&uid, label.as_deref(), sort, after.as_ref(), page.limit)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let total = repo
+        .count_friends(&uid, label.as_deref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(Json(PageResp::new(friends, total)?))
+}
+
+const FRIEND_LABEL_MAX_CHARS: usize = 32;
+const FRIEND_LABELS_MAX: usize = 16;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UpdateFriendMetadata {
+    nickname: Option<String>,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(default)]
+    favorite: bool,
+}
+
+pub(crate) async fn my_friend_metadata<M>(
+    metadata: Data<M>,
+    UserID(uid): UserID,
+) -> Result<Json<Vec<FriendMetadata>>>
+where
+    M: FriendMetadataStore,
+{
+    Ok(Json(
+        metadata
+            .all_friend_metadata(&uid)
+            .await
+            .map_err(http_error)?,
+    ))
+}
+
+pub(crate) async fn my_friend_labels<M>(
+    metadata: Data<M>,
+    UserID(uid): UserID,
+) -> Result<Json<Vec<String>>>
+where
+    M: FriendMetadataStore,
+{
+    Ok(Json(
+        metadata.friend_labels(&uid).await.map_err(http_error)?,
+    ))
+}
+
+pub(crate) async fn friend_metadata<M>(
+    metadata: Data<M>,
+    UserID(uid): UserID,
+    friend_id: Path<String>,
+) -> Result<Json<FriendMetadata>>
+where
+    M: FriendMetadataStore,
+{
+    Ok(Json(
+        metadata
+            .friend_metadata(&uid, &friend_id)
+            .await
+            .map_err(http_error)?,
+    ))
+}
+
+/// Replaces the nickname, labels and favorite flag of a friend.
+pub(crate) async fn update_friend_metadata<R, M>(
+    repo: Data<R>,
+    metadata: Data<M>,
+    UserID(uid): UserID,
+    friend_id: Path<String>,
+    Json(UpdateFriendMetadata {
+        nickname,
+        labels,
+        favorite,
+    }): Json<UpdateFriendMetadata>,
+) -> Result<Json<FriendMetadata>>
+where
+    R: Repository,
+    M: FriendMetadataStore,
+{
+    let friend_id = friend_id.into_inner();
+    if !repo
+        .is_friend(&uid, &friend_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("not a friend"));
+    }
+    let nickname =
+        profile_text("nickname", nickname, DISPLAY_NAME_MAX_CHARS, false)?;
+    let mut checked = Vec::with_capacity(labels.len());
+    for label in labels {
+        let Some(label) =
+            profile_text("label", Some(label), FRIEND_LABEL_MAX_CHARS, false)?
+        else {
+            continue;
+        };
+        if !checked.contains(&label) {
+            checked.push(label);
+        }
+    }
+    if checked.len() > FRIEND_LABELS_MAX {
+        return Err(ErrorUnprocessableEntity(format!(
+            "a friend can have at most {} labels",
+            FRIEND_LABELS_MAX
+        )));
+    }
+    let friend_metadata = FriendMetadata {
+        friend_id,
+        nickname,
+        labels: checked,
+        favorite,
+    };
+    metadata
+        .update_friend_metadata(&uid, &friend_metadata)
+        .await
+        .map_err(http_error)?;
+    Ok(Json(friend_metadata))
+}
+
+pub(crate) async fn delete_friend_metadata<M>(
+    metadata: Data<M>,
+    UserID(uid): UserID,
+    friend_id: Path<String>,
+) -> Result<HttpResponse>
+where
+    M: FriendMetadataStore,
+{
+    metadata
+        .delete_friend_metadata(&uid, &friend_id)
+        .await
+        .map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Serialize)]
+pub enum UserType {
+    Stranger,
+    Friend,
+    Myself,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchUser {
+    phone: String,
+}
+
+pub(crate) async fn search_user<F>(
+    friends_store: Data<F>,
+    phones: Data<PhoneNormalizer>,
+    UserID(uid): UserID,
+    Query(SearchUser { phone }): Query<SearchUser>,
+) -> Result<Json<Option<User>>>
+where
+    F: Repository,
+{
+    let Ok(phone) = phones.normalize(&phone) else {
+        return Ok(Json(None));
+    };
+    Ok(Json(
+        friends_store
+            .search_user(&uid, &phone)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+pub(crate) async fn contact_salt<C>(
+    contacts: Data<C>,
+) -> Result<Json<ContactSalt>>
+where
+    C: ContactStore,
+{
+    contacts
+        .current_contact_salt()
+        .await
+        .map_err(http_error)?
+        .map(Json)
+        .ok_or_else(|| ErrorServiceUnavailable("no contact salt yet"))
+}
+
+const CONTACT_SYNC_MAX_HASHES: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SyncContacts {
+    salt_id: String,
+    hashes: Vec<String>,
+}
+
+/// Tells which of the uploaded contacts are registered. Clients hash the
+/// normalized phone of each contact with the current salt, see
+/// `contact_hash`, and get a 409 once the salt expired.
+pub(crate) async fn sync_contacts<C>(
+    contacts: Data<C>,
+    UserID(uid): UserID,
+    Json(SyncContacts {
+        salt_id,
+        mut hashes,
+    }): Json<SyncContacts>,
+) -> Result<Json<Vec<DiscoveredContact>>>
+where
+    C: ContactStore,
+{
+    if hashes.len() > CONTACT_SYNC_MAX_HASHES {
+        return Err(ErrorUnprocessableEntity(format!(
+            "at most {} contacts can be synced at once",
+            CONTACT_SYNC_MAX_HASHES
+        )));
+    }
+    for hash in &mut hashes {
+        hash.make_ascii_lowercase();
+        if hash.len() != CONTACT_HASH_LEN
+            || !hash.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(ErrorUnprocessableEntity("invalid contact hash"));
+        }
+    }
+    hashes.sort();
+    hashes.dedup();
+    let Some(salt) =
+        contacts.contact_salt(&salt_id).await.map_err(http_error)?
+    else {
+        return Err(ErrorConflict("contact salt expired"));
+    };
+    Ok(Json(
+        contacts
+            .discover_contacts(&uid, &salt, &hashes)
+            .await
+            .map_err(http_error)?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AddFriend {
+    friend_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AddFriendResp {
+    id: String,
+}
+
+pub(crate) async fn add_friend<R, P, B, S>(
+    repo: Data<R>,
+    addrs: Data<S>,
+    prefs: Data<P>,
+    blocks: Data<B>,
+    UserID(uid): UserID,
+    Json(AddFriend { friend_id }): Json<AddFriend>,
+) -> Result<Json<AddFriendResp>>
+where
+    R: Repository + Clone + Unpin + 'static,
+    P: NotificationPreferences + Clone + Unpin + 'static,
+    B: BlockStore,
+    S: AddrStore + Clone + Unpin + 'static,
+{
+    check_not_blocked(blocks.as_ref(), &uid, &friend_id).await?;
+    let id = send_friend_request(
+        repo.as_ref(),
+        addrs.as_ref(),
+        prefs.as_ref(),
+        &uid,
+        &friend_id,
+    )
+    .await?;
+    Ok(Json(AddFriendResp { id }))
+}
+
+/// Sends a friend request from `uid` to `friend_id` and tells the recipient,
+/// live if they are online and by a push otherwise.
+async fn send_friend_request<R, P, S>(
+    repo: &R,
+    addrs: &S,
+    prefs: &P,
+    uid: &str,
+    friend_id: &str,
+) -> Result<String>
+where
+    R: Repository,
+    P: NotificationPreferences,
+    S: AddrStore,
+{
+    let user = repo.get_user(uid).await.map_err(ErrorInternalServerError)?;
+    let addr = addrs
+        .get_addr(friend_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let push = match addr {
+        Some(_) => None,
+        None => {
+            // the store adds the id of the request
+            push_notification(
+                prefs,
+                uid,
+                friend_id,
+                &NotificationEvent::FriendRequest {
+                    sender: &user.phone,
+                },
+                [("typ", "FriendRequest")]
+                    .into_iter()
+                    .collect::<HashMap<&str, &str>>(),
+                Duration::weeks(1),
+            )
+            .await
+        }
+    };
+    let id = repo
+        .add_friend_request(uid, friend_id, push.as_ref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if let Some(addr) = addr {
+        addr.do_send(Message::System(SystemMessage::FriendRequest {
+            id: id.clone(),
+            phone: user.phone,
+            avatar: user.avatar,
+        }))
+    }
+    Ok(id)
+}
+
+pub(crate) async fn accept_request<R, P, B, S>(
+    friends_store: Data<R>,
+    addrs: Data<S>,
+    prefs: Data<P>,
+    blocks: Data<B>,
+    UserID(uid): UserID,
+    id: Path<(String,)>,
+) -> Result<HttpResponse>
+where
+    R: Repository + Clone + Unpin + 'static,
+    P: NotificationPreferences + Clone + Unpin + 'static,
+    B: BlockStore,
+    S: AddrStore + Clone + Unpin + 'static,
+{
+    let req = friends_store
+        .get_friend_request(&id.0)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if req.to != uid {
+        return Err(ErrorForbidden("not your request"));
+    }
+    check_not_blocked(blocks.as_ref(), &uid, &req.from).await?;
+    let addr = addrs
+        .get_addr(&req.from)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let push = match addr {
+        Some(_) => None,
+        None => {
+            let me = friends_store
+                .get_user(&uid)
+                .await
+                .map_err(ErrorInternalServerError)?;
+            push_notification(
+                prefs.as_ref(),
+                &uid,
+                &req.from,
+                &NotificationEvent::FriendAccept { sender: &me.phone },
+                [("id", id.0.clone()), ("typ", "FriendAccept".into())]
+                    .into_iter()
+                    .collect::<HashMap<&str, String>>(),
+                Duration::weeks(1),
+            )
+            .await
+        }
+    };
+    friends_store
+        .accept_friend_request(&id.0, push.as_ref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if let Some(addr) = addr {
+        addr.do_send(Message::System(SystemMessage::FriendAccept {
+            id: id.0.to_owned(),
+        }));
+    }
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+pub(crate) async fn reject_request<F>(
+    friends_store: Data<F>,
+    UserID(uid): UserID,
+    id: Path<(String,)>,
+) -> Result<HttpResponse>
+where
+    F: Repository,
+{
+    let req = friends_store
+        .get_friend_request(&id.0)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if req.to != uid {
+        return Err(ErrorForbidden("not your request"));
+    }
+    friends_store
+        .reject_friend_request(&id.0)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::new(StatusCode::OK))
+}
+
+pub(crate) async fn my_requests<F>(
+    friends_store: Data<F>,
+    UserID(uid): UserID,
+    Query(SortQuery { sort }): Query<SortQuery>,
+    Query(page): Query<Pagination>,
+) -> Result<Json<PageResp<crate::core::repository::FriendRequest>>>
+where
+    F: Repository,
+{
+    let after = page.after(sort)?;
+    let reqs = friends_store
+        .pending_friend_requests(&uid, sort, after.as_ref(), page.limit)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let total = friends_store
+        .count_pending_friend_requests(&uid)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(Json(PageResp::new(reqs, total)?))
+}
+
+pub(crate) async fn my_outgoing_requests<F>(
+    friends_store: Data<F>,
+    UserID(uid): UserID,
+) -> Result<Json<Vec<crate::core::repository::FriendRequest>>>
+where
+    F: Repository,
+{
+    let reqs = friends_store
+        .outgoing_friend_requests(&uid)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(Json(reqs))
+}
+
+/// Withdraws a pending request the user sent.
+pub(crate) async fn cancel_request<F, S>(
+    friends_store: Data<F>,
+    addrs: Data<S>,
+    UserID(uid): UserID,
+    id: Path<(String,)>,
+) -> Result<HttpResponse>
+where
+    F: Repository,
+    S: AddrStore,
+{
+    let Some(to) = friends_store
+        .cancel_friend_request(&uid, &id.0)
+        .await
+        .map_err(ErrorInternalServerError)?
+    else {
+        return Err(ErrorNotFound("no such pending request"));
+    };
+    if let Some(addr) = addrs
+        .get_addr(&to)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        addr.do_send(Message::System(SystemMessage::FriendRequestCancelled {
+            id: id.0.to_owned(),
+        }));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn friend_suggestions<F>(
+    friends_store: Data<F>,
+    UserID(uid): UserID,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<FriendSuggestion>>>
+where
+    F: Repository,
+{
+    page.validate()?;
+    Ok(Json(
+        friends_store
+            .friend_suggestions(&uid, page.limit, page.offset)
+            .await
+            .map_err(http_error)?,
+    ))
+}
+
+pub(crate) async fn unfriend<F, S>(
+    friends_store: Data<F>,
+    addrs: Data<S>,
+    UserID(uid): UserID,
+    friend_id: Path<(String,)>,
+) -> Result<HttpResponse>
+where
+    F: Repository,
+    S: AddrStore,
+{
+    if !friends_store
+        .unfriend(&uid, &friend_id.0)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("not a friend"));
+    }
+    if let Some(addr) = addrs
+        .get_addr(&friend_id.0)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        addr.do_send(Message::System(SystemMessage::FriendRemoved { id: uid }));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct FriendInviteResp {
+    #[serde(flatten)]
+    invite: FriendInvite,
+    link: String,
+}
+
+impl FriendInviteResp {
+    fn new(invite: FriendInvite, links: &InviteLinks) -> Self {
+        let link = links.link(&invite.token);
+        Self { invite, link }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateFriendInvite {
+    max_uses: Option<i32>,
+    expires_at: Option<DateTime<Utc>>,
+    /// Defaults to true.
+    auto_accept: Option<bool>,
+}
+
+pub(crate) async fn create_friend_invite<I>(
+    invites: Data<I>,
+    links: Data<InviteLinks>,
+    UserID(uid): UserID,
+    Json(CreateFriendInvite {
+        max_uses,
+        expires_at,
+        auto_accept,
+    }): Json<CreateFriendInvite>,
+) -> Result<Json<FriendInviteResp>>
+where
+    I: FriendInviteStore,
+{
+    if max_uses.is_some_and(|n| n < 1) {
+        return Err(ErrorUnprocessableEntity("max_uses must be positive"));
+    }
+    if expires_at.is_some_and(|t| t <= Utc::now()) {
+        return Err(ErrorUnprocessableEntity(
+            "expires_at must be in the future",
+        ));
+    }
+    let invite = invites
+        .create_friend_invite(
+            &uid,
+            &NewFriendInvite {
+                token: Uuid::new_v4().simple().to_string(),
+                max_uses,
+                auto_accept: auto_accept.unwrap_or(true),
+                expires_at,
+            },
+        )
+        .await
+        .map_err(http_error)?;
+    Ok(Json(FriendInviteResp::new(invite, &links)))
+}
+
+pub(crate) async fn my_friend_invites<I>(
+    invites: Data<I>,
+    links: Data<InviteLinks>,
+    UserID(uid): UserID,
+) -> Result<Json<Vec<FriendInviteResp>>>
+where
+    I: FriendInviteStore,
+{
+    Ok(Json(
+        invites
+            .friend_invites(&uid)
+            .await
+            .map_err(http_error)?
+            .into_iter()
+            .map(|invite| FriendInviteResp::new(invite, &links))
+            .collect(),
+    ))
+}
+
+pub(crate) async fn revoke_friend_invite<I>(
+    invites: Data<I>,
+    UserID(uid): UserID,
+    id: Path<String>,
+) -> Result<HttpResponse>
+where
+    I: FriendInviteStore,
+{
+    if !invites
+        .revoke_friend_invite(&uid, &id)
+        .await
+        .map_err(http_error)?
+    {
+        return Err(ErrorNotFound("invite not found"));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+const FRIEND_INVITE_QR_SIZE: u32 = 512;
+
+/// The link of the invite as a QR code PNG.
+pub(crate) async fn friend_invite_qr_code<I>(
+    invites: Data<I>,
+    links: Data<InviteLinks>,
+    UserID(uid): UserID,
+    id: Path<String>,
+) -> Result<HttpResponse>
+where
+    I: FriendInviteStore,
+{
+    let Some(invite) = invites
+        .friend_invite(&uid, &id)
+        .await
+        .map_err(http_error)?
+        .filter(|invite| invite.revoked_at.is_none())
+    else {
+        return Err(ErrorNotFound("invite not found"));
+    };
+    let code = QrCode::new(links.link(&invite.token))
+        .map_err(ErrorInternalServerError)?;
+    let image = code
+        .render::<Luma<u8>>()
+        .min_dimensions(FRIEND_INVITE_QR_SIZE, FRIEND_INVITE_QR_SIZE)
+        .build();
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RedeemFriendInvite {
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RedeemFriendInviteResp {
+    /// The friend request.
+    id: String,
+    friend_id: String,
+    /// False if the request waits for the inviter.
+    accepted: bool,
+}
+
+/// Makes friends with the inviter, or sends them a request if the invite
+/// doesn't accept right away.
+pub(crate) async fn redeem_friend_invite<R, I, P, B, S>(
+    repo: Data<R>,
+    invites: Data<I>,
+    addrs: Data<S>,
+    prefs: Data<P>,
+    blocks: Data<B>,
+    UserID(uid): UserID,
+    Json(RedeemFriendInvite { token }): Json<RedeemFriendInvite>,
+) -> Result<Json<RedeemFriendInviteResp>>
+where
+    R: Repository,
+    I: FriendInviteStore,
+    P: NotificationPreferences,
+    B: BlockStore,
+    S: AddrStore,
+{
+    let Some(invite) = invites
+        .usable_friend_invite(&token)
+        .await
+        .map_err(http_error)?
+    else {
+        return Err(ErrorNotFound("invite not found"));
+    };
+    let friend_id = invite.user_id;
+    if friend_id == uid {
+        return Err(ErrorUnprocessableEntity("can't redeem your own invite"));
+    }
+    check_not_blocked(blocks.as_ref(), &uid, &friend_id).await?;
+    // a new request would turn the friendship back into a pending request
+    if repo.is_friend(&uid, &friend_id).await.map_err(http_error)? {
+        return Err(ErrorConflict("already friends"));
+    }
+    let me = repo.get_user(&uid).await.map_err(http_error)?;
+    let addr = addrs.get_addr(&friend_id).await.map_err(http_error)?;
+    let (event, typ) = if invite.auto_accept {
+        (
+            NotificationEvent::FriendAccept { sender: &me.phone },
+            "FriendInviteRedeemed",
+        )
+    } else {
+        (
+            NotificationEvent::FriendRequest { sender: &me.phone },
+            "FriendRequest",
+        )
+    };
+    let push = match addr {
+        Some(_) => None,
+        // the store adds the id of the request
+        None => {
+            push_notification(
+                prefs.as_ref(),
+                &uid,
+                &friend_id,
+                &event,
+                [("typ", typ)].into_iter().collect::<HashMap<&str, &str>>(),
+                Duration::weeks(1),
+            )
+            .await
+        }
+    };
+    let Some(id) = invites
+        .redeem_friend_invite(
+            &invite.id,
+            &uid,
+            invite.auto_accept,
+            push.as_ref(),
+        )
+        .await
+        .map_err(http_error)?
+    else {
+        return Err(ErrorNotFound("invite not found"));
+    };
+    if let Some(addr) = addr {
+        addr.do_send(Message::System(if invite.auto_accept {
+            SystemMessage::FriendInviteRedeemed {
+                id: id.clone(),
+                invite_id: invite.id,
+                from: uid,
+                phone: me.phone,
+                avatar: me.avatar,
+            }
+        } else {
+            SystemMessage::FriendRequest {
+                id: id.clone(),
+                phone: me.phone,
+                avatar: me.avatar,
+            }
+        }));
+    }
+    Ok(Json(RedeemFriendInviteResp {
+        id,
+        friend_id,
+        accepted: invite.auto_accept,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct NumOfFriendRequestsResp {
+    count: i64,
+}
+
+pub(crate) async fn num_of_friend_requests<F>(
+    friends_store: Data<F>,
+    UserID(uid): UserID,
+) -> Result<Json<NumOfFriendRequestsResp>>
+where
+    F: Repository,
+{
+    let count = friends_store
+        .count_pending_friend_requests(&uid)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(Json(NumOfFriendRequestsResp { count }))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChatMessageHistory {
+    to: String,
+    before: Option<String>,
+}
+
+pub(crate) async fn chat_message_history<F>(
+    repository: Data<F>,
+    UserID(uid): UserID,
+    Query(ChatMessageHistory { to, before }): Query<ChatMessageHistory>,
+) -> Result<Json<Vec<RepoChatMessage>>>
+where
+    F: Repository,
+{
+    let messages = repository
+        .chat_message_history(&uid, &to, 20, before.as_deref())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(Json(messages))
+}
+
+#[derive(Debug, Serialize)]
 pub(crate) struct UploadResponse {
     ids: Vec<String>,
 }
@@ -350,15 +1796,138 @@ pub(crate) struct UpsertAvatarRequest {
 
 pub(crate) async fn upsert_avatar(
     repo: Data<PostgresRepository>,
+    addrs: Data<AddrMap>,
     UserID(uid): UserID,
     Json(UpsertAvatarRequest { upload_id }): Json<UpsertAvatarRequest>,
 ) -> Result<HttpResponse> {
     repo.update_avatar(&uid, &upload_id)
         .await
         .map_err(ErrorInternalServerError)?;
+    let me = repo
+        .get_user(&uid)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    push_profile_update(repo.as_ref(), addrs.as_ref(), &me).await?;
     Ok(HttpResponse::new(StatusCode::OK))
 }
 
+const DISPLAY_NAME_MAX_CHARS: usize = 64;
+const BIO_MAX_CHARS: usize = 280;
+
+/// Tells a field set to `null` apart from a missing one.
+fn present<'de, D, T>(
+    deserializer: D,
+) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UpdateProfile {
+    #[serde(default, deserialize_with = "present")]
+    display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    bio: Option<Option<String>>,
+}
+
+/// Trims the text, an empty text clears the field.
+fn profile_text(
+    field: &str,
+    text: Option<String>,
+    max_chars: usize,
+    multiline: bool,
+) -> Result<Option<String>> {
+    let Some(text) = text else {
+        return Ok(None);
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if text.chars().count() > max_chars {
+        return Err(ErrorUnprocessableEntity(format!(
+            "{} must be at most {} characters",
+            field, max_chars
+        )));
+    }
+    if text
+        .chars()
+        .any(|c| c.is_control() && !(multiline && c == '\n'))
+    {
+        return Err(ErrorUnprocessableEntity(format!(
+            "{} must not contain control characters",
+            field
+        )));
+    }
+    Ok(Some(text.to_owned()))
+}
+
+/// Updates the fields that are present, `null` clears a field.
+pub(crate) async fn update_profile<R, S>(
+    repo: Data<R>,
+    addrs: Data<S>,
+    UserID(uid): UserID,
+    Json(UpdateProfile { display_name, bio }): Json<UpdateProfile>,
+) -> Result<Json<User>>
+where
+    R: Repository,
+    S: AddrStore,
+{
+    let update = ProfileUpdate {
+        display_name: display_name
+            .map(|name| {
+                profile_text(
+                    "display_name",
+                    name,
+                    DISPLAY_NAME_MAX_CHARS,
+                    false,
+                )
+            })
+            .transpose()?,
+        bio: bio
+            .map(|bio| profile_text("bio", bio, BIO_MAX_CHARS, true))
+            .transpose()?,
+    };
+    let me = repo
+        .update_profile(&uid, &update)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    push_profile_update(repo.as_ref(), addrs.as_ref(), &me).await?;
+    Ok(Json(me))
+}
+
+/// Tells the online friends about the new profile, the others pick it up
+/// with the friend list.
+async fn push_profile_update<R, S>(repo: &R, addrs: &S, me: &User) -> Result<()>
+where
+    R: Repository,
+    S: AddrStore,
+{
+    let friends = repo
+        .friends(&me.id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    for friend in friends {
+        if let Some(addr) = addrs
+            .get_addr(&friend.id)
+            .await
+            .map_err(ErrorInternalServerError)?
+        {
+            addr.do_send(Message::System(SystemMessage::ProfileUpdated {
+                id: me.id.clone(),
+                avatar: me.avatar.clone(),
+                display_name: me.display_name.clone(),
+                bio: me.bio.clone(),
+                updated_at: me.updated_at,
+            }));
+        }
+    }
+    Ok(())
+}
+
 pub(crate) async fn my_avatar<UR, US>(
     repo: Data<PostgresRepository>,
     UserID(uid): UserID,
@@ -446,10 +2015,12 @@ pub(crate) struct SendChatMessage {
     content: String,
 }
 
-pub(crate) async fn send_chat_message<R, N, S>(
+pub(crate) async fn send_chat_message<R, P, B, V, S>(
     repo: Data<R>,
     addrs: Data<S>,
-    notifier: Data<N>,
+    prefs: Data<P>,
+    blocks: Data<B>,
+    privacy: Data<V>,
     UserID(uid): UserID,
     Json(SendChatMessage {
         to,
@@ -459,53 +2030,85 @@ pub(crate) async fn send_chat_message<R, N, S>(
 ) -> Result<Json<RepoChatMessage>>
 where
     R: Repository + Clone + Unpin + 'static,
-    N: Notifier + Clone + Unpin + 'static,
+    P: NotificationPreferences + Clone + Unpin + 'static,
+    B: BlockStore,
+    V: PrivacyStore,
     S: AddrStore + Clone + Unpin + 'static,
 {
-    let inserted = repo
-        .insert_chat_message(&InsertChatMessage {
-            from: uid.clone(),
-            to: to.clone(),
-            mime_type: mime_type.clone(),
-            content: content.clone(),
-        })
+    check_not_blocked(blocks.as_ref(), &uid, &to).await?;
+    let contact = privacy
+        .contact(&uid, &to)
         .await
         .map_err(ErrorInternalServerError)?;
+    if contact == Contact::Denied {
+        return Err(ErrorForbidden("user only accepts messages from friends"));
+    }
     let user = repo
         .get_user(&uid)
         .await
         .map_err(ErrorInternalServerError)?;
-    let chat_msg = Message::Chat {
-        from: uid.clone(),
-        phone: user.phone.clone(),
-        payload: ChatPayload {
-            id: inserted.id.clone(),
-            mime_type,
-            content,
-        },
-    };
-    if let Some(addr) = addrs
+    let addr = addrs
         .get_addr(&to)
         .await
-        .map_err(ErrorInternalServerError)?
-    {
-        addr.do_send(chat_msg);
-    } else if let Some(fcm_token) = notifier
-        .get_token(&to)
-        .await
-        .map_err(ErrorInternalServerError)?
-    {
-        notifier
-            .send_notification(
-                &fcm_token,
-                "Chat message",
-                "You got a chat message just now",
-                [("phone", user.phone), ("typ", "Chat".into())]
+        .map_err(ErrorInternalServerError)?;
+    let push = match (&addr, &contact) {
+        // no push notification, strangers don't get to ring the phone
+        (Some(_), _) | (None, Contact::Request) => None,
+        (None, _) => {
+            push_notification(
+                prefs.as_ref(),
+                &uid,
+                &to,
+                &NotificationEvent::Chat {
+                    sender_id: &uid,
+                    sender: &user.phone,
+                    mime_type: &mime_type,
+                    content: &content,
+                },
+                [("phone", user.phone.clone()), ("typ", "Chat".into())]
                     .into_iter()
                     .collect::<HashMap<&str, String>>(),
+                // still worth showing a day later
+                Duration::days(1),
             )
             .await
+        }
+    };
+    let inserted = repo
+        .insert_chat_message(
+            &InsertChatMessage {
+                from: uid.clone(),
+                to: to.clone(),
+                mime_type: mime_type.clone(),
+                content: content.clone(),
+            },
+            push.as_ref(),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if contact == Contact::Request {
+        privacy
+            .add_message_request(&uid, &to)
+            .await
             .map_err(ErrorInternalServerError)?;
+        if let Some(addr) = addr {
+            addr.do_send(Message::System(SystemMessage::MessageRequest {
+                from: uid.clone(),
+                phone: user.phone.clone(),
+            }));
+        }
+        return Ok(Json(inserted));
+    }
+    if let Some(addr) = addr {
+        addr.do_send(Message::Chat {
+            from: uid.clone(),
+            phone: user.phone.clone(),
+            payload: ChatPayload {
+                id: inserted.id.clone(),
+                mime_type,
+                content,
+            },
+        });
     }
 
     Ok(Json(inserted))
@@ -518,25 +2121,41 @@ pub(crate) struct SendRTCMessage {
     payload: String,
 }
 
-pub(crate) async fn send_rtc_message<R, N, S>(
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn send_rtc_message<R, O, P, B, V, S>(
     repo: Data<R>,
     addrs: Data<S>,
-    notifier: Data<N>,
+    outbox: Data<O>,
+    prefs: Data<P>,
+    blocks: Data<B>,
+    privacy: Data<V>,
     UserID(uid): UserID,
     Json(SendRTCMessage { to, typ, payload }): Json<SendRTCMessage>,
 ) -> Result<HttpResponse>
 where
     R: Repository + Clone + Unpin + 'static,
-    N: Notifier + Clone + Unpin + 'static,
+    O: NotificationOutbox + Clone + Unpin + 'static,
+    P: NotificationPreferences + Clone + Unpin + 'static,
+    B: BlockStore,
+    V: PrivacyStore,
     S: AddrStore + Clone + Unpin + 'static,
 {
+    check_not_blocked(blocks.as_ref(), &uid, &to).await?;
+    if privacy
+        .contact(&uid, &to)
+        .await
+        .map_err(ErrorInternalServerError)?
+        != Contact::Allowed
+    {
+        return Err(ErrorForbidden("user only accepts calls from friends"));
+    }
     let user = repo
         .get_user(&uid)
         .await
         .map_err(ErrorInternalServerError)?;
     let rtc_msg = Message::RTC {
-        from: uid,
-        phone: user.phone,
+        from: uid.clone(),
+        phone: user.phone.clone(),
         payload,
     };
     if let Some(addr) = addrs
@@ -550,18 +2169,138 @@ where
     if typ != "Offer" {
         return Err(ErrorUnprocessableEntity("could not forward to user"));
     }
-    notifier
-        .send_notification(
-            &to,
-            "RTC message",
-            "You got an RTC message just now",
-            rtc_msg,
-        )
-        .await
-        .map_err(ErrorInternalServerError)?;
+    enqueue_notification(
+        outbox.as_ref(),
+        prefs.as_ref(),
+        &uid,
+        &to,
+        &NotificationEvent::Call {
+            sender_id: &uid,
+            sender: &user.phone,
+        },
+        rtc_msg,
+        // nobody picks up a call offer that rang a minute ago
+        Duration::minutes(1),
+    )
+    .await;
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Fails if either user blocked the other. It doesn't tell who blocked whom.
+async fn check_not_blocked<B>(blocks: &B, uid: &str, other: &str) -> Result<()>
+where
+    B: BlockStore,
+{
+    if blocks
+        .is_blocked(uid, other)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorForbidden("user is blocked"));
+    }
+    Ok(())
+}
+
+/// Renders the push notification of `event` for `uid` in the locale `uid`
+/// chose, `None` if `uid` muted it. Stores write it to the outbox along with
+/// what it notifies of, the outbox worker delivers it in the background.
+///
+/// Failing to render never fails the calling request, it is only logged.
+async fn push_notification<P, T>(
+    prefs: &P,
+    from: &str,
+    uid: &str,
+    event: &NotificationEvent<'_>,
+    data: T,
+    ttl: Duration,
+) -> Option<EnqueueNotification>
+where
+    P: NotificationPreferences,
+    T: Serialize,
+{
+    match prefs.should_notify(uid, from, event.kind()).await {
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(e) => {
+            error!("failed to check notification settings: {}", e);
+            return None;
+        }
+    }
+    let settings = match prefs.notification_settings(uid).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("failed to get notification settings: {}", e);
+            return None;
+        }
+    };
+    let PushNotification {
+        title,
+        body,
+        collapse_key,
+        thread_id,
+    } = event.render(&settings.locale, settings.hide_previews);
+    let data = match to_value(data) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("failed to serialize notification data: {}", e);
+            return None;
+        }
+    };
+    Some(EnqueueNotification {
+        user_id: uid.to_owned(),
+        title,
+        body,
+        collapse_key,
+        thread_id,
+        data,
+        ttl,
+    })
+}
+
+/// Writes the push notification of `event` to the outbox on its own, for
+/// events nothing else is stored of.
+///
+/// Failing to enqueue never fails the calling request, it is only logged.
+async fn enqueue_notification<O, P, T>(
+    outbox: &O,
+    prefs: &P,
+    from: &str,
+    uid: &str,
+    event: &NotificationEvent<'_>,
+    data: T,
+    ttl: Duration,
+) where
+    O: NotificationOutbox,
+    P: NotificationPreferences,
+    T: Serialize,
+{
+    let Some(notification) =
+        push_notification(prefs, from, uid, event, data, ttl).await
+    else {
+        return;
+    };
+    if let Err(e) = outbox.enqueue(&notification).await {
+        error!("failed to enqueue notification for user {}: {}", uid, e);
+    }
+}
+
+pub(crate) async fn dead_letters<O>(
+    outbox: Data<O>,
+    UserID(uid): UserID,
+    Query(page): Query<Pagination>,
+) -> Result<Json<Vec<DeadLetter>>>
+where
+    O: NotificationOutbox + Clone + 'static,
+{
+    page.validate()?;
+    Ok(Json(
+        outbox
+            .dead_letters(&uid, page.limit, page.offset)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
 pub(crate) async fn offline<S>(
     addrs: Data<S>,
     UserID(uid): UserID,
@@ -608,24 +2347,479 @@ where
     ))
 }
 
-pub(crate) async fn logout<R, AR, H, T>(
-    repo: Data<R>,
-    auth_service: Data<AuthService<AR, H, T>>,
+/// Logs out of the current device only; the sessions on other devices stay.
+pub(crate) async fn logout<S>(
+    sessions: Data<S>,
     UserID(uid): UserID,
+    AuthToken(token): AuthToken,
 ) -> Result<HttpResponse>
 where
-    R: Repository + Clone,
-    AR: AuthRepository + Clone,
-    H: Hasher + Clone,
-    T: TokenManager + Clone,
+    S: LoginSessionStore,
+{
+    sessions
+        .revoke_current_login_session(&uid, &sha256_hex(&token))
+        .await
+        .map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn my_login_sessions<S>(
+    sessions: Data<S>,
+    UserID(uid): UserID,
+    AuthToken(token): AuthToken,
+) -> Result<Json<Vec<LoginSession>>>
+where
+    S: LoginSessionStore,
+{
+    Ok(Json(
+        sessions
+            .login_sessions(&uid, &sha256_hex(&token))
+            .await
+            .map_err(http_error)?,
+    ))
+}
+
+pub(crate) async fn revoke_login_session<S>(
+    sessions: Data<S>,
+    UserID(uid): UserID,
+    session_id: Path<String>,
+) -> Result<HttpResponse>
+where
+    S: LoginSessionStore,
+{
+    sessions
+        .revoke_login_session(&uid, &session_id)
+        .await
+        .map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+const WS_TICKET_TTL_SECONDS: i64 = 30;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct WsTicket {
+    ticket: String,
+    /// Seconds until `ticket` expires.
+    expires_in: i64,
+}
+
+pub(crate) async fn create_ws_ticket<W>(
+    tickets: Data<W>,
+    UserID(uid): UserID,
+) -> Result<Json<WsTicket>>
+where
+    W: WsTicketStore,
+{
+    let ticket = Uuid::new_v4().simple().to_string();
+    tickets
+        .issue_ws_ticket(
+            &uid,
+            &sha256_hex(&ticket),
+            Duration::seconds(WS_TICKET_TTL_SECONDS),
+        )
+        .await
+        .map_err(http_error)?;
+    Ok(Json(WsTicket {
+        ticket,
+        expires_in: WS_TICKET_TTL_SECONDS,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RevokedLoginSessions {
+    revoked: u64,
+}
+
+pub(crate) async fn revoke_other_login_sessions<S>(
+    sessions: Data<S>,
+    UserID(uid): UserID,
+    AuthToken(token): AuthToken,
+) -> Result<Json<RevokedLoginSessions>>
+where
+    S: LoginSessionStore,
+{
+    let revoked = sessions
+        .revoke_other_login_sessions(&uid, &sha256_hex(&token))
+        .await
+        .map_err(http_error)?;
+    Ok(Json(RevokedLoginSessions { revoked }))
+}
+
+const ACCOUNT_DELETION_GRACE_DAYS: i64 = 14;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeleteAccount {
+    password: String,
+}
+
+/// Schedules the account for deletion. Until the grace period is over the
+/// account works as before and the deletion can be cancelled.
+pub(crate) async fn delete_account<R, AR, H, D>(
+    repo: Data<R>,
+    auth_repo: Data<AR>,
+    hasher: Data<H>,
+    deletions: Data<D>,
+    UserID(uid): UserID,
+    Json(DeleteAccount { password }): Json<DeleteAccount>,
+) -> Result<Json<AccountDeletion>>
+where
+    R: Repository,
+    AR: AuthRepository,
+    H: Hasher,
+    D: AccountDeletionStore,
 {
     let me = repo
         .get_user(&uid)
         .await
         .map_err(ErrorInternalServerError)?;
-    auth_service
-        .logout(&me.phone)
+    if !check_password(
+        auth_repo.as_ref(),
+        hasher.as_ref(),
+        &me.phone,
+        &password,
+    )
+    .await?
+    {
+        return Err(ErrorForbidden("invalid password"));
+    }
+    Ok(Json(
+        deletions
+            .schedule_account_deletion(
+                &uid,
+                Duration::days(ACCOUNT_DELETION_GRACE_DAYS),
+            )
+            .await
+            .map_err(http_error)?,
+    ))
+}
+
+pub(crate) async fn my_account_deletion<D>(
+    deletions: Data<D>,
+    UserID(uid): UserID,
+) -> Result<Json<Option<AccountDeletion>>>
+where
+    D: AccountDeletionStore,
+{
+    Ok(Json(
+        deletions.account_deletion(&uid).await.map_err(http_error)?,
+    ))
+}
+
+pub(crate) async fn cancel_account_deletion<D>(
+    deletions: Data<D>,
+    UserID(uid): UserID,
+) -> Result<HttpResponse>
+where
+    D: AccountDeletionStore,
+{
+    deletions
+        .cancel_account_deletion(&uid)
+        .await
+        .map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Queues an export of everything we keep about the user. The archive is
+/// built in the background, poll the returned export until it is ready.
+pub(crate) async fn request_data_export<X>(
+    exports: Data<X>,
+    UserID(uid): UserID,
+) -> Result<HttpResponse>
+where
+    X: DataExportStore,
+{
+    let export = exports
+        .request_data_export(&uid)
+        .await
+        .map_err(http_error)?;
+    Ok(HttpResponse::Accepted().json(export))
+}
+
+pub(crate) async fn my_data_export<X>(
+    exports: Data<X>,
+    UserID(uid): UserID,
+    id: Path<String>,
+) -> Result<Json<DataExport>>
+where
+    X: DataExportStore,
+{
+    exports
+        .data_export(&uid, &id)
+        .await
+        .map_err(http_error)?
+        .map(Json)
+        .ok_or_else(|| ErrorNotFound("data export not found"))
+}
+
+pub(crate) async fn download_data_export<X>(
+    exports: Data<X>,
+    UserID(uid): UserID,
+    id: Path<String>,
+) -> Result<NamedFile>
+where
+    X: DataExportStore,
+{
+    let archive = exports
+        .data_export_archive(&uid, &id)
+        .await
+        .map_err(http_error)?
+        .ok_or_else(|| ErrorNotFound("data export is not ready"))?;
+    Ok(NamedFile::open_async(archive)
+        .await?
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "export-{}.zip",
+                id
+            ))],
+        }))
+}
+
+pub(crate) async fn my_blocked_users<B>(
+    blocks: Data<B>,
+    UserID(uid): UserID,
+) -> Result<Json<Vec<BlockedUser>>>
+where
+    B: BlockStore,
+{
+    Ok(Json(blocks.blocked_users(&uid).await.map_err(http_error)?))
+}
+
+/// Blocks the user, they can no longer send friend requests, messages or
+/// calls, nor find the user by phone.
+pub(crate) async fn block_user<R, B>(
+    repo: Data<R>,
+    blocks: Data<B>,
+    UserID(uid): UserID,
+    blocked: Path<String>,
+) -> Result<HttpResponse>
+where
+    R: Repository,
+    B: BlockStore,
+{
+    if *blocked == uid {
+        return Err(ErrorUnprocessableEntity("can't block yourself"));
+    }
+    // 404 rather than a dangling block
+    repo.get_user(&blocked).await.map_err(http_error)?;
+    blocks.block(&uid, &blocked).await.map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn unblock_user<B>(
+    blocks: Data<B>,
+    UserID(uid): UserID,
+    blocked: Path<String>,
+) -> Result<HttpResponse>
+where
+    B: BlockStore,
+{
+    if !blocks.unblock(&uid, &blocked).await.map_err(http_error)? {
+        return Err(ErrorNotFound("user is not blocked"));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn my_privacy_settings<V>(
+    privacy: Data<V>,
+    UserID(uid): UserID,
+) -> Result<Json<PrivacySettings>>
+where
+    V: PrivacyStore,
+{
+    Ok(Json(
+        privacy.privacy_settings(&uid).await.map_err(http_error)?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UpdatePrivacySettings {
+    contact_policy: ContactPolicy,
+    discoverable_by_phone: bool,
+}
+
+pub(crate) async fn update_privacy_settings<V>(
+    privacy: Data<V>,
+    UserID(uid): UserID,
+    Json(UpdatePrivacySettings {
+        contact_policy,
+        discoverable_by_phone,
+    }): Json<UpdatePrivacySettings>,
+) -> Result<HttpResponse>
+where
+    V: PrivacyStore,
+{
+    let settings = PrivacySettings {
+        contact_policy,
+        discoverable_by_phone,
+    };
+    privacy
+        .update_privacy_settings(&uid, &settings)
+        .await
+        .map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn my_message_requests<V>(
+    privacy: Data<V>,
+    UserID(uid): UserID,
+) -> Result<Json<Vec<MessageRequest>>>
+where
+    V: PrivacyStore,
+{
+    Ok(Json(
+        privacy.message_requests(&uid).await.map_err(http_error)?,
+    ))
+}
+
+/// Lets the peer message and call the user like a friend would.
+pub(crate) async fn accept_message_request<V>(
+    privacy: Data<V>,
+    UserID(uid): UserID,
+    peer_id: Path<String>,
+) -> Result<HttpResponse>
+where
+    V: PrivacyStore,
+{
+    if !privacy
+        .accept_message_request(&uid, &peer_id)
+        .await
+        .map_err(http_error)?
+    {
+        return Err(ErrorNotFound("no such message request"));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn decline_message_request<V>(
+    privacy: Data<V>,
+    UserID(uid): UserID,
+    peer_id: Path<String>,
+) -> Result<HttpResponse>
+where
+    V: PrivacyStore,
+{
+    if !privacy
+        .decline_message_request(&uid, &peer_id)
+        .await
+        .map_err(http_error)?
+    {
+        return Err(ErrorNotFound("no such message request"));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn my_notification_settings<P>(
+    prefs: Data<P>,
+    UserID(uid): UserID,
+) -> Result<Json<NotificationSettings>>
+where
+    P: NotificationPreferences + Clone + 'static,
+{
+    Ok(Json(
+        prefs
+            .notification_settings(&uid)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UpdateDoNotDisturb {
+    start: NaiveTime,
+    end: NaiveTime,
+    time_zone: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UpdateNotificationSettings {
+    muted: bool,
+    do_not_disturb: Option<UpdateDoNotDisturb>,
+    dnd_allow_favorites: bool,
+    locale: String,
+    hide_previews: bool,
+}
+
+pub(crate) async fn update_notification_settings<P>(
+    prefs: Data<P>,
+    UserID(uid): UserID,
+    Json(UpdateNotificationSettings {
+        muted,
+        do_not_disturb,
+        dnd_allow_favorites,
+        locale,
+        hide_previews,
+    }): Json<UpdateNotificationSettings>,
+) -> Result<HttpResponse>
+where
+    P: NotificationPreferences + Clone + 'static,
+{
+    prefs
+        .update_notification_settings(
+            &uid,
+            &NotificationSettings {
+                muted,
+                do_not_disturb: do_not_disturb.map(|d| DoNotDisturb {
+                    start: d.start,
+                    end: d.end,
+                    time_zone: d.time_zone,
+                }),
+                dnd_allow_favorites,
+                locale,
+                hide_previews,
+            },
+        )
+        .await
+        .map_err(http_error)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn my_peer_notification_settings<P>(
+    prefs: Data<P>,
+    UserID(uid): UserID,
+) -> Result<Json<Vec<PeerNotificationSettings>>>
+where
+    P: NotificationPreferences + Clone + 'static,
+{
+    Ok(Json(
+        prefs
+            .peer_notification_settings(&uid)
+            .await
+            .map_err(ErrorInternalServerError)?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UpdatePeerNotificationSettings {
+    muted_until: Option<DateTime<Utc>>,
+    favorite: bool,
+}
+
+pub(crate) async fn update_peer_notification_settings<P>(
+    prefs: Data<P>,
+    UserID(uid): UserID,
+    peer_id: Path<(String,)>,
+    Json(UpdatePeerNotificationSettings {
+        muted_until,
+        favorite,
+    }): Json<UpdatePeerNotificationSettings>,
+) -> Result<HttpResponse>
+where
+    P: NotificationPreferences + Clone + 'static,
+{
+    prefs
+        .update_peer_notification_settings(
+            &uid,
+            &PeerNotificationSettings {
+                peer_id: peer_id.into_inner().0,
+                muted_until,
+                favorite,
+            },
+        )
         .await
         .map_err(ErrorInternalServerError)?;
     Ok(HttpResponse::Ok().finish())
 }
+
+#[cfg(test)]
+pub(crate) mod tests;
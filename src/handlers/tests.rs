@@ -0,0 +1,2488 @@
+use super::*;
+use crate::{
+    core::{contact::contact_hash, notifier::INVALID_TOKEN_STATUS},
+    hashers::argon2id::Argon2Hasher,
+    middlewares::ratelimit::TrustedProxies,
+    notifiers::memory::{MemoryNotifier, SentNotification},
+    workers::{
+        deletion::AccountDeletionWorker,
+        friend_request::FriendRequestExpiryWorker, outbox::OutboxWorker,
+    },
+    ws::actor::QueryTokenAuth,
+};
+use actix::{Addr, MessageResult};
+use actix_web::{
+    http::header::SEC_WEBSOCKET_PROTOCOL,
+    test::{call_service, init_service, read_body_json, TestRequest},
+    web::{delete, get, patch, post, put},
+    App,
+};
+use auth_service::token_managers::jwt::JWTTokenManager;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{postgres::PgPoolOptions, query, query_scalar, Executor, PgPool};
+use std::env;
+use tokio::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// Connects to the database in `DATABASE_URL` and migrates it. The tests
+/// using it are skipped when it isn't set, except under `CI`, where that
+/// would pass them without running anything. It's the database the
+/// `query!` macros check against at build time anyway, a scratch one is
+/// enough:
+///
+/// ```sh
+/// DATABASE_URL=postgres://postgres@localhost/signal_test cargo test
+/// ```
+pub(crate) async fn test_pool() -> Option<PgPool> {
+    let Ok(url) = env::var("DATABASE_URL") else {
+        assert!(
+            env::var_os("CI").is_none(),
+            "DATABASE_URL must be set to run the tests in CI"
+        );
+        eprintln!("DATABASE_URL not set, skipping");
+        return None;
+    };
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&url)
+        .await
+        .expect("failed to connect to postgresql");
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .expect("failed to run migrations");
+    Some(pool)
+}
+
+/// Creates a user with a registered device and returns its id. Ids and
+/// phones are random so tests can share one database.
+async fn create_user(pool: &PgPool, notifier: &MemoryNotifier) -> String {
+    let id = Uuid::new_v4().to_string();
+    query!(
+        "INSERT INTO users (id, phone, password, password_salt) VALUES ($1, $2, '', '')",
+        id,
+        format!("+{}", &id[..8]),
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    notifier
+        .update_token(&id, &format!("token-{}", id))
+        .await
+        .unwrap();
+    id
+}
+
+async fn make_friends(pool: &PgPool, a: &str, b: &str) {
+    query!(
+        r#"INSERT INTO friend_requests (id, "from", "to", status) VALUES ($1, $2, $3, 'Accepted')"#,
+        Uuid::new_v4().to_string(),
+        a,
+        b,
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+// The outbox worker drains the notifications of every test sharing the
+// database, so tests touching the outbox run one at a time.
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+struct Harness {
+    pool: PgPool,
+    repo: PostgresRepository,
+    notifier: MemoryNotifier,
+    addrs: AddrMap,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    async fn new() -> Option<Self> {
+        let serial = SERIAL.lock().await;
+        let pool = test_pool().await?;
+        Some(Self {
+            repo: PostgresRepository::new(pool.clone()),
+            notifier: MemoryNotifier::new(),
+            addrs: AddrMap::new(),
+            pool,
+            _serial: serial,
+        })
+    }
+
+    /// Runs the outbox worker once and returns what `uid` got pushed.
+    async fn delivered_to(&self, uid: &str) -> Vec<SentNotification> {
+        OutboxWorker::new(self.repo.clone(), self.notifier.clone())
+            .deliver_due()
+            .await
+            .unwrap();
+        self.notifier.sent_to(uid).await
+    }
+}
+
+/// Stands in for a user's websocket and keeps what it is sent.
+struct Inbox(Vec<Message>);
+
+impl Actor for Inbox {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Inbox {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _: &mut Self::Context) {
+        self.0.push(msg);
+    }
+}
+
+/// Hands over what the inbox got so far. It queues behind the messages
+/// sent before it, so they are all in.
+#[derive(actix::Message)]
+#[rtype(result = "Vec<Message>")]
+struct TakeReceived;
+
+impl Handler<TakeReceived> for Inbox {
+    type Result = MessageResult<TakeReceived>;
+
+    fn handle(
+        &mut self,
+        _: TakeReceived,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        MessageResult(std::mem::take(&mut self.0))
+    }
+}
+
+/// Puts `uid` online, the way opening a websocket does.
+async fn connect(addrs: &AddrMap, uid: &str) -> Addr<Inbox> {
+    let inbox = Inbox(Vec::new()).start();
+    addrs
+        .add_addr(uid, inbox.clone().recipient())
+        .await
+        .unwrap();
+    inbox
+}
+
+/// A `Harness`, or returns from the test if there is no database.
+macro_rules! harness {
+    () => {
+        match Harness::new().await {
+            Some(harness) => harness,
+            None => return,
+        }
+    };
+}
+
+macro_rules! app {
+    ($harness:expr) => {
+        init_service(
+            App::new()
+                .app_data(Data::new($harness.repo.clone()))
+                .app_data(Data::new($harness.addrs.clone()))
+                .route(
+                    "/chat_messages",
+                    post().to(send_chat_message::<
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        AddrMap,
+                    >),
+                )
+                .route(
+                    "/rtc_messages",
+                    post().to(send_rtc_message::<
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        AddrMap,
+                    >),
+                )
+                .route(
+                    "/friends/requests",
+                    post().to(add_friend::<
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        AddrMap,
+                    >),
+                )
+                .route(
+                    "/friends/invites/redeem",
+                    post().to(redeem_friend_invite::<
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        AddrMap,
+                    >),
+                )
+                .route(
+                    "/friends/requests/{id}/accept",
+                    put().to(accept_request::<
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                        AddrMap,
+                    >),
+                ),
+        )
+        .await
+    };
+}
+
+#[actix_web::test]
+async fn test_chat_message_notification() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    make_friends(&harness.pool, &alice, &bob).await;
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/chat_messages")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({
+                "to": bob,
+                "mime_type": "text/plain",
+                "content": "hello",
+            }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+
+    let sent = harness.delivered_to(&bob).await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].notification.body, "hello");
+    assert_eq!(
+        sent[0].notification.thread_id,
+        Some(format!("chat:{}", alice))
+    );
+    assert_eq!(sent[0].data["typ"], "Chat");
+    assert!(harness.notifier.sent_to(&alice).await.is_empty());
+}
+
+#[actix_web::test]
+async fn test_muted_peer_is_not_notified() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    make_friends(&harness.pool, &alice, &bob).await;
+    harness
+        .repo
+        .update_peer_notification_settings(
+            &bob,
+            &PeerNotificationSettings {
+                peer_id: alice.clone(),
+                muted_until: Some(Utc::now() + Duration::hours(1)),
+                favorite: false,
+            },
+        )
+        .await
+        .unwrap();
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/chat_messages")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({
+                "to": bob,
+                "mime_type": "image/png",
+                "content": "uploads/1",
+            }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    assert!(harness.delivered_to(&bob).await.is_empty());
+}
+
+#[actix_web::test]
+async fn test_rtc_offer_notification() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    make_friends(&harness.pool, &alice, &bob).await;
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/rtc_messages")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({"to": bob, "typ": "Answer", "payload": "sdp"}))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(harness.delivered_to(&bob).await.is_empty());
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/rtc_messages")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({"to": bob, "typ": "Offer", "payload": "sdp"}))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let sent = harness.delivered_to(&bob).await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].notification.body, "Incoming call");
+    assert_eq!(sent[0].data["typ"], "RTC");
+    assert_eq!(sent[0].data["payload"], "sdp");
+}
+
+#[actix_web::test]
+async fn test_friend_request_notifications() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/friends/requests")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({ "friend_id": bob }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    let id = body["id"].as_str().unwrap().to_owned();
+
+    let sent = harness.delivered_to(&bob).await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].notification.title, "Friend request");
+    assert_eq!(sent[0].data["id"], id.as_str());
+
+    let resp = call_service(
+        &app,
+        TestRequest::put()
+            .uri(&format!("/friends/requests/{}/accept", id))
+            .insert_header(("X-User-ID", bob.as_str()))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let sent = harness.delivered_to(&alice).await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].notification.title, "Friend request accepted");
+}
+
+#[actix_web::test]
+async fn test_account_deletion() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    make_friends(&harness.pool, &alice, &bob).await;
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/chat_messages")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({
+                "to": bob,
+                "mime_type": "text/plain",
+                "content": "bye",
+            }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    // an avatar of alice's own, which bob picked up too
+    let avatar = Uuid::new_v4().to_string();
+    query!(
+        "INSERT INTO uploads (id, filename, mime_type, uploader_id, uploaded_at, filepath)
+        VALUES ($1, 'avatar.png', 'image/png', $2, now(), $3)",
+        avatar,
+        alice,
+        format!("/nonexistent/{}", avatar),
+    )
+    .execute(&harness.pool)
+    .await
+    .unwrap();
+    query!(
+        "UPDATE users SET avatar = $1 WHERE id = $2 OR id = $3",
+        avatar,
+        alice,
+        bob,
+    )
+    .execute(&harness.pool)
+    .await
+    .unwrap();
+
+    let deletion = harness
+        .repo
+        .schedule_account_deletion(&alice, Duration::zero())
+        .await
+        .unwrap();
+    // scheduling again keeps the first schedule
+    let again = harness
+        .repo
+        .schedule_account_deletion(&alice, Duration::days(1))
+        .await
+        .unwrap();
+    assert_eq!(again.delete_after, deletion.delete_after);
+
+    AccountDeletionWorker::new(
+        harness.repo.clone(),
+        harness.notifier.clone(),
+        AddrMap::new(),
+    )
+    .delete_due()
+    .await
+    .unwrap();
+    assert!(harness.notifier.get_token(&alice).await.unwrap().is_none());
+    assert!(harness
+        .repo
+        .account_deletion(&alice)
+        .await
+        .unwrap()
+        .is_none());
+    let left = query_scalar!(
+        r#"SELECT
+            (SELECT COUNT(*) FROM users WHERE id = $1)
+            + (SELECT COUNT(*) FROM messages WHERE "from" = $1 OR "to" = $1)
+            + (SELECT COUNT(*) FROM uploads WHERE uploader_id = $1) AS "left!""#,
+        alice,
+    )
+    .fetch_one(&harness.pool)
+    .await
+    .unwrap();
+    assert_eq!(left, 0);
+    assert!(harness.notifier.get_token(&bob).await.unwrap().is_some());
+    assert_eq!(harness.repo.get_user(&bob).await.unwrap().avatar, None);
+}
+
+#[actix_web::test]
+async fn test_blocked_user_cannot_reach_blocker() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    harness.repo.block(&bob, &alice).await.unwrap();
+
+    for (uri, body) in [
+        (
+            "/chat_messages",
+            json!({ "to": bob, "mime_type": "text/plain", "content": "hi" }),
+        ),
+        (
+            "/rtc_messages",
+            json!({ "to": bob, "typ": "Offer", "payload": "sdp" }),
+        ),
+        ("/friends/requests", json!({ "friend_id": bob })),
+    ] {
+        let resp = call_service(
+            &app,
+            TestRequest::post()
+                .uri(uri)
+                .insert_header(("X-User-ID", alice.as_str()))
+                .set_json(body)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", uri);
+    }
+    assert!(harness.delivered_to(&bob).await.is_empty());
+
+    let phone = harness.repo.get_user(&bob).await.unwrap().phone;
+    assert!(harness
+        .repo
+        .search_user(&alice, &phone)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+/// The pending friend requests between `a` and `b`, either way.
+async fn pending_requests(pool: &PgPool, a: &str, b: &str) -> i64 {
+    query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM friend_requests
+        WHERE (("from" = $1 AND "to" = $2) OR ("from" = $2 AND "to" = $1))
+            AND status = 'Pending'"#,
+        a,
+        b,
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[actix_web::test]
+async fn test_block_drops_pending_requests() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    harness
+        .repo
+        .add_friend_request(&alice, &bob, None)
+        .await
+        .unwrap();
+    harness
+        .repo
+        .add_friend_request(&bob, &alice, None)
+        .await
+        .unwrap();
+    assert_eq!(pending_requests(&harness.pool, &alice, &bob).await, 2);
+
+    // the blocker's own request goes too, or the blocked user could accept it
+    harness.repo.block(&bob, &alice).await.unwrap();
+    assert_eq!(pending_requests(&harness.pool, &alice, &bob).await, 0);
+
+    // a request that got past the block anyway can't be accepted
+    let id = harness
+        .repo
+        .add_friend_request(&bob, &alice, None)
+        .await
+        .unwrap();
+    let resp = call_service(
+        &app,
+        TestRequest::put()
+            .uri(&format!("/friends/requests/{}/accept", id))
+            .insert_header(("X-User-ID", alice.as_str()))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(!harness.repo.is_friend(&alice, &bob).await.unwrap());
+}
+
+#[actix_web::test]
+async fn test_stranger_messages_follow_contact_policy() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let send = |content: &'static str| {
+        TestRequest::post()
+            .uri("/chat_messages")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({
+                "to": bob,
+                "mime_type": "text/plain",
+                "content": content,
+            }))
+            .to_request()
+    };
+
+    let resp = call_service(&app, send("hi")).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    harness
+        .repo
+        .update_privacy_settings(
+            &bob,
+            &PrivacySettings {
+                contact_policy: ContactPolicy::MessageRequests,
+                discoverable_by_phone: true,
+            },
+        )
+        .await
+        .unwrap();
+    let resp = call_service(&app, send("hi")).await;
+    assert!(resp.status().is_success());
+    assert!(harness.delivered_to(&bob).await.is_empty());
+    let requests = harness.repo.message_requests(&bob).await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].latest_content.as_deref(), Some("hi"));
+    assert!(harness.repo.sessions(&bob).await.unwrap().is_empty());
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/rtc_messages")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(json!({"to": bob, "typ": "Offer", "payload": "sdp"}))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // alice only takes messages from friends, but bob may answer her
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/chat_messages")
+            .insert_header(("X-User-ID", bob.as_str()))
+            .set_json(json!({
+                "to": alice,
+                "mime_type": "text/plain",
+                "content": "who is this?",
+            }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    assert_eq!(harness.delivered_to(&alice).await.len(), 1);
+
+    assert!(harness
+        .repo
+        .accept_message_request(&bob, &alice)
+        .await
+        .unwrap());
+    let resp = call_service(&app, send("again")).await;
+    assert!(resp.status().is_success());
+    assert_eq!(harness.delivered_to(&bob).await.len(), 1);
+    assert_eq!(harness.repo.sessions(&bob).await.unwrap().len(), 1);
+    // and a call goes through both ways, answers only reach a connected user
+    let alice_inbox = connect(&harness.addrs, &alice).await;
+    for (from, to, typ) in [(&alice, &bob, "Offer"), (&bob, &alice, "Answer")] {
+        let resp = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/rtc_messages")
+                .insert_header(("X-User-ID", from.as_str()))
+                .set_json(json!({"to": to, "typ": typ, "payload": "sdp"}))
+                .to_request(),
+        )
+        .await;
+        assert!(resp.status().is_success(), "{}", typ);
+    }
+    let received = alice_inbox.send(TakeReceived).await.unwrap();
+    assert!(
+        matches!(&received[..], [Message::RTC { from, .. }] if *from == bob)
+    );
+}
+
+#[actix_web::test]
+async fn test_unfriending_ends_the_conversation() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let send = |from: &str, to: &str| {
+        TestRequest::post()
+            .uri("/chat_messages")
+            .insert_header(("X-User-ID", from))
+            .set_json(json!({
+                "to": to,
+                "mime_type": "text/plain",
+                "content": "hi",
+            }))
+            .to_request()
+    };
+    harness
+        .repo
+        .update_privacy_settings(
+            &bob,
+            &PrivacySettings {
+                contact_policy: ContactPolicy::MessageRequests,
+                discoverable_by_phone: true,
+            },
+        )
+        .await
+        .unwrap();
+    // they got talking as strangers and became friends later
+    let resp = call_service(&app, send(&alice, &bob)).await;
+    assert!(resp.status().is_success());
+    assert!(harness
+        .repo
+        .accept_message_request(&bob, &alice)
+        .await
+        .unwrap());
+    make_friends(&harness.pool, &alice, &bob).await;
+    for (from, to) in [(&alice, &bob), (&bob, &alice)] {
+        let resp = call_service(&app, send(from, to)).await;
+        assert!(resp.status().is_success());
+    }
+    let pushed = harness.delivered_to(&bob).await.len();
+
+    assert!(harness.repo.unfriend(&alice, &bob).await.unwrap());
+    // alice only takes messages from friends again
+    let resp = call_service(&app, send(&bob, &alice)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    // and bob's acceptance is gone, alice is back in his requests
+    let resp = call_service(&app, send(&alice, &bob)).await;
+    assert!(resp.status().is_success());
+    assert_eq!(harness.delivered_to(&bob).await.len(), pushed);
+    let requests = harness.repo.message_requests(&bob).await.unwrap();
+    assert_eq!(requests.len(), 1);
+    // which lets bob answer her
+    let resp = call_service(&app, send(&bob, &alice)).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_contact_sync_hides_undiscoverable_users() {
+    let harness = harness!();
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let carol = create_user(&harness.pool, &harness.notifier).await;
+    harness
+        .repo
+        .update_privacy_settings(
+            &carol,
+            &PrivacySettings {
+                discoverable_by_phone: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    let salt = harness
+        .repo
+        .add_contact_salt(&Uuid::new_v4().to_string(), Duration::hours(1))
+        .await
+        .unwrap();
+    // signed up after the salt was created
+    let dave = create_user(&harness.pool, &harness.notifier).await;
+    let mut hashes = Vec::new();
+    for id in [&bob, &carol, &dave] {
+        let phone = harness.repo.get_user(id).await.unwrap().phone;
+        hashes.push(contact_hash(&salt.salt, &phone));
+    }
+    let discovered = |contacts: Vec<DiscoveredContact>| {
+        let mut ids: Vec<_> = contacts.into_iter().map(|c| c.user.id).collect();
+        ids.sort();
+        ids
+    };
+
+    let contacts = harness
+        .repo
+        .discover_contacts(&alice, &salt, &hashes)
+        .await
+        .unwrap();
+    let mut expected = vec![bob.clone(), dave.clone()];
+    expected.sort();
+    assert_eq!(discovered(contacts), expected);
+
+    make_friends(&harness.pool, &alice, &carol).await;
+    let contacts = harness
+        .repo
+        .discover_contacts(&alice, &salt, &hashes)
+        .await
+        .unwrap();
+    let mut expected = vec![bob, carol, dave];
+    expected.sort();
+    assert_eq!(discovered(contacts), expected);
+}
+
+#[actix_web::test]
+async fn test_friend_invite_use_limit() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let carol = create_user(&harness.pool, &harness.notifier).await;
+    let invite = harness
+        .repo
+        .create_friend_invite(
+            &alice,
+            &NewFriendInvite {
+                token: Uuid::new_v4().simple().to_string(),
+                max_uses: Some(1),
+                auto_accept: true,
+                expires_at: None,
+            },
+        )
+        .await
+        .unwrap();
+    let redeem = |uid: &str| {
+        TestRequest::post()
+            .uri("/friends/invites/redeem")
+            .insert_header(("X-User-ID", uid))
+            .set_json(json!({ "token": invite.token }))
+            .to_request()
+    };
+
+    let resp = call_service(&app, redeem(&alice)).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let resp = call_service(&app, redeem(&bob)).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["accepted"], true);
+    assert!(harness.repo.is_friend(&alice, &bob).await.unwrap());
+    let resp = call_service(&app, redeem(&carol)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert!(!harness.repo.is_friend(&alice, &carol).await.unwrap());
+}
+
+#[actix_web::test]
+async fn test_friend_invite_request_goes_with_the_use() {
+    let harness = harness!();
+    let app = app!(harness);
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let carol = create_user(&harness.pool, &harness.notifier).await;
+    let invite = harness
+        .repo
+        .create_friend_invite(
+            &alice,
+            &NewFriendInvite {
+                token: Uuid::new_v4().simple().to_string(),
+                max_uses: Some(1),
+                auto_accept: false,
+                expires_at: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/friends/invites/redeem")
+            .insert_header(("X-User-ID", bob.as_str()))
+            .set_json(json!({ "token": invite.token }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["accepted"], false);
+    let request = harness
+        .repo
+        .get_friend_request(body["id"].as_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!((request.from, request.to), (bob, alice.clone()));
+    let sent = harness.delivered_to(&alice).await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].data["typ"], "FriendRequest");
+    assert_eq!(sent[0].data["id"], body["id"]);
+
+    // a use that doesn't count leaves no request behind
+    let redeemed = harness
+        .repo
+        .redeem_friend_invite(&invite.id, &carol, false, None)
+        .await
+        .unwrap();
+    assert!(redeemed.is_none());
+    let requests = query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM friend_requests WHERE "from" = $1"#,
+        carol,
+    )
+    .fetch_one(&harness.pool)
+    .await
+    .unwrap();
+    assert_eq!(requests, 0);
+}
+
+#[actix_web::test]
+async fn test_friend_suggestions_rank_by_mutual_friends() {
+    let harness = harness!();
+    let mut users = Vec::new();
+    for _ in 0..6 {
+        users.push(create_user(&harness.pool, &harness.notifier).await);
+    }
+    let [alice, bob, carol, dave, erin, frank] = &users[..] else {
+        unreachable!()
+    };
+    make_friends(&harness.pool, alice, bob).await;
+    make_friends(&harness.pool, carol, alice).await;
+    for friend in [bob, carol] {
+        make_friends(&harness.pool, friend, dave).await;
+    }
+    make_friends(&harness.pool, bob, erin).await;
+    make_friends(&harness.pool, frank, carol).await;
+    harness.repo.block(frank, alice).await.unwrap();
+
+    let suggestions =
+        harness.repo.friend_suggestions(alice, 20, 0).await.unwrap();
+    let payload = serde_json::to_value(&suggestions[0]).unwrap();
+    assert!(payload.get("phone").is_none());
+    let suggestions = suggestions
+        .into_iter()
+        .map(|s| (s.id, s.mutual_friends))
+        .collect::<Vec<_>>();
+    assert_eq!(suggestions, [(dave.clone(), 2), (erin.clone(), 1)]);
+
+    harness
+        .repo
+        .add_friend_request(alice, dave, None)
+        .await
+        .unwrap();
+    let suggestions = harness
+        .repo
+        .friend_suggestions(alice, 20, 0)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|s| s.id)
+        .collect::<Vec<_>>();
+    assert_eq!(suggestions, [erin.as_str()]);
+}
+
+#[actix_web::test]
+async fn test_friends_page_by_cursor() {
+    let harness = harness!();
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let mut friends = Vec::new();
+    for _ in 0..5 {
+        let friend = create_user(&harness.pool, &harness.notifier).await;
+        make_friends(&harness.pool, &alice, &friend).await;
+        friends.push(friend);
+    }
+
+    for sort in [FriendSort::Name, FriendSort::Recent] {
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let page = harness
+                .repo
+                .friends_page(&alice, None, sort, after.as_ref(), 2)
+                .await
+                .unwrap();
+            assert!(page.items.len() <= 2);
+            seen.extend(page.items.into_iter().map(|u| u.id));
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        seen.sort();
+        let mut expected = friends.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+    assert_eq!(harness.repo.count_friends(&alice, None).await.unwrap(), 5);
+}
+
+#[actix_web::test]
+async fn test_friend_requests_page_by_cursor() {
+    let harness = harness!();
+    let app = init_service(
+        App::new().app_data(Data::new(harness.repo.clone())).route(
+            "/friends/requests",
+            get().to(my_requests::<PostgresRepository>),
+        ),
+    )
+    .await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let mut ids = Vec::new();
+    for _ in 0..5 {
+        let from = create_user(&harness.pool, &harness.notifier).await;
+        ids.push(
+            harness
+                .repo
+                .add_friend_request(&from, &alice, None)
+                .await
+                .unwrap(),
+        );
+    }
+    let list = |query: String| {
+        TestRequest::get()
+            .uri(&format!("/friends/requests?{}", query))
+            .insert_header(("X-User-ID", alice.as_str()))
+            .to_request()
+    };
+
+    for sort in ["Name", "Recent"] {
+        let mut seen = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut query = format!("sort={}&limit=2", sort);
+            if let Some(cursor) = &cursor {
+                query += &format!("&cursor={}", cursor);
+            }
+            let resp = call_service(&app, list(query)).await;
+            assert!(resp.status().is_success());
+            let page: serde_json::Value = read_body_json(resp).await;
+            assert_eq!(page["total"], 5);
+            let items = page["items"].as_array().unwrap();
+            assert!(items.len() <= 2);
+            seen.extend(
+                items.iter().map(|r| r["id"].as_str().unwrap().to_owned()),
+            );
+            match page["next_cursor"].as_str() {
+                Some(next) => cursor = Some(next.to_owned()),
+                None => break,
+            }
+        }
+        seen.sort();
+        let mut expected = ids.clone();
+        expected.sort();
+        assert_eq!(seen, expected, "{}", sort);
+    }
+
+    let resp = call_service(&app, list("sort=Name&limit=2".into())).await;
+    let page: serde_json::Value = read_body_json(resp).await;
+    let cursor = page["next_cursor"].as_str().unwrap();
+    let resp = call_service(
+        &app,
+        list(format!("sort=Recent&limit=2&cursor={}", cursor)),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let resp =
+        call_service(&app, list("sort=Name&cursor=garbage".into())).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+async fn enqueue_test_notification(harness: &Harness, uid: &str) -> String {
+    harness
+        .repo
+        .enqueue(&EnqueueNotification {
+            user_id: uid.to_owned(),
+            title: "title".into(),
+            body: "body".into(),
+            collapse_key: None,
+            thread_id: None,
+            data: json!({}),
+            ttl: Duration::days(1),
+        })
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn test_outbox_retries_with_backoff_then_dead_letters() {
+    let harness = harness!();
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let id = enqueue_test_notification(&harness, &bob).await;
+    harness.notifier.fail_with(Some(503)).await;
+    let worker =
+        OutboxWorker::new(harness.repo.clone(), harness.notifier.clone());
+
+    for attempt in 1..=8 {
+        let before = Utc::now();
+        worker.deliver_due().await.unwrap();
+        let row = query!(
+            "SELECT status, attempts, next_attempt_at, last_error
+            FROM notification_outbox WHERE id = $1",
+            id,
+        )
+        .fetch_one(&harness.pool)
+        .await
+        .unwrap();
+        assert_eq!(row.attempts, attempt);
+        assert!(row.last_error.is_some());
+        if attempt == 8 {
+            assert_eq!(row.status, "Dead");
+            break;
+        }
+        assert_eq!(row.status, "Pending");
+        // 5s doubling with every attempt
+        let backoff = row.next_attempt_at - before;
+        let expected = Duration::seconds(5 << (attempt - 1));
+        assert!(backoff >= expected, "attempt {}: {}", attempt, backoff);
+        assert!(backoff < expected + Duration::seconds(5));
+        query!(
+            "UPDATE notification_outbox SET next_attempt_at = now() WHERE id = $1",
+            id,
+        )
+        .execute(&harness.pool)
+        .await
+        .unwrap();
+    }
+
+    let dead = harness.repo.dead_letters(&bob, 20, 0).await.unwrap();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].id, id);
+    assert_eq!(dead[0].attempts, 8);
+    assert!(harness.notifier.sent().await.is_empty());
+}
+
+#[actix_web::test]
+async fn test_outbox_dead_letters_permanent_failures() {
+    let harness = harness!();
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let worker =
+        OutboxWorker::new(harness.repo.clone(), harness.notifier.clone());
+
+    let id = enqueue_test_notification(&harness, &bob).await;
+    harness.notifier.fail_with(Some(400)).await;
+    worker.deliver_due().await.unwrap();
+    let dead = harness.repo.dead_letters(&bob, 20, 0).await.unwrap();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].id, id);
+    assert_eq!(dead[0].attempts, 1);
+
+    // a dead token isn't worth a dead letter, the notification is dropped
+    let id = enqueue_test_notification(&harness, &bob).await;
+    harness.notifier.fail_with(Some(INVALID_TOKEN_STATUS)).await;
+    worker.deliver_due().await.unwrap();
+    let left = query_scalar!(
+        "SELECT count(*) FROM notification_outbox WHERE id = $1",
+        id,
+    )
+    .fetch_one(&harness.pool)
+    .await
+    .unwrap();
+    assert_eq!(left, Some(0));
+    assert_eq!(harness.notifier.get_token(&bob).await.unwrap(), None);
+    assert_eq!(
+        harness.repo.dead_letters(&bob, 20, 0).await.unwrap().len(),
+        1
+    );
+}
+
+/// Starts a login for `user_id` that waits for its second factor.
+async fn create_challenge(repo: &PostgresRepository, user_id: &str) -> String {
+    let challenge = generate_challenge_id();
+    repo.create_two_factor_challenge(
+        &sha256_hex(&challenge),
+        &TwoFactorChallenge {
+            user_id: user_id.to_owned(),
+            phone: String::new(),
+            device_name: None,
+            platform: None,
+        },
+        Duration::minutes(TWO_FACTOR_CHALLENGE_TTL_MINUTES),
+    )
+    .await
+    .unwrap();
+    challenge
+}
+
+/// The code the authenticator app would show now.
+fn current_code(secret: &str) -> String {
+    Totp::from_encoded(secret)
+        .unwrap()
+        .code(Totp::step(Utc::now().timestamp()))
+}
+
+#[actix_web::test]
+async fn test_two_factor_enrollment() {
+    let harness = harness!();
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(harness.repo.clone()))
+            .route("/two_factor", get().to(my_two_factor::<PostgresRepository>))
+            .route(
+                "/two_factor",
+                post().to(enroll_two_factor::<
+                    PostgresRepository,
+                    PostgresRepository,
+                >),
+            )
+            .route(
+                "/two_factor/confirm",
+                post().to(confirm_two_factor::<PostgresRepository>),
+            )
+            .route(
+                "/two_factor/disable",
+                post().to(disable_two_factor::<PostgresRepository>),
+            ),
+    )
+    .await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let request = |uri: &str, code: Option<&str>| {
+        let req = TestRequest::post()
+            .uri(uri)
+            .insert_header(("X-User-ID", alice.as_str()));
+        match code {
+            Some(code) => req.set_json(json!({ "code": code })),
+            None => req,
+        }
+        .to_request()
+    };
+    let resp = call_service(&app, request("/two_factor", None)).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    let secret = body["secret"].as_str().unwrap().to_owned();
+    assert!(body["otpauth_uri"]
+        .as_str()
+        .unwrap()
+        .contains(&format!("secret={}", secret)));
+    let resp = call_service(
+        &app,
+        TestRequest::get()
+            .uri("/two_factor")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .to_request(),
+    )
+    .await;
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["enabled"], false);
+
+    let totp = Totp::from_encoded(&secret).unwrap();
+    let stale = totp.code(Totp::step(Utc::now().timestamp()) - 10);
+    let resp =
+        call_service(&app, request("/two_factor/confirm", Some(&stale))).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let code = current_code(&secret);
+    let resp =
+        call_service(&app, request("/two_factor/confirm", Some(&code))).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    let recovery_codes = body["recovery_codes"].as_array().unwrap();
+    assert_eq!(recovery_codes.len(), RECOVERY_CODES);
+    let status = harness.repo.two_factor_status(&alice).await.unwrap();
+    assert!(status.enabled);
+    assert_eq!(status.recovery_codes_left, RECOVERY_CODES as i64);
+
+    // enrolling again would swap the secret behind the user's back
+    let resp = call_service(&app, request("/two_factor", None)).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    // a code works once
+    let resp =
+        call_service(&app, request("/two_factor/disable", Some(&code))).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let recovery_code = recovery_codes[0].as_str().unwrap().to_uppercase();
+    let resp = call_service(
+        &app,
+        request("/two_factor/disable", Some(&recovery_code)),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let status = harness.repo.two_factor_status(&alice).await.unwrap();
+    assert!(!status.enabled);
+    assert_eq!(status.recovery_codes_left, 0);
+}
+
+#[actix_web::test]
+async fn test_two_factor_login_challenge() {
+    let harness = harness!();
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(harness.repo.clone()))
+            .app_data(Data::new(JWTTokenManager::<Hmac<Sha256>>::new(
+                Hmac::new_from_slice(b"test secret").unwrap(),
+            )))
+            .app_data(Data::new(TokenLifetimes {
+                access: Duration::minutes(15),
+                refresh: Duration::days(30),
+            }))
+            .route(
+                "/login/two_factor",
+                post().to(login_two_factor::<
+                    PostgresRepository,
+                    JWTTokenManager<Hmac<Sha256>>,
+                    PostgresRepository,
+                    PostgresRepository,
+                >),
+            ),
+    )
+    .await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let totp = Totp::generate();
+    let (recovery_codes, hashes) = generate_recovery_codes();
+    harness
+        .repo
+        .save_pending_two_factor(&alice, &totp.encoded())
+        .await
+        .unwrap();
+    harness
+        .repo
+        .enable_two_factor(&alice, &hashes)
+        .await
+        .unwrap();
+    let attempt = |challenge: &str, code: &str| {
+        TestRequest::post()
+            .uri("/login/two_factor")
+            .set_json(json!({ "challenge": challenge, "code": code }))
+            .to_request()
+    };
+    let wrong = totp.code(Totp::step(Utc::now().timestamp()) - 10);
+
+    // the attempts are used up, even the right code doesn't help then
+    let locked = create_challenge(&harness.repo, &alice).await;
+    for _ in 0..TWO_FACTOR_MAX_ATTEMPTS {
+        let resp = call_service(&app, attempt(&locked, &wrong)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    let resp = call_service(&app, attempt(&locked, &recovery_codes[0])).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let passed = create_challenge(&harness.repo, &alice).await;
+    let resp = call_service(&app, attempt(&passed, &recovery_codes[0])).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert!(body["token"].is_string());
+    assert!(body["refresh_token"].is_string());
+    // challenges and recovery codes are single use
+    let resp = call_service(&app, attempt(&passed, &recovery_codes[1])).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = call_service(
+        &app,
+        attempt(
+            &create_challenge(&harness.repo, &alice).await,
+            &recovery_codes[0],
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        harness
+            .repo
+            .two_factor_status(&alice)
+            .await
+            .unwrap()
+            .recovery_codes_left,
+        RECOVERY_CODES as i64 - 1
+    );
+    let resp = call_service(
+        &app,
+        attempt(&generate_challenge_id(), &current_code(&totp.encoded())),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn test_login_context_ip_goes_through_trusted_proxies() {
+    let lifetimes = TokenLifetimes {
+        access: Duration::minutes(15),
+        refresh: Duration::days(30),
+    };
+    let request = |proxies: &str| {
+        TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "198.51.100.1, 203.0.113.7"))
+            .insert_header(("X-Real-IP", "198.51.100.1"))
+            .app_data(Data::new(proxies.parse::<TrustedProxies>().unwrap()))
+            .to_http_request()
+    };
+
+    let login = login_context(&request("10.0.0.1"), &lifetimes, None, None);
+    assert_eq!(login.ip.as_deref(), Some("203.0.113.7"));
+    // clients can't name their own address
+    let login = login_context(&request(""), &lifetimes, None, None);
+    assert_eq!(login.ip.as_deref(), Some("10.0.0.1"));
+}
+
+#[test]
+fn test_login_outcome_is_tagged() {
+    let challenge = to_value(LoginOutcome::TwoFactorRequired {
+        challenge: "c".into(),
+        expires_in: 300,
+    })
+    .unwrap();
+    assert_eq!(
+        challenge,
+        json!({
+            "status": "two_factor_required",
+            "challenge": "c",
+            "expires_in": 300,
+        })
+    );
+    let token = to_value(LoginOutcome::Authenticated(LoginResp {
+        token: "t".into(),
+        refresh_token: "r".into(),
+        expires_in: 900,
+    }))
+    .unwrap();
+    assert_eq!(
+        token,
+        json!({
+            "status": "authenticated",
+            "token": "t",
+            "refresh_token": "r",
+            "expires_in": 900,
+        })
+    );
+}
+
+#[actix_web::test]
+async fn test_update_profile() {
+    let harness = harness!();
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(harness.repo.clone()))
+            .app_data(Data::new(harness.addrs.clone()))
+            .route(
+                "/me",
+                patch().to(update_profile::<PostgresRepository, AddrMap>),
+            ),
+    )
+    .await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let carol = create_user(&harness.pool, &harness.notifier).await;
+    make_friends(&harness.pool, &alice, &bob).await;
+    let bob_inbox = connect(&harness.addrs, &bob).await;
+    let carol_inbox = connect(&harness.addrs, &carol).await;
+    let patch_me = |body: serde_json::Value| {
+        TestRequest::patch()
+            .uri("/me")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(body)
+            .to_request()
+    };
+
+    let resp = call_service(
+        &app,
+        patch_me(json!({ "display_name": "  Alice ", "bio": "one\ntwo" })),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let me: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(me["display_name"], "Alice");
+    assert_eq!(me["bio"], "one\ntwo");
+    let received = bob_inbox.send(TakeReceived).await.unwrap();
+    assert!(
+        matches!(
+            &received[..],
+            [Message::System(SystemMessage::ProfileUpdated {
+                id,
+                display_name: Some(name),
+                bio: Some(_),
+                ..
+            })] if *id == alice && name == "Alice"
+        ),
+        "{:?}",
+        received
+    );
+    assert!(carol_inbox.send(TakeReceived).await.unwrap().is_empty());
+
+    // a missing field is left alone, null clears it
+    let resp = call_service(&app, patch_me(json!({ "bio": null }))).await;
+    let me: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(me["display_name"], "Alice");
+    assert_eq!(me["bio"], serde_json::Value::Null);
+    let resp = call_service(&app, patch_me(json!({}))).await;
+    let me: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(me["display_name"], "Alice");
+    let exported = harness.repo.user_data(&alice).await.unwrap().profile;
+    assert_eq!(exported.display_name.as_deref(), Some("Alice"));
+    assert_eq!(exported.bio, None);
+    assert_eq!(bob_inbox.send(TakeReceived).await.unwrap().len(), 2);
+
+    for invalid in [
+        json!({ "display_name": "a".repeat(DISPLAY_NAME_MAX_CHARS + 1) }),
+        json!({ "display_name": "two\nlines" }),
+        json!({ "bio": "b".repeat(BIO_MAX_CHARS + 1) }),
+        json!({ "bio": "bell\u{7}" }),
+    ] {
+        let resp = call_service(&app, patch_me(invalid.clone())).await;
+        assert_eq!(
+            resp.status(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}",
+            invalid
+        );
+    }
+    // blank clears too
+    let resp =
+        call_service(&app, patch_me(json!({ "display_name": "   " }))).await;
+    let me: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(me["display_name"], serde_json::Value::Null);
+    assert_eq!(bob_inbox.send(TakeReceived).await.unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn test_friend_metadata() {
+    let harness = harness!();
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(harness.repo.clone()))
+            .route("/friends", get().to(my_friends::<PostgresRepository>))
+            .route(
+                "/friends/metadata",
+                get().to(my_friend_metadata::<PostgresRepository>),
+            )
+            .route(
+                "/friends/labels",
+                get().to(my_friend_labels::<PostgresRepository>),
+            )
+            .route(
+                "/friends/{friend_id}/metadata",
+                get().to(friend_metadata::<PostgresRepository>),
+            )
+            .route(
+                "/friends/{friend_id}/metadata",
+                put().to(update_friend_metadata::<
+                    PostgresRepository,
+                    PostgresRepository,
+                >),
+            )
+            .route(
+                "/friends/{friend_id}/metadata",
+                delete().to(delete_friend_metadata::<PostgresRepository>),
+            )
+            .route("/sessions", get().to(my_sessions::<PostgresRepository>)),
+    )
+    .await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let carol = create_user(&harness.pool, &harness.notifier).await;
+    let dave = create_user(&harness.pool, &harness.notifier).await;
+    make_friends(&harness.pool, &alice, &bob).await;
+    make_friends(&harness.pool, &carol, &alice).await;
+    harness
+        .repo
+        .update_profile(
+            &bob,
+            &ProfileUpdate {
+                display_name: Some(Some("Bob".into())),
+                bio: None,
+            },
+        )
+        .await
+        .unwrap();
+    query!(
+        r#"INSERT INTO messages (id, "from", "to", content, mime_type)
+        VALUES ($1, $2, $3, 'hi', 'text/plain')"#,
+        Uuid::new_v4().to_string(),
+        bob,
+        alice,
+    )
+    .execute(&harness.pool)
+    .await
+    .unwrap();
+    let get_as = |uid: &str, uri: &str| {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header(("X-User-ID", uid))
+            .to_request()
+    };
+    let put_metadata = |friend_id: &str, body: serde_json::Value| {
+        TestRequest::put()
+            .uri(&format!("/friends/{}/metadata", friend_id))
+            .insert_header(("X-User-ID", alice.as_str()))
+            .set_json(body)
+            .to_request()
+    };
+    let friends_labelled =
+        |label: &str| get_as(&alice, &format!("/friends?label={}", label));
+
+    let resp = call_service(
+        &app,
+        get_as(&alice, &format!("/friends/{}/metadata", bob)),
+    )
+    .await;
+    let metadata: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(
+        metadata,
+        json!({
+            "friend_id": bob,
+            "nickname": null,
+            "labels": [],
+            "favorite": false,
+        })
+    );
+
+    let resp = call_service(
+        &app,
+        put_metadata(
+            &bob,
+            json!({
+                "nickname": " Bobby ",
+                "labels": ["Work", " Family", "Work", ""],
+                "favorite": true,
+            }),
+        ),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let metadata: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(metadata["nickname"], "Bobby");
+    assert_eq!(metadata["labels"], json!(["Work", "Family"]));
+    assert_eq!(metadata["favorite"], true);
+    let resp = call_service(
+        &app,
+        put_metadata(&carol, json!({ "labels": ["Family"] })),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let resp = call_service(&app, put_metadata(&dave, json!({}))).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let too_many: Vec<_> = (0..=FRIEND_LABELS_MAX)
+        .map(|i| format!("label {}", i))
+        .collect();
+    let resp =
+        call_service(&app, put_metadata(&bob, json!({ "labels": too_many })))
+            .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let resp = call_service(&app, get_as(&alice, "/friends/labels")).await;
+    let labels: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(labels, json!(["Family", "Work"]));
+    let resp = call_service(&app, get_as(&alice, "/friends/metadata")).await;
+    let all: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(all.as_array().unwrap().len(), 2);
+    // it's alice's alone
+    let resp = call_service(&app, get_as(&bob, "/friends/metadata")).await;
+    let all: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(all, json!([]));
+
+    let resp = call_service(&app, friends_labelled("Work")).await;
+    let page: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["items"][0]["id"], bob);
+    assert_eq!(page["items"][0]["display_name"], "Bobby");
+    let resp = call_service(&app, friends_labelled("Family")).await;
+    let page: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(page["total"], 2);
+    let resp = call_service(&app, friends_labelled("Nobody")).await;
+    let page: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(page["total"], 0);
+    let resp = call_service(&app, get_as(&alice, "/sessions")).await;
+    let sessions: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(sessions[0]["peer_display_name"], "Bobby");
+
+    let resp = call_service(
+        &app,
+        TestRequest::delete()
+            .uri(&format!("/friends/{}/metadata", bob))
+            .insert_header(("X-User-ID", alice.as_str()))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let resp = call_service(
+        &app,
+        get_as(&alice, &format!("/friends/{}/metadata", bob)),
+    )
+    .await;
+    let metadata: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(metadata["nickname"], serde_json::Value::Null);
+    assert_eq!(metadata["favorite"], false);
+    let resp = call_service(&app, friends_labelled("Work")).await;
+    let page: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(page["total"], 0);
+    let resp = call_service(&app, get_as(&alice, "/sessions")).await;
+    let sessions: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(sessions[0]["peer_display_name"], "Bob");
+}
+
+#[actix_web::test]
+async fn test_unfriend_and_cancel_request() {
+    let harness = harness!();
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(harness.repo.clone()))
+            .app_data(Data::new(harness.addrs.clone()))
+            .route(
+                "/friends/requests/outgoing",
+                get().to(my_outgoing_requests::<PostgresRepository>),
+            )
+            .route(
+                "/friends/requests/{id}",
+                delete().to(cancel_request::<PostgresRepository, AddrMap>),
+            )
+            .route(
+                "/friends/{friend_id}",
+                delete().to(unfriend::<PostgresRepository, AddrMap>),
+            ),
+    )
+    .await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let carol = create_user(&harness.pool, &harness.notifier).await;
+    make_friends(&harness.pool, &alice, &bob).await;
+    let bob_inbox = connect(&harness.addrs, &bob).await;
+    let carol_inbox = connect(&harness.addrs, &carol).await;
+    harness
+        .repo
+        .update_friend_metadata(
+            &alice,
+            &FriendMetadata {
+                friend_id: bob.clone(),
+                nickname: Some("Bobby".into()),
+                labels: vec!["Work".into()],
+                favorite: true,
+            },
+        )
+        .await
+        .unwrap();
+    let delete_as = |uid: &str, uri: String| {
+        TestRequest::delete()
+            .uri(&uri)
+            .insert_header(("X-User-ID", uid))
+            .to_request()
+    };
+    let outgoing = || {
+        TestRequest::get()
+            .uri("/friends/requests/outgoing")
+            .insert_header(("X-User-ID", alice.as_str()))
+            .to_request()
+    };
+
+    let resp =
+        call_service(&app, delete_as(&alice, format!("/friends/{}", bob)))
+            .await;
+    assert!(resp.status().is_success());
+    assert!(!harness.repo.is_friend(&alice, &bob).await.unwrap());
+    let received = bob_inbox.send(TakeReceived).await.unwrap();
+    assert!(
+        matches!(
+            &received[..],
+            [Message::System(SystemMessage::FriendRemoved { id })]
+                if *id == alice
+        ),
+        "{:?}",
+        received
+    );
+    // what alice kept about bob went with the friendship
+    let metadata = harness.repo.friend_metadata(&alice, &bob).await.unwrap();
+    assert_eq!(metadata.nickname, None);
+    assert!(metadata.labels.is_empty());
+    assert!(!metadata.favorite);
+    let resp =
+        call_service(&app, delete_as(&alice, format!("/friends/{}", bob)))
+            .await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let id = harness
+        .repo
+        .add_friend_request(&alice, &carol, None)
+        .await
+        .unwrap();
+    let resp = call_service(&app, outgoing()).await;
+    let requests: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(requests.as_array().unwrap().len(), 1);
+    assert_eq!(requests[0]["id"], id);
+    assert_eq!(requests[0]["to"], carol);
+    // only the sender can withdraw it
+    let resp = call_service(
+        &app,
+        delete_as(&carol, format!("/friends/requests/{}", id)),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = call_service(
+        &app,
+        delete_as(&alice, format!("/friends/requests/{}", id)),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let received = carol_inbox.send(TakeReceived).await.unwrap();
+    assert!(
+        matches!(
+            &received[..],
+            [Message::System(SystemMessage::FriendRequestCancelled {
+                id: cancelled
+            })] if *cancelled == id
+        ),
+        "{:?}",
+        received
+    );
+    let resp = call_service(&app, outgoing()).await;
+    let requests: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(requests, json!([]));
+    let resp = call_service(
+        &app,
+        delete_as(&alice, format!("/friends/requests/{}", id)),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+/// A repository whose connections run in `time_zone` instead of the
+/// server's default.
+async fn repo_in_time_zone(time_zone: &'static str) -> PostgresRepository {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .after_connect(move |conn, _| {
+            Box::pin(async move {
+                conn.execute(format!("SET TIME ZONE '{}'", time_zone).as_str())
+                    .await?;
+                Ok(())
+            })
+        })
+        .connect(&env::var("DATABASE_URL").unwrap())
+        .await
+        .unwrap();
+    PostgresRepository::new(pool)
+}
+
+#[actix_web::test]
+async fn test_friend_requests_expire() {
+    let harness = harness!();
+    // created_at is a TIMESTAMP in the session's time zone. Were it taken
+    // as UTC anywhere, requests made at UTC-11 would look 11 hours old.
+    let repo = repo_in_time_zone("Pacific/Pago_Pago").await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let bob = create_user(&harness.pool, &harness.notifier).await;
+    let carol = create_user(&harness.pool, &harness.notifier).await;
+    let alice_inbox = connect(&harness.addrs, &alice).await;
+    let bob_inbox = connect(&harness.addrs, &bob).await;
+    let carol_inbox = connect(&harness.addrs, &carol).await;
+    let stale = repo.add_friend_request(&alice, &bob, None).await.unwrap();
+    let fresh = repo.add_friend_request(&alice, &carol, None).await.unwrap();
+    query!(
+        "UPDATE friend_requests SET created_at = created_at - INTERVAL '2 hours' WHERE id = $1",
+        stale,
+    )
+    .execute(&harness.pool)
+    .await
+    .unwrap();
+
+    let worker = FriendRequestExpiryWorker::new(
+        repo.clone(),
+        harness.addrs.clone(),
+        Duration::hours(1),
+    );
+    // requests other runs left behind may take a few batches
+    while worker.expire().await.unwrap() > 0 {}
+    let pending: Vec<_> = repo
+        .outgoing_friend_requests(&alice)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|request| request.id)
+        .collect();
+    assert_eq!(pending, [fresh]);
+    for inbox in [alice_inbox, bob_inbox] {
+        let received = inbox.send(TakeReceived).await.unwrap();
+        assert!(
+            matches!(
+                &received[..],
+                [Message::System(SystemMessage::FriendRequestExpired { id })]
+                    if *id == stale
+            ),
+            "{:?}",
+            received
+        );
+    }
+    assert!(carol_inbox.send(TakeReceived).await.unwrap().is_empty());
+}
+
+/// Keeps the texts it is asked to send.
+#[derive(Clone, Default)]
+struct RecordingSms(std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+impl SmsSender for RecordingSms {
+    async fn send_sms(
+        &self,
+        phone: &str,
+        text: &str,
+    ) -> crate::core::error::Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .push((phone.to_owned(), text.to_owned()));
+        Ok(())
+    }
+}
+
+impl RecordingSms {
+    fn sent(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// The code in the last text to `phone`.
+    fn code(&self, phone: &str) -> String {
+        let texts = self.0.lock().unwrap();
+        let (_, text) = texts.iter().rev().find(|(to, _)| to == phone).unwrap();
+        text.split(' ')
+            .nth(4)
+            .unwrap()
+            .trim_end_matches(',')
+            .to_owned()
+    }
+}
+
+/// A phone nobody else has, normalized already.
+fn random_phone() -> String {
+    format!("+86138{:08}", Uuid::new_v4().as_u128() % 100_000_000)
+}
+
+type TestAuthService = AuthService<
+    PostgresRepository,
+    Argon2Hasher,
+    JWTTokenManager<Hmac<Sha256>>,
+>;
+
+/// Cheap enough to hash with in every test.
+fn test_hasher() -> Argon2Hasher {
+    Argon2Hasher::new(8, 1, 1).unwrap()
+}
+
+fn test_auth_service(repo: &PostgresRepository) -> TestAuthService {
+    AuthService::new(
+        repo.clone(),
+        test_hasher(),
+        JWTTokenManager::new(Hmac::new_from_slice(b"test secret").unwrap()),
+    )
+}
+
+macro_rules! auth_app {
+    ($harness:expr, $sms:expr) => {
+        init_service(
+            App::new()
+                .app_data(Data::new($harness.repo.clone()))
+                .app_data(Data::new($sms.clone()))
+                .app_data(Data::new(PhoneNormalizer::new("cn").unwrap()))
+                .app_data(Data::new(test_auth_service(&$harness.repo)))
+                .app_data(Data::new(test_hasher()))
+                .route(
+                    "/signup/code",
+                    post().to(request_signup_code::<
+                        PostgresRepository,
+                        RecordingSms,
+                    >),
+                )
+                .route(
+                    "/signup",
+                    post().to(signup::<
+                        PostgresRepository,
+                        Argon2Hasher,
+                        JWTTokenManager<Hmac<Sha256>>,
+                        PostgresRepository,
+                    >),
+                )
+                .route(
+                    "/password_reset/code",
+                    post().to(request_password_reset_code::<
+                        PostgresRepository,
+                        PostgresRepository,
+                        RecordingSms,
+                    >),
+                )
+                .route(
+                    "/password_reset",
+                    post().to(reset_password::<
+                        PostgresRepository,
+                        Argon2Hasher,
+                        JWTTokenManager<Hmac<Sha256>>,
+                        PostgresRepository,
+                        PostgresRepository,
+                    >),
+                ),
+        )
+    };
+}
+
+#[actix_web::test]
+async fn test_signup_code_cooldown() {
+    let harness = harness!();
+    let sms = RecordingSms::default();
+    let app = auth_app!(harness, sms).await;
+    let phone = random_phone();
+    let request_code = || {
+        TestRequest::post()
+            .uri("/signup/code")
+            .set_json(json!({ "phone": phone }))
+            .to_request()
+    };
+
+    let resp = call_service(&app, request_code()).await;
+    assert!(resp.status().is_success());
+    let resp = call_service(&app, request_code()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(sms.sent(), 1);
+}
+
+#[actix_web::test]
+async fn test_signup_code_attempts_are_limited() {
+    let harness = harness!();
+    let sms = RecordingSms::default();
+    let app = auth_app!(harness, sms).await;
+    let phone = random_phone();
+    let signup = |code: &str| {
+        TestRequest::post()
+            .uri("/signup")
+            .set_json(
+                json!({ "phone": phone, "password": "secret", "code": code }),
+            )
+            .to_request()
+    };
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/signup/code")
+            .set_json(json!({ "phone": phone }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let code = sms.code(&phone);
+    let wrong =
+        format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+    for _ in 0..VERIFICATION_MAX_ATTEMPTS {
+        let resp = call_service(&app, signup(&wrong)).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    // not even the right code works anymore
+    let resp = call_service(&app, signup(&code)).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(!harness.repo.exists_user(&phone).await.unwrap());
+}
+
+#[actix_web::test]
+async fn test_signup_code_expires() {
+    let harness = harness!();
+    let sms = RecordingSms::default();
+    let app = auth_app!(harness, sms).await;
+    let phone = random_phone();
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/signup/code")
+            .set_json(json!({ "phone": phone }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    query!(
+        "UPDATE phone_verifications SET expires_at = now() WHERE phone = $1",
+        phone,
+    )
+    .execute(&harness.pool)
+    .await
+    .unwrap();
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/signup")
+            .set_json(json!({
+                "phone": phone,
+                "password": "secret",
+                "code": sms.code(&phone),
+            }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(!harness.repo.exists_user(&phone).await.unwrap());
+}
+
+#[actix_web::test]
+async fn test_signup_taken_phone() {
+    let harness = harness!();
+    let sms = RecordingSms::default();
+    let app = auth_app!(harness, sms).await;
+    let phone = random_phone();
+    let signup = |code: &str| {
+        TestRequest::post()
+            .uri("/signup")
+            .set_json(
+                json!({ "phone": phone, "password": "secret", "code": code }),
+            )
+            .to_request()
+    };
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/signup/code")
+            .set_json(json!({ "phone": phone }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let code = sms.code(&phone);
+    let resp = call_service(&app, signup(&code)).await;
+    assert!(resp.status().is_success());
+    assert!(harness.repo.exists_user(&phone).await.unwrap());
+
+    let resp = call_service(&app, signup(&code)).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+}
+
+fn test_lifetimes() -> TokenLifetimes {
+    TokenLifetimes {
+        access: Duration::minutes(15),
+        refresh: Duration::days(30),
+    }
+}
+
+fn test_login(session_id: &str) -> LoginContext {
+    LoginContext {
+        session_id: session_id.to_owned(),
+        key_expires_at: Utc::now() + test_lifetimes().access,
+        device_name: None,
+        platform: None,
+        ip: None,
+    }
+}
+
+/// Logs `phone` in the way the login handler does.
+async fn log_in(
+    auth: &TestAuthService,
+    phone: &str,
+    password: &str,
+) -> std::result::Result<String, auth_service::core::error::Error> {
+    let login = test_login(&Uuid::new_v4().to_string());
+    LOGIN.scope(login, auth.login(phone, password)).await
+}
+
+#[actix_web::test]
+async fn test_reset_password() {
+    let harness = harness!();
+    let sms = RecordingSms::default();
+    let app = auth_app!(harness, sms).await;
+    let auth = test_auth_service(&harness.repo);
+    let phone = random_phone();
+    auth.signup(&phone, "old secret").await.unwrap();
+    let reset = |code: &str| {
+        TestRequest::post()
+            .uri("/password_reset")
+            .set_json(json!({
+                "phone": phone,
+                "code": code,
+                "password": "new secret",
+            }))
+            .to_request()
+    };
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/password_reset/code")
+            .set_json(json!({ "phone": phone }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let code = sms.code(&phone);
+    let wrong =
+        format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+    let resp = call_service(&app, reset(&wrong)).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(log_in(&auth, &phone, "old secret").await.is_ok());
+
+    let resp = call_service(&app, reset(&code)).await;
+    assert!(resp.status().is_success());
+    assert!(log_in(&auth, &phone, "old secret").await.is_err());
+    assert!(log_in(&auth, &phone, "new secret").await.is_ok());
+    // the code is single use
+    let resp = call_service(&app, reset(&code)).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn test_reset_password_revokes_sessions() {
+    let harness = harness!();
+    let sms = RecordingSms::default();
+    let app = auth_app!(harness, sms).await;
+    let auth = test_auth_service(&harness.repo);
+    let phone = random_phone();
+    auth.signup(&phone, "old secret").await.unwrap();
+    let uid = query_scalar!("SELECT id FROM users WHERE phone = $1", phone)
+        .fetch_one(&harness.pool)
+        .await
+        .unwrap();
+    let mut tokens = Vec::new();
+    for _ in 0..2 {
+        tokens.push(log_in(&auth, &phone, "old secret").await.unwrap());
+    }
+    for token in &tokens {
+        assert_eq!(auth.verify_token(token).await.unwrap(), uid);
+    }
+
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/password_reset/code")
+            .set_json(json!({ "phone": phone }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/password_reset")
+            .set_json(json!({
+                "phone": phone,
+                "code": sms.code(&phone),
+                "password": "new secret",
+            }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    for token in &tokens {
+        assert!(auth.verify_token(token).await.is_err());
+    }
+    let sessions = query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM login_sessions WHERE user_id = $1"#,
+        uid,
+    )
+    .fetch_one(&harness.pool)
+    .await
+    .unwrap();
+    assert_eq!(sessions, 0);
+}
+
+#[actix_web::test]
+async fn test_reset_code_for_unknown_phone() {
+    let harness = harness!();
+    let sms = RecordingSms::default();
+    let app = auth_app!(harness, sms).await;
+
+    // looks the same as for a registered phone, but nothing is sent
+    let resp = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/password_reset/code")
+            .set_json(json!({ "phone": random_phone() }))
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    assert_eq!(sms.sent(), 0);
+}
+
+/// Logs `phone` in and hands out the first refresh token, like a login
+/// without a second factor. Returns the id of the session too.
+async fn open_session(
+    repo: &PostgresRepository,
+    auth: &TestAuthService,
+    phone: &str,
+) -> (String, LoginResp) {
+    let session_id = Uuid::new_v4().to_string();
+    let token = LOGIN
+        .scope(test_login(&session_id), auth.login(phone, "secret"))
+        .await
+        .unwrap();
+    let resp = start_session(repo, &test_lifetimes(), &session_id, token)
+        .await
+        .unwrap();
+    (session_id, resp)
+}
+
+macro_rules! refresh_app {
+    ($harness:expr) => {
+        init_service(
+            App::new()
+                .app_data(Data::new($harness.repo.clone()))
+                .app_data(Data::new(JWTTokenManager::<Hmac<Sha256>>::new(
+                    Hmac::new_from_slice(b"test secret").unwrap(),
+                )))
+                .app_data(Data::new(test_lifetimes()))
+                .route(
+                    "/token/refresh",
+                    post().to(refresh_token::<
+                        JWTTokenManager<Hmac<Sha256>>,
+                        PostgresRepository,
+                    >),
+                ),
+        )
+    };
+}
+
+fn refresh(refresh_token: &str) -> TestRequest {
+    TestRequest::post()
+        .uri("/token/refresh")
+        .set_json(json!({ "refresh_token": refresh_token }))
+}
+
+#[actix_web::test]
+async fn test_refresh_token_rotation() {
+    let harness = harness!();
+    let app = refresh_app!(harness).await;
+    let auth = test_auth_service(&harness.repo);
+    let phone = random_phone();
+    auth.signup(&phone, "secret").await.unwrap();
+    let (_, login) = open_session(&harness.repo, &auth, &phone).await;
+    let uid = auth.verify_token(&login.token).await.unwrap();
+
+    let resp =
+        call_service(&app, refresh(&login.refresh_token).to_request()).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    let token = body["token"].as_str().unwrap();
+    let refresh_token = body["refresh_token"].as_str().unwrap();
+    assert_ne!(refresh_token, login.refresh_token);
+    assert_eq!(auth.verify_token(token).await.unwrap(), uid);
+    // the session moved on to the new token
+    assert!(auth.verify_token(&login.token).await.is_err());
+    let resp = call_service(&app, refresh(refresh_token).to_request()).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_refresh_token_reuse_revokes_session() {
+    let harness = harness!();
+    let app = refresh_app!(harness).await;
+    let auth = test_auth_service(&harness.repo);
+    let phone = random_phone();
+    auth.signup(&phone, "secret").await.unwrap();
+    let (session_id, login) = open_session(&harness.repo, &auth, &phone).await;
+    let (_, other) = open_session(&harness.repo, &auth, &phone).await;
+
+    let resp =
+        call_service(&app, refresh(&login.refresh_token).to_request()).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = read_body_json(resp).await;
+    let token = body["token"].as_str().unwrap();
+    let refresh_token = body["refresh_token"].as_str().unwrap();
+
+    let resp =
+        call_service(&app, refresh(&login.refresh_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let sessions = query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM login_sessions WHERE id = $1"#,
+        session_id,
+    )
+    .fetch_one(&harness.pool)
+    .await
+    .unwrap();
+    assert_eq!(sessions, 0);
+    // whoever holds the newer tokens of the session is out as well
+    assert!(auth.verify_token(token).await.is_err());
+    let resp = call_service(&app, refresh(refresh_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    // the other devices stay logged in
+    assert!(auth.verify_token(&other.token).await.is_ok());
+}
+
+#[actix_web::test]
+async fn test_refresh_token_expires() {
+    let harness = harness!();
+    let app = refresh_app!(harness).await;
+    let auth = test_auth_service(&harness.repo);
+    let phone = random_phone();
+    auth.signup(&phone, "secret").await.unwrap();
+    let (_, login) = open_session(&harness.repo, &auth, &phone).await;
+    query!(
+        "UPDATE refresh_tokens SET expires_at = now() WHERE token_hash = $1",
+        sha256_hex(&login.refresh_token),
+    )
+    .execute(&harness.pool)
+    .await
+    .unwrap();
+
+    let resp =
+        call_service(&app, refresh(&login.refresh_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+macro_rules! ws_app {
+    ($harness:expr, $query_token_auth:expr) => {
+        init_service(
+            App::new()
+                .app_data(Data::new($harness.repo.clone()))
+                .app_data(Data::new($harness.addrs.clone()))
+                .app_data(Data::new($harness.notifier.clone()))
+                .app_data(Data::new(test_auth_service(&$harness.repo)))
+                .app_data(Data::new(QueryTokenAuth($query_token_auth)))
+                .route(
+                    "/ws",
+                    get().to(crate::ws::actor::index::<
+                        PostgresRepository,
+                        Argon2Hasher,
+                        JWTTokenManager<Hmac<Sha256>>,
+                        PostgresRepository,
+                        PostgresRepository,
+                        MemoryNotifier,
+                        AddrMap,
+                    >),
+                ),
+        )
+    };
+}
+
+/// A websocket handshake for `uri`.
+fn upgrade(uri: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(uri)
+        .insert_header(("Upgrade", "websocket"))
+        .insert_header(("Connection", "Upgrade"))
+        .insert_header(("Sec-WebSocket-Version", "13"))
+        .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+}
+
+/// Signs a user up and logs them in, returns their id and token.
+async fn signed_up(harness: &Harness) -> (String, String) {
+    let auth = test_auth_service(&harness.repo);
+    let phone = random_phone();
+    auth.signup(&phone, "secret").await.unwrap();
+    let token = log_in(&auth, &phone, "secret").await.unwrap();
+    (auth.verify_token(&token).await.unwrap(), token)
+}
+
+#[actix_web::test]
+async fn test_ws_ticket_is_single_use() {
+    let harness = harness!();
+    let app = ws_app!(harness, false).await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let ticket = Uuid::new_v4().simple().to_string();
+    harness
+        .repo
+        .issue_ws_ticket(&alice, &sha256_hex(&ticket), Duration::minutes(1))
+        .await
+        .unwrap();
+    let uri = format!("/ws?ticket={}", ticket);
+
+    let resp = call_service(&app, upgrade(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert!(harness.addrs.get_addr(&alice).await.unwrap().is_some());
+    let resp = call_service(&app, upgrade(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn test_ws_ticket_expires() {
+    let harness = harness!();
+    let app = ws_app!(harness, false).await;
+    let alice = create_user(&harness.pool, &harness.notifier).await;
+    let ticket = Uuid::new_v4().simple().to_string();
+    harness
+        .repo
+        .issue_ws_ticket(&alice, &sha256_hex(&ticket), Duration::minutes(1))
+        .await
+        .unwrap();
+    query!(
+        "UPDATE ws_tickets SET expires_at = now() WHERE ticket_hash = $1",
+        sha256_hex(&ticket),
+    )
+    .execute(&harness.pool)
+    .await
+    .unwrap();
+
+    let uri = format!("/ws?ticket={}", ticket);
+    let resp = call_service(&app, upgrade(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(harness.addrs.get_addr(&alice).await.unwrap().is_none());
+}
+
+#[actix_web::test]
+async fn test_ws_token_auth() {
+    let harness = harness!();
+    let app = ws_app!(harness, false).await;
+    let (uid, token) = signed_up(&harness).await;
+
+    let resp = call_service(
+        &app,
+        upgrade("/ws")
+            .insert_header(("X-Auth-Token", token.as_str()))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert!(harness.addrs.get_addr(&uid).await.unwrap().is_some());
+    harness.addrs.remove_addr(&uid).await.unwrap();
+
+    let resp = call_service(
+        &app,
+        upgrade("/ws")
+            .insert_header((
+                SEC_WEBSOCKET_PROTOCOL,
+                format!("chat, auth.{}", token),
+            ))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    // the token isn't echoed back
+    assert_eq!(resp.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(), "chat");
+    assert!(harness.addrs.get_addr(&uid).await.unwrap().is_some());
+
+    let resp = call_service(
+        &app,
+        upgrade("/ws")
+            .insert_header(("X-Auth-Token", "forged"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = call_service(&app, upgrade("/ws").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_ws_query_token_can_be_turned_off() {
+    let harness = harness!();
+    let (_, token) = signed_up(&harness).await;
+    let uri = format!("/ws?auth_token={}", token);
+
+    let app = ws_app!(harness, true).await;
+    let resp = call_service(&app, upgrade(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    let app = ws_app!(harness, false).await;
+    let resp = call_service(&app, upgrade(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
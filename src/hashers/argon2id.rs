@@ -0,0 +1,138 @@
+use ::argon2::{
+    password_hash::{
+        errors::InvalidValue, rand_core::OsRng, Error as HashError,
+        PasswordHasher, Salt, SaltString,
+    },
+    Algorithm, Argon2, Params, Version,
+};
+use auth_service::{
+    core::{error::Error, hasher::Hasher},
+    hashers::sha::ShaHasher,
+};
+
+use crate::core::credential::RehashPolicy;
+
+const PREFIX: &str = "$argon2id$v=19$";
+
+/// Hashes passwords with Argon2id.
+///
+/// The salt is stored in PHC form with the parameters it is meant for, like
+/// `$argon2id$v=19$m=19456,t=2,p=1$<salt>`, and hashes come out as full PHC
+/// strings, so both say which algorithm made them and changing the
+/// parameters doesn't break existing passwords. Salts without the prefix
+/// belong to passwords from before Argon2id and are hashed with SHA.
+#[derive(Debug, Clone)]
+pub(crate) struct Argon2Hasher {
+    params: Params,
+    legacy: ShaHasher,
+}
+
+impl Argon2Hasher {
+    /// `memory_kib` is the memory cost in KiB, `iterations` the time cost and
+    /// `parallelism` the number of lanes.
+    pub(crate) fn new(
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> Result<Self, ::argon2::Error> {
+        Ok(Self {
+            params: Params::new(memory_kib, iterations, parallelism, None)?,
+            legacy: ShaHasher {},
+        })
+    }
+
+    fn params_string(params: &Params) -> String {
+        format!(
+            "m={},t={},p={}",
+            params.m_cost(),
+            params.t_cost(),
+            params.p_cost()
+        )
+    }
+}
+
+fn parse_salt(salt: &str) -> Result<(Params, &str), HashError> {
+    let malformed = HashError::SaltInvalid(InvalidValue::Malformed);
+    let (params, salt) = salt
+        .strip_prefix(PREFIX)
+        .and_then(|s| s.split_once('$'))
+        .ok_or(malformed)?;
+    let mut cost = [None; 3];
+    for param in params.split(',') {
+        let (name, value) = param.split_once('=').ok_or(malformed)?;
+        let i = ["m", "t", "p"]
+            .iter()
+            .position(|n| *n == name)
+            .ok_or(malformed)?;
+        cost[i] = Some(value.parse().map_err(|_| malformed)?);
+    }
+    let [Some(m), Some(t), Some(p)] = cost else {
+        return Err(malformed);
+    };
+    Ok((Params::new(m, t, p, None)?, salt))
+}
+
+impl Hasher for Argon2Hasher {
+    fn generate_salt(&self) -> Result<String, Error> {
+        Ok(format!(
+            "{}{}${}",
+            PREFIX,
+            Self::params_string(&self.params),
+            SaltString::generate(&mut OsRng).as_str()
+        ))
+    }
+
+    fn hash(&self, password: &str, salt: &str) -> Result<String, Error> {
+        if !salt.starts_with(PREFIX) {
+            return self.legacy.hash(password, salt);
+        }
+        let hash = || -> Result<String, HashError> {
+            let (params, salt) = parse_salt(salt)?;
+            Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password(password.as_bytes(), Salt::from_b64(salt)?)?
+                .to_string())
+        };
+        // all that can go wrong is the salt stored with the password
+        hash().map_err(|e| Error::FailedToGetPasswordSalt(Box::new(e)))
+    }
+}
+
+impl RehashPolicy for Argon2Hasher {
+    fn needs_rehash(&self, salt: &str) -> bool {
+        match parse_salt(salt) {
+            Ok((params, _)) => params != self.params,
+            Err(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_stable_per_salt() {
+        let hasher = Argon2Hasher::new(64, 1, 1).unwrap();
+        let salt = hasher.generate_salt().unwrap();
+        assert!(salt.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+        let hash = hasher.hash("secret", &salt).unwrap();
+        assert!(hash.starts_with(&salt));
+        assert_eq!(hash, hasher.hash("secret", &salt).unwrap());
+        assert_ne!(hash, hasher.hash("Secret", &salt).unwrap());
+        assert!(!hasher.needs_rehash(&salt));
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let old = Argon2Hasher::new(64, 1, 1).unwrap();
+        let new = Argon2Hasher::new(128, 2, 1).unwrap();
+        let salt = old.generate_salt().unwrap();
+        assert!(new.needs_rehash(&salt));
+        // passwords hashed with the old parameters still verify
+        assert_eq!(
+            old.hash("secret", &salt).unwrap(),
+            new.hash("secret", &salt).unwrap()
+        );
+        assert!(new.needs_rehash("legacy-sha-salt"));
+    }
+}
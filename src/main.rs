@@ -3,27 +3,50 @@
 
 pub mod core;
 pub mod handlers;
+pub mod hashers;
+pub mod middlewares;
 pub mod notifiers;
+pub mod phone;
+pub mod sms;
 pub mod stores;
+pub mod totp;
 pub mod utils;
+pub mod workers;
 pub mod ws;
 
+use crate::core::{
+    invite::InviteLinks,
+    ratelimit::{Counted, RateLimitPolicy},
+    session::TokenLifetimes,
+};
+use hashers::argon2id::Argon2Hasher;
+use middlewares::ratelimit::{RateLimitKey, RateLimiter, TrustedProxies};
 use notifiers::fcm::FCMNotifier;
+use phone::PhoneNormalizer;
+use sms::log::LogSmsSender;
 use sqlx::{postgres::PgPoolOptions, Postgres};
-use std::env;
+use std::{env, io, str::FromStr};
 use stores::{addr::AddrMap, postgres::PostgresRepository};
+use workers::{
+    contact::ContactSaltWorker, deletion::AccountDeletionWorker,
+    export::DataExportWorker, friend_request::FriendRequestExpiryWorker,
+    outbox::OutboxWorker,
+};
+use ws::actor::QueryTokenAuth;
 
 use actix_web::{
     middleware::Logger,
-    web::{delete, get, post, put, route, scope, Data},
+    web::{delete, get, patch, post, put, resource, route, scope, Data},
     App, HttpServer,
 };
 use auth_service::{
-    core::service::Service as AuthService, hashers::sha::ShaHasher,
+    core::service::Service as AuthService,
     middlewares::actix_web::AuthTokenMiddleware,
     token_managers::jwt::JWTTokenManager,
 };
+use chrono::Duration;
 use hmac::{Hmac, Mac};
+use log::info;
 use nb_from_env::{FromEnv, FromEnvDerive};
 use upload_service::{
     core::service::Service as UploadService, stores::local_fs::LocalFSStore,
@@ -33,6 +56,18 @@ use upload_service::{
 struct Config {
     listen_address: String,
     auth_token_secret: String,
+    default_phone_region: String,
+}
+
+/// Parses the environment variable `name`, or returns `default` if it's not
+/// set.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .map(|s| {
+            s.parse()
+                .unwrap_or_else(|_| panic!("invalid value of {}", name))
+        })
+        .unwrap_or(default)
 }
 
 #[actix_web::main]
@@ -49,15 +84,68 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("failed to connect to postgresql");
     let repository = PostgresRepository::new(pg_pool.clone());
-    let auth_hasher = ShaHasher {};
+    let phones = PhoneNormalizer::new(&config.default_phone_region)
+        .expect("invalid default phone region");
+    // a one-off job bringing the phones stored before E.164 in line
+    if env::args().nth(1).as_deref() == Some("normalize-phones") {
+        let report = repository
+            .normalize_phones(&phones)
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        info!(
+            "normalized {} phones, {} conflicts recorded in phone_conflicts",
+            report.normalized, report.conflicts
+        );
+        return Ok(());
+    }
+    let auth_hasher = Argon2Hasher::new(
+        env_or("ARGON2_MEMORY_KIB", 19 * 1024),
+        env_or("ARGON2_ITERATIONS", 2),
+        env_or("ARGON2_PARALLELISM", 1),
+    )
+    .expect("invalid argon2 parameters");
     let jwt_token_manager: JWTTokenManager<Hmac<sha2::Sha256>> =
         JWTTokenManager::new(
             Hmac::new_from_slice(config.auth_token_secret.as_bytes())
                 .expect("invalid auth token secret"),
         );
+    let token_lifetimes = TokenLifetimes {
+        access: Duration::seconds(env_or("ACCESS_TOKEN_TTL_SECONDS", 15 * 60)),
+        refresh: Duration::days(env_or("REFRESH_TOKEN_TTL_DAYS", 30)),
+    };
+    let credential_limit = RateLimitPolicy {
+        max_attempts: 5,
+        window: Duration::minutes(15),
+        base_lockout: Duration::minutes(1),
+        max_lockout: Duration::hours(1),
+        counted: Counted::Failures,
+    };
+    let search_limit = RateLimitPolicy {
+        max_attempts: 30,
+        window: Duration::minutes(1),
+        base_lockout: Duration::minutes(1),
+        max_lockout: Duration::minutes(30),
+        counted: Counted::Requests,
+    };
+    // a sync carries hundreds of phones, so there are far fewer of them
+    let contact_sync_limit = RateLimitPolicy {
+        max_attempts: 10,
+        window: Duration::hours(1),
+        base_lockout: Duration::hours(1),
+        max_lockout: Duration::days(1),
+        counted: Counted::Requests,
+    };
+    // every code is a text message somebody pays for
+    let sms_limit = RateLimitPolicy {
+        max_attempts: 5,
+        window: Duration::hours(1),
+        base_lockout: Duration::hours(1),
+        max_lockout: Duration::days(1),
+        counted: Counted::Requests,
+    };
     let auth_service = AuthService::new(
         repository.clone(),
-        auth_hasher,
+        auth_hasher.clone(),
         jwt_token_manager.clone(),
     );
     let upload_service = UploadService::new(
@@ -67,30 +155,170 @@ async fn main() -> std::io::Result<()> {
                 .expect("Environment varialble UPLOAD_STORE_PATH not set"),
         ),
     );
+    let notifier = FCMNotifier::new(pg_pool.clone());
+    actix_web::rt::spawn(
+        OutboxWorker::new(repository.clone(), notifier.clone()).run(),
+    );
+    actix_web::rt::spawn(
+        AccountDeletionWorker::new(
+            repository.clone(),
+            notifier.clone(),
+            map.clone(),
+        )
+        .run(),
+    );
+    actix_web::rt::spawn(
+        FriendRequestExpiryWorker::new(
+            repository.clone(),
+            map.clone(),
+            Duration::days(env_or("FRIEND_REQUEST_TTL_DAYS", 30)),
+        )
+        .run(),
+    );
+    actix_web::rt::spawn(
+        DataExportWorker::new(
+            repository.clone(),
+            env_or("DATA_EXPORT_PATH", env::temp_dir().join("data_exports")),
+            Duration::days(env_or("DATA_EXPORT_TTL_DAYS", 7)),
+        )
+        .run(),
+    );
+    let trusted_proxies: TrustedProxies =
+        env_or("TRUSTED_PROXIES", TrustedProxies::default());
+    // deprecated, turn it off once clients moved to headers or tickets
+    let ws_query_token_auth =
+        QueryTokenAuth(env_or("WS_QUERY_TOKEN_AUTH", true));
+    let invite_links = InviteLinks::new(env_or(
+        "FRIEND_INVITE_LINK_PREFIX",
+        "webrtc-signal://invite/".to_owned(),
+    ));
+    actix_web::rt::spawn(
+        ContactSaltWorker::new(
+            repository.clone(),
+            Duration::hours(env_or("CONTACT_SALT_ROTATION_HOURS", 24)),
+        )
+        .run(),
+    );
     HttpServer::new(move || {
         App::new()
             .app_data(Data::new(auth_service.clone()))
             .app_data(Data::new(map.clone()))
             .app_data(Data::new(repository.clone()))
             .app_data(Data::new(upload_service.clone()))
-            .app_data(Data::new(FCMNotifier::new(pg_pool.clone())))
-            .wrap(Logger::default())
-            .route(
-                "/login",
-                post().to(handlers::login::<
-                    PostgresRepository,
-                    ShaHasher,
-                    JWTTokenManager<Hmac<sha2::Sha256>>,
-                >),
+            .app_data(Data::new(notifier.clone()))
+            .app_data(Data::new(LogSmsSender))
+            .app_data(Data::new(phones))
+            .app_data(Data::new(auth_hasher.clone()))
+            .app_data(Data::new(jwt_token_manager.clone()))
+            .app_data(Data::new(token_lifetimes))
+            .app_data(Data::new(invite_links.clone()))
+            .app_data(Data::new(ws_query_token_auth))
+            .app_data(Data::new(trusted_proxies.clone()))
+            .wrap(
+                // the default format logs the query string, which may carry
+                // tickets and tokens
+                Logger::new(
+                    "%a \"%{request}xi\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T",
+                )
+                .custom_request_replace("request", utils::redacted_request_line),
+            )
+            .service(
+                resource("/login")
+                    .wrap(RateLimiter::new(
+                        "login",
+                        repository.clone(),
+                        credential_limit,
+                        vec![RateLimitKey::Ip, RateLimitKey::Phone("phone")],
+                    ))
+                    .route(post().to(handlers::login::<
+                        PostgresRepository,
+                        Argon2Hasher,
+                        JWTTokenManager<Hmac<sha2::Sha256>>,
+                        PostgresRepository,
+                        PostgresRepository,
+                        PostgresRepository,
+                    >)),
+            )
+            .service(
+                resource("/login/two_factor")
+                    .wrap(RateLimiter::new(
+                        "login_two_factor",
+                        repository.clone(),
+                        credential_limit,
+                        vec![RateLimitKey::Ip, RateLimitKey::Body("challenge")],
+                    ))
+                    .route(post().to(handlers::login_two_factor::<
+                        PostgresRepository,
+                        JWTTokenManager<Hmac<sha2::Sha256>>,
+                        PostgresRepository,
+                        PostgresRepository,
+                    >)),
             )
             .route(
-                "/signup",
-                post().to(handlers::signup::<
-                    PostgresRepository,
-                    ShaHasher,
+                "/token/refresh",
+                post().to(handlers::refresh_token::<
                     JWTTokenManager<Hmac<sha2::Sha256>>,
+                    PostgresRepository,
                 >),
             )
+            .service(
+                resource("/signup")
+                    .wrap(RateLimiter::new(
+                        "signup",
+                        repository.clone(),
+                        credential_limit,
+                        vec![RateLimitKey::Ip, RateLimitKey::Phone("phone")],
+                    ))
+                    .route(post().to(handlers::signup::<
+                        PostgresRepository,
+                        Argon2Hasher,
+                        JWTTokenManager<Hmac<sha2::Sha256>>,
+                        PostgresRepository,
+                    >)),
+            )
+            .service(
+                resource("/password_reset/code")
+                    .wrap(RateLimiter::new(
+                        "password_reset_code",
+                        repository.clone(),
+                        sms_limit,
+                        vec![RateLimitKey::Ip, RateLimitKey::Phone("phone")],
+                    ))
+                    .route(post().to(handlers::request_password_reset_code::<
+                        PostgresRepository,
+                        PostgresRepository,
+                        LogSmsSender,
+                    >)),
+            )
+            .service(
+                resource("/password_reset")
+                    .wrap(RateLimiter::new(
+                        "password_reset",
+                        repository.clone(),
+                        credential_limit,
+                        vec![RateLimitKey::Ip, RateLimitKey::Phone("phone")],
+                    ))
+                    .route(post().to(handlers::reset_password::<
+                        PostgresRepository,
+                        Argon2Hasher,
+                        JWTTokenManager<Hmac<sha2::Sha256>>,
+                        PostgresRepository,
+                        PostgresRepository,
+                    >)),
+            )
+            .service(
+                resource("/signup/code")
+                    .wrap(RateLimiter::new(
+                        "signup_code",
+                        repository.clone(),
+                        sms_limit,
+                        vec![RateLimitKey::Ip, RateLimitKey::Phone("phone")],
+                    ))
+                    .route(post().to(handlers::request_signup_code::<
+                        PostgresRepository,
+                        LogSmsSender,
+                    >)),
+            )
             .service(
                 scope("/logout")
                     .wrap(AuthTokenMiddleware::new(
@@ -100,21 +328,17 @@ async fn main() -> std::io::Result<()> {
                     ))
                     .route(
                         "",
-                        delete().to(handlers::logout::<
-                            PostgresRepository,
-                            PostgresRepository,
-                            ShaHasher,
-                            JWTTokenManager<Hmac<sha2::Sha256>>,
-                        >),
+                        delete().to(handlers::logout::<PostgresRepository>),
                     ),
             )
             .service(scope("/ws").route(
                 "",
                 get().to(ws::actor::index::<
                     PostgresRepository,
-                    ShaHasher,
+                    Argon2Hasher,
                     JWTTokenManager<Hmac<sha2::Sha256>>,
                     PostgresRepository,
+                    PostgresRepository,
                     FCMNotifier,
                     AddrMap,
                 >),
@@ -126,13 +350,28 @@ async fn main() -> std::io::Result<()> {
                         "X-User-ID",
                         auth_service.clone(),
                     ))
+                    .route(
+                        "/ws_tickets",
+                        post().to(handlers::create_ws_ticket::<
+                            PostgresRepository,
+                        >),
+                    )
                     .service(
                         scope("/users")
-                            .route(
-                                "",
-                                get().to(handlers::search_user::<
-                                    PostgresRepository,
-                                >),
+                            .service(
+                                resource("")
+                                    .wrap(RateLimiter::new(
+                                        "search_user",
+                                        repository.clone(),
+                                        search_limit,
+                                        vec![
+                                            RateLimitKey::Ip,
+                                            RateLimitKey::Header("X-User-ID"),
+                                        ],
+                                    ))
+                                    .route(get().to(handlers::search_user::<
+                                        PostgresRepository,
+                                    >)),
                             )
                             .route(
                                 "/{uid}/avatar",
@@ -142,6 +381,32 @@ async fn main() -> std::io::Result<()> {
                                 >),
                             ),
                     )
+                    .service(
+                        scope("/contacts")
+                            .route(
+                                "/salt",
+                                get().to(handlers::contact_salt::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .service(
+                                resource("/sync")
+                                    .wrap(RateLimiter::new(
+                                        "sync_contacts",
+                                        repository.clone(),
+                                        contact_sync_limit,
+                                        vec![
+                                            RateLimitKey::Ip,
+                                            RateLimitKey::Header("X-User-ID"),
+                                        ],
+                                    ))
+                                    .route(post().to(
+                                        handlers::sync_contacts::<
+                                            PostgresRepository,
+                                        >,
+                                    )),
+                            ),
+                    )
                     .service(
                         scope("friends")
                             .route(
@@ -150,13 +415,98 @@ async fn main() -> std::io::Result<()> {
                                     PostgresRepository,
                                 >),
                             )
+                            .route(
+                                "/metadata",
+                                get().to(handlers::my_friend_metadata::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/labels",
+                                get().to(handlers::my_friend_labels::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/suggestions",
+                                get().to(handlers::friend_suggestions::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/{friend_id}/metadata",
+                                get().to(handlers::friend_metadata::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/{friend_id}/metadata",
+                                put().to(handlers::update_friend_metadata::<
+                                    PostgresRepository,
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/{friend_id}/metadata",
+                                delete().to(
+                                    handlers::delete_friend_metadata::<
+                                        PostgresRepository,
+                                    >,
+                                ),
+                            )
+                            .service(
+                                scope("invites")
+                                    .route(
+                                        "",
+                                        post().to(
+                                            handlers::create_friend_invite::<
+                                                PostgresRepository,
+                                            >,
+                                        ),
+                                    )
+                                    .route(
+                                        "",
+                                        get().to(handlers::my_friend_invites::<
+                                            PostgresRepository,
+                                        >),
+                                    )
+                                    .route(
+                                        "/redeem",
+                                        post().to(
+                                            handlers::redeem_friend_invite::<
+                                                PostgresRepository,
+                                                PostgresRepository,
+                                                PostgresRepository,
+                                                PostgresRepository,
+                                                AddrMap,
+                                            >,
+                                        ),
+                                    )
+                                    .route(
+                                        "/{id}",
+                                        delete().to(
+                                            handlers::revoke_friend_invite::<
+                                                PostgresRepository,
+                                            >,
+                                        ),
+                                    )
+                                    .route(
+                                        "/{id}/qr",
+                                        get().to(
+                                            handlers::friend_invite_qr_code::<
+                                                PostgresRepository,
+                                            >,
+                                        ),
+                                    ),
+                            )
                             .service(
                                 scope("requests")
                                     .route(
                                         "",
                                         post().to(handlers::add_friend::<
                                             PostgresRepository,
-                                            FCMNotifier,
+                                            PostgresRepository,
+                                            PostgresRepository,
                                             AddrMap,
                                         >),
                                     )
@@ -166,11 +516,27 @@ async fn main() -> std::io::Result<()> {
                                             PostgresRepository,
                                         >),
                                     )
+                                    .route(
+                                        "/outgoing",
+                                        get().to(
+                                            handlers::my_outgoing_requests::<
+                                                PostgresRepository,
+                                            >,
+                                        ),
+                                    )
+                                    .route(
+                                        "/{id}",
+                                        delete().to(handlers::cancel_request::<
+                                            PostgresRepository,
+                                            AddrMap,
+                                        >),
+                                    )
                                     .route(
                                         "/{id}/accept",
                                         put().to(handlers::accept_request::<
                                             PostgresRepository,
-                                            FCMNotifier,
+                                            PostgresRepository,
+                                            PostgresRepository,
                                             AddrMap,
                                         >),
                                     )
@@ -188,6 +554,13 @@ async fn main() -> std::io::Result<()> {
                                             >,
                                         ),
                                     ),
+                            )
+                            .route(
+                                "/{friend_id}",
+                                delete().to(handlers::unfriend::<
+                                    PostgresRepository,
+                                    AddrMap,
+                                >),
                             ),
                     )
                     .service(
@@ -202,7 +575,9 @@ async fn main() -> std::io::Result<()> {
                                 "",
                                 post().to(handlers::send_chat_message::<
                                     PostgresRepository,
-                                    FCMNotifier,
+                                    PostgresRepository,
+                                    PostgresRepository,
+                                    PostgresRepository,
                                     AddrMap,
                                 >),
                             )
@@ -232,6 +607,13 @@ async fn main() -> std::io::Result<()> {
                     )
                     .service(
                         scope("/me")
+                            .route(
+                                "",
+                                patch().to(handlers::update_profile::<
+                                    PostgresRepository,
+                                    AddrMap,
+                                >),
+                            )
                             .route(
                                 "/avatar",
                                 get().to(handlers::my_avatar::<
@@ -248,6 +630,165 @@ async fn main() -> std::io::Result<()> {
                                     >,
                                 ),
                             )
+                            .route(
+                                "/notification_settings",
+                                get().to(handlers::my_notification_settings::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/notification_settings",
+                                put().to(
+                                    handlers::update_notification_settings::<
+                                        PostgresRepository,
+                                    >,
+                                ),
+                            )
+                            .route(
+                                "/notification_settings/peers",
+                                get().to(
+                                    handlers::my_peer_notification_settings::<
+                                        PostgresRepository,
+                                    >,
+                                ),
+                            )
+                            .route(
+                                "/notification_settings/peers/{peer_id}",
+                                put().to(
+                                    handlers::update_peer_notification_settings::<
+                                        PostgresRepository,
+                                    >,
+                                ),
+                            )
+                            .route(
+                                "/dead_notifications",
+                                get().to(handlers::dead_letters::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/deletion",
+                                get().to(handlers::my_account_deletion::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/deletion",
+                                post().to(handlers::delete_account::<
+                                    PostgresRepository,
+                                    PostgresRepository,
+                                    Argon2Hasher,
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/deletion",
+                                delete().to(handlers::cancel_account_deletion::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/privacy",
+                                get().to(handlers::my_privacy_settings::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/privacy",
+                                put().to(handlers::update_privacy_settings::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/blocks",
+                                get().to(handlers::my_blocked_users::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/blocks/{user_id}",
+                                put().to(handlers::block_user::<
+                                    PostgresRepository,
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/blocks/{user_id}",
+                                delete().to(handlers::unblock_user::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/exports",
+                                post().to(handlers::request_data_export::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/exports/{id}",
+                                get().to(handlers::my_data_export::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/exports/{id}/archive",
+                                get().to(handlers::download_data_export::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/two_factor",
+                                get().to(handlers::my_two_factor::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/two_factor",
+                                post().to(handlers::enroll_two_factor::<
+                                    PostgresRepository,
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/two_factor/confirm",
+                                post().to(handlers::confirm_two_factor::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/two_factor/disable",
+                                post().to(handlers::disable_two_factor::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/two_factor/recovery_codes",
+                                post().to(
+                                    handlers::regenerate_recovery_codes::<
+                                        PostgresRepository,
+                                    >,
+                                ),
+                            )
+                            .route(
+                                "/login_sessions",
+                                get().to(handlers::my_login_sessions::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/login_sessions",
+                                delete().to(
+                                    handlers::revoke_other_login_sessions::<
+                                        PostgresRepository,
+                                    >,
+                                ),
+                            )
+                            .route(
+                                "/login_sessions/{id}",
+                                delete().to(handlers::revoke_login_session::<
+                                    PostgresRepository,
+                                >),
+                            )
                             .route(
                                 "/sessions",
                                 get().to(handlers::my_sessions::<
@@ -267,11 +808,37 @@ async fn main() -> std::io::Result<()> {
                         "",
                         get().to(handlers::my_friends::<PostgresRepository>),
                     ))
+                    .service(
+                        scope("/message_requests")
+                            .route(
+                                "",
+                                get().to(handlers::my_message_requests::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/{peer_id}/accept",
+                                put().to(handlers::accept_message_request::<
+                                    PostgresRepository,
+                                >),
+                            )
+                            .route(
+                                "/{peer_id}",
+                                delete().to(
+                                    handlers::decline_message_request::<
+                                        PostgresRepository,
+                                    >,
+                                ),
+                            ),
+                    )
                     .service(scope("/rtc_messages").route(
                         "",
                         post().to(handlers::send_rtc_message::<
                             PostgresRepository,
-                            FCMNotifier,
+                            PostgresRepository,
+                            PostgresRepository,
+                            PostgresRepository,
+                            PostgresRepository,
                             AddrMap,
                         >),
                     ))
@@ -1,2 +1,3 @@
 pub mod cors;
+pub(crate) mod ratelimit;
 pub mod utf8;
@@ -0,0 +1,518 @@
+use std::{
+    future::{ready, Ready},
+    net::{AddrParseError, IpAddr},
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{
+        forward_ready, Payload, Service, ServiceRequest, ServiceResponse,
+        Transform,
+    },
+    error::ErrorInternalServerError,
+    http::{header::RETRY_AFTER, StatusCode},
+    web::{Bytes, Data},
+    HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use futures_util::{future::LocalBoxFuture, stream};
+use log::{error, warn};
+use serde_json::Value;
+
+use crate::{
+    core::ratelimit::{Counted, RateLimitPolicy, RateLimitStore},
+    phone::PhoneNormalizer,
+};
+
+/// What requests are told apart by. Each source makes a separate counter,
+/// and a request is turned away when any of its counters is locked out.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RateLimitKey {
+    /// The client address, see `TrustedProxies`.
+    Ip,
+    Header(&'static str),
+    /// A string in the given field of the JSON body.
+    Body(&'static str),
+    /// A phone number in the given field of the JSON body.
+    Phone(&'static str),
+}
+
+impl RateLimitKey {
+    fn label(&self) -> &'static str {
+        match self {
+            RateLimitKey::Ip => "ip",
+            RateLimitKey::Header(name)
+            | RateLimitKey::Body(name)
+            | RateLimitKey::Phone(name) => name,
+        }
+    }
+}
+
+/// The proxies in front of the server. Their `X-Forwarded-For` is believed,
+/// anyone else's is ignored since clients can put anything there. Without
+/// any, clients are told apart by the address they connect from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TrustedProxies(Vec<IpAddr>);
+
+impl FromStr for TrustedProxies {
+    type Err = AddrParseError;
+
+    /// Parses a comma separated list of addresses.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl TrustedProxies {
+    /// The client address: the peer, or while the peer is a trusted proxy,
+    /// the address it forwarded for, walking `X-Forwarded-For` from the
+    /// right.
+    fn client_ip(
+        &self,
+        peer: Option<IpAddr>,
+        forwarded_for: Option<&str>,
+    ) -> Option<IpAddr> {
+        let mut ip = peer?;
+        for hop in forwarded_for.into_iter().flat_map(|f| f.rsplit(',')) {
+            if !self.0.contains(&ip) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => ip = hop,
+                Err(_) => break,
+            }
+        }
+        Some(ip)
+    }
+}
+
+/// The client address of `req`, through the `TrustedProxies` in the app
+/// data if there are any.
+pub(crate) fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|a| a.ip());
+    let forwarded_for = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok());
+    match req.app_data::<Data<TrustedProxies>>() {
+        Some(proxies) => proxies.client_ip(peer, forwarded_for),
+        None => peer,
+    }
+}
+
+/// Locks clients out after too many attempts, for twice as long each time.
+/// While locked out, requests get a bare 429 that says nothing about the
+/// account they were for.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter<S> {
+    /// Keeps the counters of different endpoints apart.
+    name: &'static str,
+    store: S,
+    policy: RateLimitPolicy,
+    keys: Arc<Vec<RateLimitKey>>,
+}
+
+impl<S> RateLimiter<S> {
+    pub(crate) fn new(
+        name: &'static str,
+        store: S,
+        policy: RateLimitPolicy,
+        keys: Vec<RateLimitKey>,
+    ) -> Self {
+        Self {
+            name,
+            store,
+            policy,
+            keys: Arc::new(keys),
+        }
+    }
+
+    /// The counters the request goes against, with the key each is for.
+    async fn keys(
+        &self,
+        req: &mut ServiceRequest,
+    ) -> Result<Vec<(RateLimitKey, String)>, actix_web::Error> {
+        let mut keys = Vec::with_capacity(self.keys.len());
+        let mut body: Option<Value> = None;
+        for key in self.keys.iter() {
+            let value = match *key {
+                RateLimitKey::Ip => {
+                    client_ip(req.request()).map(|ip| ip.to_string())
+                }
+                RateLimitKey::Header(name) => req
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_owned),
+                RateLimitKey::Body(field) => json_body(req, &mut body)
+                    .await?
+                    .get(field)
+                    .and_then(Value::as_str)
+                    .map(str::to_owned),
+                RateLimitKey::Phone(field) => json_body(req, &mut body)
+                    .await?
+                    .get(field)
+                    .and_then(Value::as_str)
+                    .map(|phone| {
+                        req.app_data::<Data<PhoneNormalizer>>()
+                            .and_then(|p| p.normalize(phone).ok())
+                            .unwrap_or_else(|| phone.to_owned())
+                    }),
+            };
+            if let Some(value) = value {
+                keys.push((
+                    *key,
+                    format!("{}:{}:{}", self.name, key.label(), value),
+                ));
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Reads the body as JSON the first time and puts it back for the handler.
+async fn json_body<'a>(
+    req: &mut ServiceRequest,
+    body: &'a mut Option<Value>,
+) -> Result<&'a Value, actix_web::Error> {
+    if body.is_none() {
+        let bytes = req.extract::<Bytes>().await?;
+        *body = Some(serde_json::from_slice(&bytes).unwrap_or(Value::Null));
+        req.set_payload(Payload::Stream {
+            payload: Box::pin(stream::once(ready(Ok(bytes)))),
+        });
+    }
+    Ok(body.get_or_insert(Value::Null))
+}
+
+impl<S, St, B> Transform<St, ServiceRequest> for RateLimiter<S>
+where
+    S: RateLimitStore + Clone + 'static,
+    St: Service<
+            ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+        > + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S, St>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: St) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            limiter: self.clone(),
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub(crate) struct RateLimitMiddleware<S, St> {
+    limiter: RateLimiter<S>,
+    service: Rc<St>,
+}
+
+impl<S, St, B> Service<ServiceRequest> for RateLimitMiddleware<S, St>
+where
+    S: RateLimitStore + Clone + 'static,
+    St: Service<
+            ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+        > + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let limiter = self.limiter.clone();
+        let service = self.service.clone();
+        Box::pin(async move {
+            let sources = limiter.keys(&mut req).await?;
+            let keys: Vec<String> =
+                sources.iter().map(|(_, key)| key.clone()).collect();
+            if let Some(until) = limiter
+                .store
+                .locked_until(&keys)
+                .await
+                .map_err(ErrorInternalServerError)?
+            {
+                let retry_after = (until - Utc::now()).num_seconds().max(1);
+                return Ok(req
+                    .into_response(
+                        HttpResponse::TooManyRequests()
+                            .insert_header((RETRY_AFTER, retry_after))
+                            .body("too many attempts, try again later"),
+                    )
+                    .map_into_right_body());
+            }
+            let resp = service.call(req).await?;
+            let status = resp.status();
+            let counts = match limiter.policy.counted {
+                Counted::Requests => true,
+                Counted::Failures => {
+                    status.is_client_error()
+                        && status != StatusCode::TOO_MANY_REQUESTS
+                }
+            };
+            if counts {
+                for key in &keys {
+                    match limiter
+                        .store
+                        .record_attempt(key, &limiter.policy)
+                        .await
+                    {
+                        Ok(Some(until)) => {
+                            warn!("{} locked out until {}", key, until)
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("failed to record attempt: {}", e),
+                    }
+                }
+            } else if limiter.policy.counted == Counted::Failures
+                && status.is_success()
+            {
+                // the failures against the account the client proved itself
+                // for are forgiven, its address keeps counting though, or
+                // logging into an own account now and then would let it
+                // keep guessing the passwords of others
+                let identities: Vec<String> = sources
+                    .into_iter()
+                    .filter(|(source, _)| !matches!(source, RateLimitKey::Ip))
+                    .map(|(_, key)| key)
+                    .collect();
+                if let Err(e) = limiter.store.reset(&identities).await {
+                    error!("failed to reset rate limits: {}", e);
+                }
+            }
+            Ok(resp.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handlers::tests::test_pool, stores::postgres::PostgresRepository,
+    };
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web::{post, Json},
+        App,
+    };
+    use chrono::Duration;
+    use serde_json::json;
+    use sqlx::query;
+    use std::net::SocketAddr;
+    use uuid::Uuid;
+
+    fn policy() -> RateLimitPolicy {
+        RateLimitPolicy {
+            max_attempts: 2,
+            window: Duration::minutes(15),
+            base_lockout: Duration::minutes(1),
+            max_lockout: Duration::hours(1),
+            counted: Counted::Failures,
+        }
+    }
+
+    /// Fails unless the body says `ok` and echoes the body it got.
+    async fn echo(Json(body): Json<Value>) -> HttpResponse {
+        if body["ok"] == true {
+            HttpResponse::Ok().json(body)
+        } else {
+            HttpResponse::Unauthorized().json(body)
+        }
+    }
+
+    #[test]
+    fn test_client_ip_only_trusts_proxies() {
+        let proxies: TrustedProxies = "10.0.0.1, 10.0.0.2".parse().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().ok();
+        // forwarding headers of clients are ignored
+        assert_eq!(
+            proxies.client_ip(ip("203.0.113.7"), Some("198.51.100.1")),
+            ip("203.0.113.7")
+        );
+        // the proxies' are followed, but not past what they forwarded for
+        assert_eq!(
+            proxies.client_ip(
+                ip("10.0.0.1"),
+                Some("198.51.100.1, 203.0.113.7, 10.0.0.2")
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            TrustedProxies::default()
+                .client_ip(ip("10.0.0.1"), Some("198.51.100.1")),
+            ip("10.0.0.1")
+        );
+        assert!("10.0.0.1, nope".parse::<TrustedProxies>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_lockout_doubles_and_is_generic() {
+        let Some(pool) = test_pool().await else {
+            return;
+        };
+        let app = init_service(
+            App::new()
+                .wrap(RateLimiter::new(
+                    "test",
+                    PostgresRepository::new(pool.clone()),
+                    policy(),
+                    vec![RateLimitKey::Header("X-Test-Key")],
+                ))
+                .route("/", post().to(echo)),
+        )
+        .await;
+        let key = Uuid::new_v4().to_string();
+        let attempt = |ok: bool| {
+            TestRequest::post()
+                .uri("/")
+                .insert_header(("X-Test-Key", key.as_str()))
+                .set_json(json!({ "ok": ok }))
+                .to_request()
+        };
+
+        for lockout in [Duration::minutes(1), Duration::minutes(2)] {
+            for _ in 0..policy().max_attempts {
+                let resp = call_service(&app, attempt(false)).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            }
+            // locked out, even with the right credentials
+            let resp = call_service(&app, attempt(true)).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+            let retry_after: i64 = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(retry_after <= lockout.num_seconds());
+            assert!(retry_after > lockout.num_seconds() - 5);
+            assert_eq!(
+                read_body(resp).await,
+                "too many attempts, try again later"
+            );
+            query!(
+                "UPDATE rate_limits SET locked_until = now() WHERE key = $1",
+                format!("test:X-Test-Key:{}", key),
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        // a success forgives the failures before it
+        let resp = call_service(&app, attempt(true)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, attempt(false)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = call_service(&app, attempt(true)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_success_only_forgives_the_identity() {
+        let Some(pool) = test_pool().await else {
+            return;
+        };
+        let app = init_service(
+            App::new()
+                .wrap(RateLimiter::new(
+                    "test",
+                    PostgresRepository::new(pool),
+                    policy(),
+                    vec![RateLimitKey::Ip, RateLimitKey::Header("X-Test-Key")],
+                ))
+                .route("/", post().to(echo)),
+        )
+        .await;
+        // a random address, so that other runs don't count against it
+        let [a, b, c, ..] = Uuid::new_v4().into_bytes();
+        let peer = SocketAddr::from(([10, a, b, c], 4000));
+        let attempt = |ok: bool| {
+            TestRequest::post()
+                .uri("/")
+                .peer_addr(peer)
+                .insert_header(("X-Test-Key", Uuid::new_v4().to_string()))
+                .set_json(json!({ "ok": ok }))
+                .to_request()
+        };
+
+        let resp = call_service(&app, attempt(false)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        // logging into an own account in between doesn't clear the address
+        let resp = call_service(&app, attempt(true)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, attempt(false)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = call_service(&app, attempt(true)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn test_phone_key_puts_body_back() {
+        let Some(pool) = test_pool().await else {
+            return;
+        };
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(PhoneNormalizer::new("cn").unwrap()))
+                .wrap(RateLimiter::new(
+                    "test",
+                    PostgresRepository::new(pool),
+                    policy(),
+                    vec![RateLimitKey::Phone("phone")],
+                ))
+                .route("/", post().to(echo)),
+        )
+        .await;
+        // a random number, so that other runs don't count against it
+        let phone = format!("138{:08}", Uuid::new_v4().as_u128() % 100_000_000);
+        let body = json!({ "phone": phone, "password": "secret", "ok": false });
+
+        for _ in 0..policy().max_attempts {
+            let resp = call_service(
+                &app,
+                TestRequest::post().uri("/").set_json(&body).to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            let echoed: Value =
+                serde_json::from_slice(&read_body(resp).await).unwrap();
+            assert_eq!(echoed, body);
+        }
+        // the same number written differently shares the counter
+        let resp = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/")
+                .set_json(
+                    json!({ "phone": format!("+86 {}", phone), "ok": true }),
+                )
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
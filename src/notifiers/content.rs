@@ -0,0 +1,203 @@
+use crate::core::{notifier::PushNotification, preference::NotificationKind};
+
+const DEFAULT_LOCALE: &str = "en";
+const MAX_PREVIEW_CHARS: usize = 80;
+
+/// Something a user gets notified about.
+#[derive(Debug, Clone)]
+pub(crate) enum NotificationEvent<'a> {
+    Chat {
+        sender_id: &'a str,
+        sender: &'a str,
+        mime_type: &'a str,
+        content: &'a str,
+    },
+    Call {
+        sender_id: &'a str,
+        sender: &'a str,
+    },
+    FriendRequest {
+        sender: &'a str,
+    },
+    FriendAccept {
+        sender: &'a str,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Template {
+    Chat,
+    ChatHidden,
+    Call,
+    FriendRequest,
+    FriendAccept,
+    Photo,
+    Video,
+    Voice,
+    File,
+}
+
+// (template, locale, title, body), `{sender}` and `{preview}` are replaced
+// when rendering.
+const TEMPLATES: &[(Template, &str, &str, &str)] = &[
+    (Template::Chat, "en", "{sender}", "{preview}"),
+    (Template::Chat, "zh", "{sender}", "{preview}"),
+    (Template::ChatHidden, "en", "{sender}", "New message"),
+    (Template::ChatHidden, "zh", "{sender}", "你收到一条新消息"),
+    (Template::Call, "en", "{sender}", "Incoming call"),
+    (Template::Call, "zh", "{sender}", "邀请你进行通话"),
+    (
+        Template::FriendRequest,
+        "en",
+        "Friend request",
+        "{sender} wants to add you as a friend",
+    ),
+    (
+        Template::FriendRequest,
+        "zh",
+        "好友请求",
+        "{sender} 请求添加你为好友",
+    ),
+    (
+        Template::FriendAccept,
+        "en",
+        "Friend request accepted",
+        "{sender} accepted your friend request",
+    ),
+    (
+        Template::FriendAccept,
+        "zh",
+        "好友请求已通过",
+        "{sender} 通过了你的好友请求",
+    ),
+    (Template::Photo, "en", "", "📷 Photo"),
+    (Template::Photo, "zh", "", "📷 图片"),
+    (Template::Video, "en", "", "🎥 Video"),
+    (Template::Video, "zh", "", "🎥 视频"),
+    (Template::Voice, "en", "", "🎤 Voice message"),
+    (Template::Voice, "zh", "", "🎤 语音消息"),
+    (Template::File, "en", "", "📎 File"),
+    (Template::File, "zh", "", "📎 文件"),
+];
+
+/// Looks the template up by the most specific matching locale, "zh-CN"
+/// falls back to "zh" and then to English.
+fn lookup(template: Template, locale: &str) -> (&'static str, &'static str) {
+    let language = locale.split(['-', '_']).next().unwrap_or_default();
+    [locale, language, DEFAULT_LOCALE]
+        .iter()
+        .find_map(|l| {
+            TEMPLATES.iter().find(|(t, tl, _, _)| {
+                *t == template && tl.eq_ignore_ascii_case(l)
+            })
+        })
+        .map(|(_, _, title, body)| (*title, *body))
+        .expect("every template has an English version")
+}
+
+fn preview(mime_type: &str, content: &str, locale: &str) -> String {
+    let template = match mime_type.split('/').next().unwrap_or_default() {
+        "text" => return truncate(content, MAX_PREVIEW_CHARS),
+        "image" => Template::Photo,
+        "video" => Template::Video,
+        "audio" => Template::Voice,
+        _ => Template::File,
+    };
+    lookup(template, locale).1.to_owned()
+}
+
+fn truncate(s: &str, max_chars: usize) -> String {
+    match s.char_indices().nth(max_chars) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s.to_owned(),
+    }
+}
+
+impl NotificationEvent<'_> {
+    pub(crate) fn kind(&self) -> NotificationKind {
+        match self {
+            Self::Chat { .. } => NotificationKind::Chat,
+            Self::Call { .. } => NotificationKind::Call,
+            Self::FriendRequest { .. } | Self::FriendAccept { .. } => {
+                NotificationKind::FriendRequest
+            }
+        }
+    }
+
+    /// Renders the event in `locale`, message contents are left out if
+    /// `hide_previews` is set.
+    pub(crate) fn render(
+        &self,
+        locale: &str,
+        hide_previews: bool,
+    ) -> PushNotification {
+        let (template, sender, preview, thread_id) = match self {
+            Self::Chat {
+                sender_id,
+                sender,
+                mime_type,
+                content,
+            } => {
+                let template = if hide_previews {
+                    Template::ChatHidden
+                } else {
+                    Template::Chat
+                };
+                (
+                    template,
+                    sender,
+                    preview(mime_type, content, locale),
+                    Some(format!("chat:{}", sender_id)),
+                )
+            }
+            Self::Call { sender_id, sender } => (
+                Template::Call,
+                sender,
+                String::new(),
+                Some(format!("call:{}", sender_id)),
+            ),
+            Self::FriendRequest { sender } => {
+                (Template::FriendRequest, sender, String::new(), None)
+            }
+            Self::FriendAccept { sender } => {
+                (Template::FriendAccept, sender, String::new(), None)
+            }
+        };
+        let (title, body) = lookup(template, locale);
+        let fill = |s: &str| {
+            s.replace("{sender}", sender).replace("{preview}", &preview)
+        };
+        PushNotification {
+            title: fill(title),
+            body: fill(body),
+            collapse_key: thread_id.clone(),
+            thread_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_chat_preview() {
+        let event = NotificationEvent::Chat {
+            sender_id: "1",
+            sender: "Alice",
+            mime_type: "image/png",
+            content: "uploads/1",
+        };
+        let rendered = event.render("zh-CN", false);
+        assert_eq!(rendered.title, "Alice");
+        assert_eq!(rendered.body, "📷 图片");
+        assert_eq!(rendered.thread_id.as_deref(), Some("chat:1"));
+        assert_eq!(event.render("fr", true).body, "New message");
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("你好世界", 2), "你好…");
+        assert_eq!(truncate("hello", 5), "hello");
+    }
+}
@@ -10,7 +10,7 @@ use sha2::Sha256;
 use sqlx::{query, query_scalar, PgPool};
 use tokio::sync::RwLock;
 
-use crate::core::notifier::Notifier;
+use crate::core::notifier::{Notifier, PushNotification, INVALID_TOKEN_STATUS};
 use std::sync::Arc;
 
 #[derive(Debug, Clone)]
@@ -36,7 +36,6 @@ pub(crate) struct Notification {
     body: String,
 }
 
-
 #[derive(Debug, Serialize)]
 pub(crate) struct Message<T>
 where
@@ -45,6 +44,31 @@ where
     token: String,
     notification: Notification,
     data: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    android: Option<AndroidConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apns: Option<APNSConfig>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AndroidConfig {
+    collapse_key: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct APNSConfig {
+    payload: APNSPayload,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct APNSPayload {
+    aps: APS,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct APS {
+    #[serde(rename = "thread-id")]
+    thread_id: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -55,6 +79,36 @@ where
     message: Message<T>,
 }
 
+#[derive(Debug, Deserialize)]
+struct ErrorResp {
+    error: ErrorStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorStatus {
+    message: String,
+    status: String,
+    #[serde(default)]
+    details: Vec<ErrorDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    #[serde(rename = "errorCode")]
+    error_code: Option<String>,
+}
+
+impl ErrorStatus {
+    // FCM puts its own error code into the details, the top level status is
+    // the generic google rpc one.
+    fn error_code(&self) -> &str {
+        self.details
+            .iter()
+            .find_map(|d| d.error_code.as_deref())
+            .unwrap_or(&self.status)
+    }
+}
+
 impl Notifier for FCMNotifier {
     async fn get_token(&self, uid: &str) -> Result<Option<String>> {
         query_scalar!("SELECT fcm_token FROM users WHERE id = $1", uid)
@@ -74,17 +128,27 @@ impl Notifier for FCMNotifier {
             })?;
         Ok(())
     }
+
+    async fn remove_token(&self, token: &str) -> Result<()> {
+        query!(
+            "UPDATE users SET fcm_token = NULL WHERE fcm_token = $1",
+            token
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to remove token".into(), 500, e))?;
+        Ok(())
+    }
+
     async fn send_notification<T>(
         &self,
         to: &str,
-        title: &str,
-        body: &str,
+        notification: &PushNotification,
         data: T,
     ) -> Result<()>
     where
         T: Serialize,
     {
-
         let mut access_token = self.access_token.lock().await;
         if access_token.is_none() {
             *access_token = Some(mint_access_token().await?);
@@ -99,10 +163,20 @@ impl Notifier for FCMNotifier {
                     message: Message {
                         token: to.into(),
                         notification: Notification {
-                                title: title.into(),
-                                body: body.into(),
+                                title: notification.title.clone(),
+                                body: notification.body.clone(),
                         },
-                        data
+                        data,
+                        android: notification.collapse_key.clone().map(
+                            |collapse_key| AndroidConfig { collapse_key },
+                        ),
+                        apns: notification.thread_id.clone().map(
+                            |thread_id| APNSConfig {
+                                payload: APNSPayload {
+                                    aps: APS { thread_id },
+                                },
+                            },
+                        ),
                     }
                 })
                 .map_err(|e| {
@@ -118,18 +192,88 @@ impl Notifier for FCMNotifier {
                 Error::wrap("failed to build request".into(), 500, e)
             })?;
         let res = self.client.execute(req).await.map_err(|e| {
-            Error::wrap("failed to execute request".into(), 500, e)
+            Error::wrap("failed to execute request".into(), 503, e)
         })?;
-        if !res.status().is_success() {
-            let reason = res.text().await.map_err(|e| {
-                Error::wrap("failed to read response body".into(), 500, e)
-            })?;
-            return Err(Error::new(
-                format!("failed to send notification: {}", reason),
-                500,
-            ));
+        if res.status().is_success() {
+            return Ok(());
         }
-        Ok(())
+        let http_status = res.status().as_u16();
+        let reason = res.text().await.map_err(|e| {
+            Error::wrap("failed to read response body".into(), 503, e)
+        })?;
+        Err(self
+            .fail(
+                to,
+                &mut access_token,
+                SendFailure::parse(http_status, &reason),
+            )
+            .await?)
+    }
+}
+
+/// A response FCM rejected a message with.
+#[derive(Debug, PartialEq)]
+struct SendFailure {
+    /// The FCM error code, `None` if the body wasn't an FCM error.
+    code: Option<String>,
+    message: String,
+    status_code: u16,
+}
+
+impl SendFailure {
+    fn parse(http_status: u16, body: &str) -> Self {
+        let Ok(ErrorResp { error }) = from_str::<ErrorResp>(body) else {
+            return Self {
+                code: None,
+                message: body.to_owned(),
+                status_code: if http_status >= 500 { 503 } else { 500 },
+            };
+        };
+        let status_code = match error.error_code() {
+            "UNREGISTERED" | "INVALID_ARGUMENT" | "SENDER_ID_MISMATCH" => {
+                INVALID_TOKEN_STATUS
+            }
+            "QUOTA_EXCEEDED" => 429,
+            "UNAUTHENTICATED" | "UNAVAILABLE" | "INTERNAL" => 503,
+            _ => 500,
+        };
+        Self {
+            code: Some(error.error_code().to_owned()),
+            message: error.message,
+            status_code,
+        }
+    }
+}
+
+impl FCMNotifier {
+    /// Prunes the token if FCM reported it as dead and returns the error to
+    /// fail the send with.
+    async fn fail(
+        &self,
+        to: &str,
+        access_token: &mut Option<String>,
+        failure: SendFailure,
+    ) -> Result<Error> {
+        if failure.status_code == INVALID_TOKEN_STATUS {
+            self.remove_token(to).await?;
+        }
+        if failure.code.as_deref() == Some("UNAUTHENTICATED") {
+            // the cached access token has expired, mint a new one on the
+            // next attempt
+            *access_token = None;
+        }
+        Ok(Error::new(
+            match failure.code {
+                Some(code) => format!(
+                    "failed to send notification({}): {}",
+                    code, failure.message
+                ),
+                None => {
+                    format!("failed to send notification: {}", failure.message)
+                }
+            },
+            failure.status_code,
+        ))
     }
 }
 
@@ -271,8 +415,122 @@ async fn mint_access_token() -> Result<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::handlers::tests::test_pool;
+    use uuid::Uuid;
+
+    fn fcm_error(status: &str, error_code: Option<&str>) -> String {
+        let details = match error_code {
+            Some(code) => serde_json::json!([{
+                "@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                "errorCode": code,
+            }]),
+            None => serde_json::json!([]),
+        };
+        serde_json::json!({
+            "error": {
+                "code": 400,
+                "message": "Requested entity was not found.",
+                "status": status,
+                "details": details,
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_parse_send_failure() {
+        for (http_status, status, error_code, expected) in [
+            (404, "NOT_FOUND", Some("UNREGISTERED"), INVALID_TOKEN_STATUS),
+            (
+                400,
+                "INVALID_ARGUMENT",
+                Some("INVALID_ARGUMENT"),
+                INVALID_TOKEN_STATUS,
+            ),
+            (
+                403,
+                "PERMISSION_DENIED",
+                Some("SENDER_ID_MISMATCH"),
+                INVALID_TOKEN_STATUS,
+            ),
+            (429, "RESOURCE_EXHAUSTED", Some("QUOTA_EXCEEDED"), 429),
+            (503, "UNAVAILABLE", Some("UNAVAILABLE"), 503),
+            (401, "UNAUTHENTICATED", None, 503),
+            (400, "FAILED_PRECONDITION", None, 500),
+        ] {
+            let failure =
+                SendFailure::parse(http_status, &fcm_error(status, error_code));
+            assert_eq!(
+                failure.code.as_deref(),
+                Some(error_code.unwrap_or(status))
+            );
+            assert_eq!(failure.status_code, expected, "{}", status);
+        }
+    }
+
+    #[test]
+    fn test_parse_send_failure_without_fcm_error() {
+        let failure = SendFailure::parse(502, "<html>Bad Gateway</html>");
+        assert_eq!(failure.code, None);
+        assert_eq!(failure.status_code, 503);
+        assert_eq!(failure.message, "<html>Bad Gateway</html>");
+        assert_eq!(SendFailure::parse(400, "").status_code, 500);
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_token_is_pruned() {
+        let Some(pool) = test_pool().await else {
+            return;
+        };
+        let notifier = FCMNotifier::new(pool);
+        let id = Uuid::new_v4().to_string();
+        let token = format!("token-{}", id);
+        query!(
+            "INSERT INTO users (id, phone, password, password_salt, fcm_token)
+            VALUES ($1, $2, '', '', $3)",
+            id,
+            format!("+{}", &id[..8]),
+            token,
+        )
+        .execute(&notifier.pool)
+        .await
+        .unwrap();
+
+        let mut access_token = Some("access".to_owned());
+        let failure = SendFailure::parse(
+            429,
+            &fcm_error("RESOURCE_EXHAUSTED", Some("QUOTA_EXCEEDED")),
+        );
+        let e = notifier
+            .fail(&token, &mut access_token, failure)
+            .await
+            .unwrap();
+        assert_eq!(e.status_code, 429);
+        assert_eq!(notifier.get_token(&id).await.unwrap(), Some(token.clone()));
+
+        let failure = SendFailure::parse(
+            404,
+            &fcm_error("NOT_FOUND", Some("UNREGISTERED")),
+        );
+        let e = notifier
+            .fail(&token, &mut access_token, failure)
+            .await
+            .unwrap();
+        assert_eq!(e.status_code, INVALID_TOKEN_STATUS);
+        assert_eq!(notifier.get_token(&id).await.unwrap(), None);
+        assert_eq!(access_token.as_deref(), Some("access"));
+
+        let failure =
+            SendFailure::parse(401, &fcm_error("UNAUTHENTICATED", None));
+        notifier
+            .fail(&token, &mut access_token, failure)
+            .await
+            .unwrap();
+        assert_eq!(access_token, None);
+    }
 
-    #[tokio::test]
+    #[actix_web::test]
+    #[ignore = "needs service-account.json and access to Google"]
     async fn test_mint_access_token() {
         let service_account = read_service_account().unwrap();
         let jwt_token = generate_jwt_token(service_account).unwrap();
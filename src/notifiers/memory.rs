@@ -0,0 +1,105 @@
+use crate::core::{
+    error::{Error, Result},
+    notifier::{Notifier, PushNotification, INVALID_TOKEN_STATUS},
+};
+use serde::Serialize;
+use serde_json::{to_value, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SentNotification {
+    pub(crate) token: String,
+    pub(crate) notification: PushNotification,
+    pub(crate) data: Value,
+}
+
+/// Keeps push tokens in memory and records every notification instead of
+/// sending it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryNotifier {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+    sent: Arc<RwLock<Vec<SentNotification>>>,
+    failure: Arc<RwLock<Option<u16>>>,
+}
+
+impl MemoryNotifier {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notifications sent so far, oldest first.
+    pub(crate) async fn sent(&self) -> Vec<SentNotification> {
+        self.sent.read().await.clone()
+    }
+
+    /// Makes every send fail with `status_code` until it is set back to
+    /// `None`. Like a real provider, failing with `INVALID_TOKEN_STATUS`
+    /// removes the token.
+    pub(crate) async fn fail_with(&self, status_code: Option<u16>) {
+        *self.failure.write().await = status_code;
+    }
+
+    /// Notifications sent to the device `uid` registered.
+    pub(crate) async fn sent_to(&self, uid: &str) -> Vec<SentNotification> {
+        let Some(token) = self.get_token(uid).await.unwrap() else {
+            return Vec::new();
+        };
+        self.sent
+            .read()
+            .await
+            .iter()
+            .filter(|n| n.token == token)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Notifier for MemoryNotifier {
+    async fn update_token(&self, uid: &str, token: &str) -> Result<()> {
+        self.tokens
+            .write()
+            .await
+            .insert(uid.to_owned(), token.to_owned());
+        Ok(())
+    }
+
+    async fn get_token(&self, uid: &str) -> Result<Option<String>> {
+        Ok(self.tokens.read().await.get(uid).cloned())
+    }
+
+    async fn remove_token(&self, token: &str) -> Result<()> {
+        self.tokens.write().await.retain(|_, t| t != token);
+        Ok(())
+    }
+
+    async fn send_notification<T>(
+        &self,
+        to: &str,
+        notification: &PushNotification,
+        data: T,
+    ) -> Result<()>
+    where
+        T: Serialize,
+    {
+        if let Some(status_code) = *self.failure.read().await {
+            if status_code == INVALID_TOKEN_STATUS {
+                self.remove_token(to).await?;
+            }
+            return Err(Error::new(
+                "failed to send notification".into(),
+                status_code,
+            ));
+        }
+        let data = to_value(data).map_err(|e| {
+            Error::wrap("failed to serialize notification data".into(), 500, e)
+        })?;
+        self.sent.write().await.push(SentNotification {
+            token: to.to_owned(),
+            notification: notification.clone(),
+            data,
+        });
+        Ok(())
+    }
+}
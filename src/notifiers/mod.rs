@@ -1 +1,4 @@
+pub(crate) mod content;
 pub(crate) mod fcm;
+#[cfg(test)]
+pub(crate) mod memory;
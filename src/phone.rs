@@ -0,0 +1,54 @@
+use crate::core::error::{Error, Result};
+use phonenumber::{country, Mode};
+
+/// Parses phone numbers into E.164, numbers without a country code are
+/// taken to be in `default_region`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PhoneNormalizer {
+    default_region: country::Id,
+}
+
+impl PhoneNormalizer {
+    pub(crate) fn new(default_region: &str) -> Result<Self> {
+        Ok(Self {
+            default_region: default_region.to_uppercase().parse().map_err(
+                |e| {
+                    Error::wrap(
+                        format!("invalid phone region: {}", default_region),
+                        500,
+                        e,
+                    )
+                },
+            )?,
+        })
+    }
+
+    pub(crate) fn normalize(&self, phone: &str) -> Result<String> {
+        let number = phonenumber::parse(Some(self.default_region), phone)
+            .map_err(|e| {
+                Error::wrap(format!("invalid phone number: {}", phone), 422, e)
+            })?;
+        if !phonenumber::is_valid(&number) {
+            return Err(Error::new(
+                format!("invalid phone number: {}", phone),
+                422,
+            ));
+        }
+        Ok(number.format().mode(Mode::E164).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let normalizer = PhoneNormalizer::new("cn").unwrap();
+        for phone in ["13812345678", "+86 138 1234 5678", "0086-138-1234-5678"]
+        {
+            assert_eq!(normalizer.normalize(phone).unwrap(), "+8613812345678");
+        }
+        assert_eq!(normalizer.normalize("12345").unwrap_err().status_code, 422);
+    }
+}
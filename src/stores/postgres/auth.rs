@@ -1,4 +1,5 @@
 use super::PostgresRepository;
+use crate::core::session::{LoginContext, LOGIN};
 use auth_service::core::{
     entities::CreateUser, error::Error, repository::Repository,
 };
@@ -26,13 +27,26 @@ impl Repository for PostgresRepository {
         &self,
         key: &str,
     ) -> Result<Option<String>, auth_service::core::error::Error> {
-        Ok(
-            query!("SELECT id::VARCHAR FROM users WHERE session_key = $1", key)
-                .fetch_optional(&self.pool)
-                .await
-                .map_err(|e| Error::FailedToGetID(Box::new(e)))?
-                .map(|r| r.id),
+        // last_used_at only needs to be roughly right, so spare the write on
+        // most requests
+        query_scalar!(
+            r#"
+            WITH
+                session AS (
+                    SELECT id, user_id, last_used_at FROM login_sessions
+                    WHERE key = $1 AND key_expires_at > now()
+                ),
+                touched AS (
+                    UPDATE login_sessions SET last_used_at = now()
+                    WHERE id IN (SELECT id FROM session WHERE last_used_at < now() - INTERVAL '1 minute')
+                )
+            SELECT user_id FROM session
+            "#,
+            key
         )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::FailedToGetID(Box::new(e)))
     }
 
     async fn get_password_salt(
@@ -54,9 +68,20 @@ impl Repository for PostgresRepository {
         identifier: &str,
         key: &str,
     ) -> Result<(), auth_service::core::error::Error> {
+        // keys are only handed out by the login handler, which says how long
+        // they last
+        let login = LOGIN
+            .try_with(LoginContext::clone)
+            .map_err(|e| Error::FailedToSetKey(Box::new(e)))?;
         query!(
-            "UPDATE users SET session_key = $1 WHERE phone = $2",
+            "INSERT INTO login_sessions (id, user_id, key, key_expires_at, device_name, platform, ip)
+            SELECT $1, id, $2, $3, $4, $5, $6 FROM users WHERE phone = $7",
+            login.session_id,
             key,
+            login.key_expires_at,
+            login.device_name,
+            login.platform,
+            login.ip,
             identifier
         )
         .execute(&self.pool)
@@ -91,9 +116,10 @@ impl Repository for PostgresRepository {
         Ok(id)
     }
 
+    // revokes the sessions on every device
     async fn delete_key(&self, identifier: &str) -> Result<(), Error> {
         query!(
-            "UPDATE users SET session_key = NULL WHERE phone = $1",
+            "DELETE FROM login_sessions WHERE user_id = (SELECT id FROM users WHERE phone = $1)",
             identifier
         )
         .execute(&self.pool)
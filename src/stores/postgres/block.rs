@@ -0,0 +1,68 @@
+use super::PostgresRepository;
+use crate::core::block::{BlockStore, BlockedUser};
+use crate::core::error::{Error, Result};
+use sqlx::{query, query_as, query_scalar};
+
+impl BlockStore for PostgresRepository {
+    async fn block(&self, blocker: &str, blocked: &str) -> Result<()> {
+        query!(
+            r#"
+            WITH requests AS (
+                DELETE FROM friend_requests
+                WHERE (("from" = $1 AND "to" = $2) OR ("from" = $2 AND "to" = $1))
+                    AND status = 'Pending'
+            )
+            INSERT INTO user_blocks (blocker_id, blocked_id) VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            blocker,
+            blocked,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to block user".into(), 500, e))?;
+        Ok(())
+    }
+
+    async fn unblock(&self, blocker: &str, blocked: &str) -> Result<bool> {
+        Ok(query!(
+            "DELETE FROM user_blocks WHERE blocker_id = $1 AND blocked_id = $2",
+            blocker,
+            blocked,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to unblock user".into(), 500, e))?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn blocked_users(&self, blocker: &str) -> Result<Vec<BlockedUser>> {
+        query_as!(
+            BlockedUser,
+            "SELECT u.id, u.phone, u.display_name, b.created_at AS blocked_at
+            FROM user_blocks AS b JOIN users AS u ON b.blocked_id = u.id
+            WHERE b.blocker_id = $1
+            ORDER BY b.created_at DESC",
+            blocker,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get blocked users".into(), 500, e))
+    }
+
+    async fn is_blocked(&self, user_id: &str, other_id: &str) -> Result<bool> {
+        query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM user_blocks
+                WHERE (blocker_id = $1 AND blocked_id = $2)
+                    OR (blocker_id = $2 AND blocked_id = $1)
+            ) AS "blocked!""#,
+            user_id,
+            other_id,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to check blocks".into(), 500, e))
+    }
+}
@@ -0,0 +1,168 @@
+use super::PostgresRepository;
+use crate::core::contact::{ContactSalt, ContactStore, DiscoveredContact};
+use crate::core::error::{Error, Result};
+use crate::core::repository::{User, UserType};
+use chrono::{Duration, Utc};
+use sqlx::{query, query_as};
+
+fn user_type(typ: &str) -> Result<UserType> {
+    match typ {
+        "Requesting" => Ok(UserType::Requesting),
+        "Requested" => Ok(UserType::Requested),
+        "Friend" => Ok(UserType::Friend),
+        "Myself" => Ok(UserType::Myself),
+        "Stranger" => Ok(UserType::Stranger),
+        _ => Err(Error::new(format!("invalid user type: {}", typ), 500)),
+    }
+}
+
+impl ContactStore for PostgresRepository {
+    async fn current_contact_salt(&self) -> Result<Option<ContactSalt>> {
+        query_as!(
+            ContactSalt,
+            "SELECT id, salt, created_at, expires_at FROM contact_salts
+            WHERE expires_at > now()
+            ORDER BY created_at DESC LIMIT 1"
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get contact salt".into(), 500, e))
+    }
+
+    async fn contact_salt(&self, id: &str) -> Result<Option<ContactSalt>> {
+        query_as!(
+            ContactSalt,
+            "SELECT id, salt, created_at, expires_at FROM contact_salts
+            WHERE id = $1 AND expires_at > now()",
+            id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get contact salt".into(), 500, e))
+    }
+
+    async fn add_contact_salt(
+        &self,
+        salt: &str,
+        ttl: Duration,
+    ) -> Result<ContactSalt> {
+        let mut tx = self.pool.begin().await.map_err(|e| {
+            Error::wrap("failed to begin transaction".into(), 500, e)
+        })?;
+        let salt = query_as!(
+            ContactSalt,
+            "INSERT INTO contact_salts (id, salt, expires_at) VALUES ($1, $2, $3)
+            RETURNING id, salt, created_at, expires_at",
+            self.id_generator.lock().await.generate().to_string(),
+            salt,
+            Utc::now() + ttl,
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| Error::wrap("failed to add contact salt".into(), 500, e))?;
+        query!(
+            "INSERT INTO phone_hashes (salt_id, hash, user_id)
+            SELECT $1, substr(encode(sha256(convert_to($2 || phone, 'UTF8')), 'hex'), 1, 16), id
+            FROM users
+            ON CONFLICT DO NOTHING",
+            salt.id,
+            salt.salt,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| Error::wrap("failed to hash phones".into(), 500, e))?;
+        tx.commit().await.map_err(|e| {
+            Error::wrap("failed to commit transaction".into(), 500, e)
+        })?;
+        Ok(salt)
+    }
+
+    async fn expire_contact_salts(&self) -> Result<()> {
+        query!("DELETE FROM contact_salts WHERE expires_at <= now()")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| {
+                Error::wrap("failed to expire contact salts".into(), 500, e)
+            })?;
+        Ok(())
+    }
+
+    async fn discover_contacts(
+        &self,
+        user_id: &str,
+        salt: &ContactSalt,
+        hashes: &[String],
+    ) -> Result<Vec<DiscoveredContact>> {
+        // The stored hashes are checked against the current phone, users
+        // whose phone changed after the salt was created are hashed again
+        // with the users who signed up since. The minute covers the users
+        // whose sign up was still being committed then.
+        query!(
+            r#"
+            WITH matched AS (
+                SELECT h.hash, u.id
+                FROM phone_hashes AS h JOIN users AS u ON u.id = h.user_id
+                WHERE h.salt_id = $2 AND h.hash = ANY($4)
+                    AND substr(encode(sha256(convert_to($3 || u.phone, 'UTF8')), 'hex'), 1, 16) = h.hash
+                UNION
+                SELECT substr(encode(sha256(convert_to($3 || phone, 'UTF8')), 'hex'), 1, 16), id
+                FROM users
+                WHERE updated_at >= $5
+                    AND substr(encode(sha256(convert_to($3 || phone, 'UTF8')), 'hex'), 1, 16) = ANY($4)
+            )
+            SELECT
+                m.hash AS "hash!",
+                u.id,
+                u.phone,
+                u.avatar,
+                u.display_name,
+                u.bio,
+                u.updated_at,
+                CASE
+                    WHEN f.status = 'Pending' THEN 'Requested'
+                    WHEN t.status = 'Pending' THEN 'Requesting'
+                    WHEN f.status = 'Accepted' OR t.status = 'Accepted' THEN 'Friend'
+                    WHEN u.id = $1 THEN 'Myself'
+                    ELSE 'Stranger'
+                END AS "typ!"
+            FROM matched AS m
+                JOIN users AS u ON u.id = m.id
+                LEFT JOIN friend_requests AS f ON u.id = f."from" AND f."to" = $1
+                LEFT JOIN friend_requests AS t ON u.id = t."to" AND t."from" = $1
+                LEFT JOIN privacy_settings AS p ON p.user_id = u.id
+            WHERE (
+                    COALESCE(p.discoverable_by_phone, true)
+                    OR u.id = $1 OR f.id IS NOT NULL OR t.id IS NOT NULL
+                )
+                AND NOT EXISTS (
+                    SELECT 1 FROM user_blocks AS b WHERE b.blocker_id = u.id AND b.blocked_id = $1
+                )
+            ORDER BY m.hash
+            "#,
+            user_id,
+            salt.id,
+            salt.salt,
+            hashes,
+            salt.created_at - Duration::minutes(1),
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to discover contacts".into(), 500, e))?
+        .into_iter()
+        .map(|record| {
+            Ok(DiscoveredContact {
+                hash: record.hash,
+                user: User {
+                    id: record.id,
+                    phone: record.phone,
+                    avatar: record.avatar,
+                    display_name: record.display_name,
+                    bio: record.bio,
+                    updated_at: record.updated_at,
+                    typ: Some(user_type(&record.typ)?),
+                },
+            })
+        })
+        .collect()
+    }
+}
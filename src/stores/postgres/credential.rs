@@ -0,0 +1,31 @@
+use super::PostgresRepository;
+use crate::core::credential::CredentialStore;
+use crate::core::error::{Error, Result};
+use sqlx::query;
+
+impl CredentialStore for PostgresRepository {
+    async fn update_password(
+        &self,
+        phone: &str,
+        password: &str,
+        password_salt: &str,
+    ) -> Result<()> {
+        let updated = query!(
+            "UPDATE users SET password = $1, password_salt = $2 WHERE phone = $3",
+            password,
+            password_salt,
+            phone,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to update password".into(), 500, e))?
+        .rows_affected();
+        if updated == 0 {
+            return Err(Error::new(
+                format!("user not found(phone: {})", phone),
+                404,
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use super::PostgresRepository;
+use crate::core::deletion::{AccountDeletion, AccountDeletionStore};
+use crate::core::error::{Error, Result};
+use chrono::{Duration, Utc};
+use sqlx::{query, query_as, query_scalar};
+
+impl AccountDeletionStore for PostgresRepository {
+    async fn schedule_account_deletion(
+        &self,
+        user_id: &str,
+        grace: Duration,
+    ) -> Result<AccountDeletion> {
+        // the no-op update makes the existing row come back
+        query_as!(
+            AccountDeletion,
+            "INSERT INTO account_deletions (user_id, delete_after) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET user_id = $1
+            RETURNING requested_at, delete_after",
+            user_id,
+            Utc::now() + grace,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to schedule account deletion".into(), 500, e)
+        })
+    }
+
+    async fn account_deletion(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<AccountDeletion>> {
+        query_as!(
+            AccountDeletion,
+            "SELECT requested_at, delete_after FROM account_deletions WHERE user_id = $1",
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get account deletion".into(), 500, e)
+        })
+    }
+
+    async fn cancel_account_deletion(&self, user_id: &str) -> Result<()> {
+        let deleted =
+            query!("DELETE FROM account_deletions WHERE user_id = $1", user_id)
+                .execute(&self.pool)
+                .await
+                .map_err(|e| {
+                    Error::wrap(
+                        "failed to cancel account deletion".into(),
+                        500,
+                        e,
+                    )
+                })?
+                .rows_affected();
+        if deleted == 0 {
+            return Err(Error::new(
+                "no account deletion scheduled".into(),
+                404,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn due_account_deletions(&self, limit: i64) -> Result<Vec<String>> {
+        query_scalar!(
+            "SELECT user_id FROM account_deletions WHERE delete_after <= now()
+            ORDER BY delete_after LIMIT $1",
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get due account deletions".into(), 500, e)
+        })
+    }
+
+    async fn delete_account(&self, user_id: &str) -> Result<Vec<String>> {
+        // one statement, so that it all goes or nothing does
+        query_scalar!(
+            r#"
+            WITH
+                deleted_user AS (DELETE FROM users WHERE id = $1 RETURNING phone),
+                -- the user's own row is left to deleted_user, a row changed
+                -- twice in one statement keeps only one of the changes
+                avatars AS (
+                    UPDATE users SET avatar = NULL
+                    WHERE avatar IN (SELECT id FROM uploads WHERE uploader_id = $1)
+                        AND id <> $1
+                ),
+                deleted_uploads AS (
+                    DELETE FROM uploads WHERE uploader_id = $1 RETURNING filepath
+                ),
+                messages AS (DELETE FROM messages WHERE "from" = $1 OR "to" = $1),
+                friend_requests AS (
+                    DELETE FROM friend_requests WHERE "from" = $1 OR "to" = $1
+                ),
+                outbox AS (DELETE FROM notification_outbox WHERE user_id = $1),
+                settings AS (DELETE FROM notification_settings WHERE user_id = $1),
+                peer_settings AS (
+                    DELETE FROM peer_notification_settings WHERE user_id = $1 OR peer_id = $1
+                ),
+                friend_metadata AS (
+                    DELETE FROM friend_metadata WHERE owner_id = $1 OR friend_id = $1
+                ),
+                blocks AS (
+                    DELETE FROM user_blocks WHERE blocker_id = $1 OR blocked_id = $1
+                ),
+                privacy AS (DELETE FROM privacy_settings WHERE user_id = $1),
+                phone_hashes AS (DELETE FROM phone_hashes WHERE user_id = $1),
+                invites AS (DELETE FROM friend_invites WHERE user_id = $1),
+                message_requests AS (
+                    DELETE FROM message_requests WHERE user_id = $1 OR peer_id = $1
+                ),
+                sessions AS (DELETE FROM login_sessions WHERE user_id = $1),
+                tickets AS (DELETE FROM ws_tickets WHERE user_id = $1),
+                two_factor AS (DELETE FROM two_factor WHERE user_id = $1),
+                recovery_codes AS (
+                    DELETE FROM two_factor_recovery_codes WHERE user_id = $1
+                ),
+                challenges AS (DELETE FROM two_factor_challenges WHERE user_id = $1),
+                verifications AS (
+                    DELETE FROM phone_verifications
+                    WHERE phone IN (SELECT phone FROM deleted_user)
+                ),
+                deleted_exports AS (
+                    DELETE FROM data_exports WHERE user_id = $1 RETURNING archive
+                ),
+                deletion AS (DELETE FROM account_deletions WHERE user_id = $1)
+            SELECT filepath AS "filepath!" FROM deleted_uploads
+            UNION ALL
+            SELECT archive FROM deleted_exports WHERE archive IS NOT NULL
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to delete account".into(), 500, e))
+    }
+}
@@ -0,0 +1,250 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::export::{
+    ClaimedDataExport, DataExport, DataExportStatus, DataExportStore,
+    ExportedFriendRequest, ExportedProfile, ExportedUpload, UserData,
+};
+use crate::core::repository::{ChatMessage, Repository};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{query, query_as, query_scalar};
+
+fn export_status(status: &str) -> Result<DataExportStatus> {
+    match status {
+        "Pending" => Ok(DataExportStatus::Pending),
+        "Running" => Ok(DataExportStatus::Running),
+        "Ready" => Ok(DataExportStatus::Ready),
+        "Failed" => Ok(DataExportStatus::Failed),
+        _ => Err(Error::new(
+            format!("invalid data export status: {}", status),
+            500,
+        )),
+    }
+}
+
+fn data_export(
+    id: String,
+    status: &str,
+    requested_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<DataExport> {
+    Ok(DataExport {
+        id,
+        status: export_status(status)?,
+        requested_at,
+        finished_at,
+        expires_at,
+    })
+}
+
+impl DataExportStore for PostgresRepository {
+    async fn request_data_export(&self, user_id: &str) -> Result<DataExport> {
+        // the no-op update makes the active export come back
+        let record = query!(
+            "INSERT INTO data_exports (id, user_id) VALUES ($1, $2)
+            ON CONFLICT (user_id) WHERE status IN ('Pending', 'Running')
+            DO UPDATE SET user_id = $2
+            RETURNING id, status, requested_at, finished_at, expires_at",
+            self.id_generator.lock().await.generate().to_string(),
+            user_id,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to request data export".into(), 500, e)
+        })?;
+        data_export(
+            record.id,
+            &record.status,
+            record.requested_at,
+            record.finished_at,
+            record.expires_at,
+        )
+    }
+
+    async fn data_export(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<Option<DataExport>> {
+        query!(
+            "SELECT id, status, requested_at, finished_at, expires_at
+            FROM data_exports WHERE id = $1 AND user_id = $2",
+            id,
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get data export".into(), 500, e))?
+        .map(|record| {
+            data_export(
+                record.id,
+                &record.status,
+                record.requested_at,
+                record.finished_at,
+                record.expires_at,
+            )
+        })
+        .transpose()
+    }
+
+    async fn data_export_archive(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<Option<String>> {
+        Ok(query_scalar!(
+            "SELECT archive FROM data_exports
+            WHERE id = $1 AND user_id = $2 AND status = 'Ready' AND expires_at > now()",
+            id,
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get data export archive".into(), 500, e)
+        })?
+        .flatten())
+    }
+
+    async fn claim_data_export(
+        &self,
+        lease: Duration,
+    ) -> Result<Option<ClaimedDataExport>> {
+        query_as!(
+            ClaimedDataExport,
+            r#"
+            UPDATE data_exports SET status = 'Running', claimed_at = now()
+            WHERE id = (
+                SELECT id FROM data_exports
+                WHERE status = 'Pending' OR (status = 'Running' AND claimed_at <= $1)
+                ORDER BY requested_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id
+            "#,
+            Utc::now() - lease,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to claim data export".into(), 500, e)
+        })
+    }
+
+    async fn complete_data_export(
+        &self,
+        id: &str,
+        archive: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        query!(
+            "UPDATE data_exports
+            SET status = 'Ready', archive = $2, finished_at = now(), expires_at = $3
+            WHERE id = $1",
+            id,
+            archive,
+            Utc::now() + ttl,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to complete data export".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn fail_data_export(
+        &self,
+        id: &str,
+        error: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        query!(
+            "UPDATE data_exports
+            SET status = 'Failed', error = $2, finished_at = now(), expires_at = $3
+            WHERE id = $1",
+            id,
+            error,
+            Utc::now() + ttl,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to fail data export".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn expire_data_exports(&self) -> Result<Vec<String>> {
+        Ok(query_scalar!(
+            "DELETE FROM data_exports WHERE expires_at <= now() RETURNING archive"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to expire data exports".into(), 500, e)
+        })?
+        .into_iter()
+        .flatten()
+        .collect())
+    }
+
+    async fn user_data(&self, user_id: &str) -> Result<UserData> {
+        let profile = query_as!(
+            ExportedProfile,
+            "SELECT id, phone, avatar, display_name, bio, created_at, updated_at
+            FROM users WHERE id = $1",
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get user".into(), 500, e))?
+        .ok_or_else(|| Error::new("user not found".into(), 404))?;
+        let friends = self.friends(user_id).await?;
+        let friend_requests = query_as!(
+            ExportedFriendRequest,
+            r#"
+            SELECT id, "from", "to", status, created_at AT TIME ZONE 'UTC' AS "created_at!"
+            FROM friend_requests WHERE "from" = $1 OR "to" = $1
+            ORDER BY created_at
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get friend requests".into(), 500, e)
+        })?;
+        let messages = query_as!(
+            ChatMessage,
+            r#"
+            SELECT id, "from", "to", content, sent_at, has_read, mime_type
+            FROM messages WHERE "from" = $1 OR "to" = $1
+            ORDER BY sent_at
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get messages".into(), 500, e))?;
+        let uploads = query_as!(
+            ExportedUpload,
+            "SELECT id, filename, mime_type, uploaded_at, filepath
+            FROM uploads WHERE uploader_id = $1
+            ORDER BY uploaded_at",
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get uploads".into(), 500, e))?;
+        Ok(UserData {
+            profile,
+            friends,
+            friend_requests,
+            messages,
+            uploads,
+        })
+    }
+}
@@ -0,0 +1,129 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::friend::{FriendMetadata, FriendMetadataStore};
+use sqlx::{query, query_as, query_scalar};
+
+// The favorite flag lives with the peer notification settings, where it
+// already decided whether calls ring through do-not-disturb.
+impl FriendMetadataStore for PostgresRepository {
+    async fn all_friend_metadata(
+        &self,
+        owner_id: &str,
+    ) -> Result<Vec<FriendMetadata>> {
+        query_as!(
+            FriendMetadata,
+            r#"
+            SELECT
+                COALESCE(m.friend_id, p.peer_id) AS "friend_id!",
+                m.nickname,
+                COALESCE(m.labels, '{}') AS "labels!",
+                p.peer_id IS NOT NULL AS "favorite!"
+            FROM (SELECT * FROM friend_metadata WHERE owner_id = $1) AS m
+                FULL JOIN (
+                    SELECT peer_id FROM peer_notification_settings
+                    WHERE user_id = $1 AND favorite
+                ) AS p ON p.peer_id = m.friend_id
+            "#,
+            owner_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get friend metadata".into(), 500, e)
+        })
+    }
+
+    async fn friend_metadata(
+        &self,
+        owner_id: &str,
+        friend_id: &str,
+    ) -> Result<FriendMetadata> {
+        query_as!(
+            FriendMetadata,
+            r#"
+            SELECT
+                $2::VARCHAR AS "friend_id!",
+                m.nickname,
+                COALESCE(m.labels, '{}') AS "labels!",
+                COALESCE(p.favorite, false) AS "favorite!"
+            FROM (SELECT 1) AS one
+                LEFT JOIN friend_metadata AS m ON m.owner_id = $1 AND m.friend_id = $2
+                LEFT JOIN peer_notification_settings AS p
+                    ON p.user_id = $1 AND p.peer_id = $2
+            "#,
+            owner_id,
+            friend_id,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get friend metadata".into(), 500, e)
+        })
+    }
+
+    async fn update_friend_metadata(
+        &self,
+        owner_id: &str,
+        metadata: &FriendMetadata,
+    ) -> Result<()> {
+        query!(
+            "WITH metadata AS (
+                INSERT INTO friend_metadata (owner_id, friend_id, nickname, labels)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (owner_id, friend_id) DO UPDATE
+                SET nickname = $3, labels = $4, updated_at = now()
+            )
+            INSERT INTO peer_notification_settings (user_id, peer_id, favorite)
+            VALUES ($1, $2, $5)
+            ON CONFLICT (user_id, peer_id) DO UPDATE SET favorite = $5",
+            owner_id,
+            metadata.friend_id,
+            metadata.nickname,
+            &metadata.labels,
+            metadata.favorite,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to update friend metadata".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn delete_friend_metadata(
+        &self,
+        owner_id: &str,
+        friend_id: &str,
+    ) -> Result<()> {
+        query!(
+            "WITH metadata AS (
+                DELETE FROM friend_metadata WHERE owner_id = $1 AND friend_id = $2
+            )
+            UPDATE peer_notification_settings SET favorite = false
+            WHERE user_id = $1 AND peer_id = $2",
+            owner_id,
+            friend_id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to delete friend metadata".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn friend_labels(&self, owner_id: &str) -> Result<Vec<String>> {
+        query_scalar!(
+            r#"
+            SELECT DISTINCT label AS "label!"
+            FROM friend_metadata, unnest(labels) AS label
+            WHERE owner_id = $1
+            ORDER BY 1
+            "#,
+            owner_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get friend labels".into(), 500, e))
+    }
+}
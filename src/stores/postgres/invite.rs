@@ -0,0 +1,154 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::invite::{FriendInvite, FriendInviteStore, NewFriendInvite};
+use crate::core::outbox::EnqueueNotification;
+use sqlx::{query, query_as, query_scalar, types::Uuid};
+
+impl FriendInviteStore for PostgresRepository {
+    async fn create_friend_invite(
+        &self,
+        user_id: &str,
+        invite: &NewFriendInvite,
+    ) -> Result<FriendInvite> {
+        query_as!(
+            FriendInvite,
+            "INSERT INTO friend_invites (id, user_id, token, max_uses, auto_accept, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, user_id, token, max_uses, uses, auto_accept, created_at,
+                expires_at, revoked_at",
+            self.id_generator.lock().await.generate().to_string(),
+            user_id,
+            invite.token,
+            invite.max_uses,
+            invite.auto_accept,
+            invite.expires_at,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to create friend invite".into(), 500, e)
+        })
+    }
+
+    async fn friend_invites(&self, user_id: &str) -> Result<Vec<FriendInvite>> {
+        query_as!(
+            FriendInvite,
+            "SELECT id, user_id, token, max_uses, uses, auto_accept, created_at,
+                expires_at, revoked_at
+            FROM friend_invites WHERE user_id = $1
+            ORDER BY created_at DESC",
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get friend invites".into(), 500, e))
+    }
+
+    async fn friend_invite(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<Option<FriendInvite>> {
+        query_as!(
+            FriendInvite,
+            "SELECT id, user_id, token, max_uses, uses, auto_accept, created_at,
+                expires_at, revoked_at
+            FROM friend_invites WHERE id = $1 AND user_id = $2",
+            id,
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get friend invite".into(), 500, e))
+    }
+
+    async fn revoke_friend_invite(
+        &self,
+        user_id: &str,
+        id: &str,
+    ) -> Result<bool> {
+        Ok(query!(
+            "UPDATE friend_invites SET revoked_at = now()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+            id,
+            user_id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to revoke friend invite".into(), 500, e)
+        })?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn usable_friend_invite(
+        &self,
+        token: &str,
+    ) -> Result<Option<FriendInvite>> {
+        query_as!(
+            FriendInvite,
+            "SELECT id, user_id, token, max_uses, uses, auto_accept, created_at,
+                expires_at, revoked_at
+            FROM friend_invites
+            WHERE token = $1
+                AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > now())
+                AND (max_uses IS NULL OR uses < max_uses)",
+            token,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get friend invite".into(), 500, e))
+    }
+
+    async fn redeem_friend_invite(
+        &self,
+        id: &str,
+        user_id: &str,
+        accept: bool,
+        push: Option<&EnqueueNotification>,
+    ) -> Result<Option<String>> {
+        let map_err =
+            |e| Error::wrap("failed to redeem friend invite".into(), 500, e);
+        let mut tx = self.pool.begin().await.map_err(map_err)?;
+        // the conditions are checked again, another redeem may have used
+        // the last use up
+        let Some(inviter) = query_scalar!(
+            "UPDATE friend_invites SET uses = uses + 1
+            WHERE id = $1
+                AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > now())
+                AND (max_uses IS NULL OR uses < max_uses)
+            RETURNING user_id",
+            id,
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(map_err)?
+        else {
+            return Ok(None);
+        };
+        let request_id = query_scalar!(
+            r#"INSERT INTO friend_requests (id, "from", "to", status) VALUES ($1, $2, $3, $4)
+            ON CONFLICT ("from", "to") DO UPDATE SET status = $4, created_at = CURRENT_TIMESTAMP
+            RETURNING id"#,
+            Uuid::new_v4().to_string(),
+            user_id,
+            inviter,
+            if accept { "Accepted" } else { "Pending" },
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(map_err)?;
+        if let Some(push) = push {
+            let mut push = push.clone();
+            if let Some(data) = push.data.as_object_mut() {
+                data.insert("id".into(), request_id.clone().into());
+            }
+            self.enqueue_with(&mut *tx, &push).await?;
+        }
+        tx.commit().await.map_err(map_err)?;
+        Ok(Some(request_id))
+    }
+}
@@ -1,6 +1,21 @@
 pub(crate) mod auth;
+pub(crate) mod block;
+pub(crate) mod contact;
+pub(crate) mod credential;
+pub(crate) mod deletion;
+pub(crate) mod export;
+pub(crate) mod friend;
+pub(crate) mod invite;
+pub(crate) mod outbox;
+pub(crate) mod phone;
+pub(crate) mod preference;
+pub(crate) mod privacy;
+pub(crate) mod ratelimit;
+pub(crate) mod session;
 pub(crate) mod store;
+pub(crate) mod twofactor;
 pub(crate) mod upload;
+pub(crate) mod verification;
 
 use futures_util::lock::Mutex;
 use snowflake::SnowflakeIdGenerator;
@@ -0,0 +1,162 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::outbox::{
+    DeadLetter, EnqueueNotification, NotificationOutbox, OutboxNotification,
+};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{query, query_as, query_scalar, PgExecutor};
+
+impl PostgresRepository {
+    /// Writes `notification` to the outbox through `executor`, so that it
+    /// can be part of the transaction writing what it notifies of.
+    pub(super) async fn enqueue_with<'c>(
+        &self,
+        executor: impl PgExecutor<'c>,
+        notification: &EnqueueNotification,
+    ) -> Result<String> {
+        query_scalar!(
+            "INSERT INTO notification_outbox (id, user_id, title, body, collapse_key, thread_id, data, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id",
+            self.id_generator.lock().await.generate().to_string(),
+            notification.user_id,
+            notification.title,
+            notification.body,
+            notification.collapse_key,
+            notification.thread_id,
+            notification.data,
+            Utc::now() + notification.ttl,
+        )
+        .fetch_one(executor)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to enqueue notification".into(), 500, e)
+        })
+    }
+}
+
+impl NotificationOutbox for PostgresRepository {
+    async fn enqueue(
+        &self,
+        notification: &EnqueueNotification,
+    ) -> Result<String> {
+        self.enqueue_with(&self.pool, notification).await
+    }
+
+    async fn claim_due(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<OutboxNotification>> {
+        query_as!(
+            OutboxNotification,
+            r#"
+            UPDATE notification_outbox
+            SET attempts = attempts + 1, next_attempt_at = $2
+            WHERE id IN (
+                SELECT id FROM notification_outbox
+                WHERE status = 'Pending' AND next_attempt_at <= now() AND expires_at > now()
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id, title, body, collapse_key, thread_id, data, attempts, expires_at
+            "#,
+            limit,
+            Utc::now() + lease,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to claim notifications".into(), 500, e)
+        })
+    }
+
+    async fn discard_expired(&self) -> Result<u64> {
+        Ok(query!(
+            "DELETE FROM notification_outbox WHERE status = 'Pending' AND expires_at <= now()"
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to discard notifications".into(), 500, e)
+        })?
+        .rows_affected())
+    }
+
+    async fn remove(&self, id: &str) -> Result<()> {
+        query!("DELETE FROM notification_outbox WHERE id = $1", id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| {
+                Error::wrap("failed to remove notification".into(), 500, e)
+            })?;
+        Ok(())
+    }
+
+    async fn reschedule(
+        &self,
+        id: &str,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        query!(
+            "UPDATE notification_outbox SET next_attempt_at = $1, last_error = $2 WHERE id = $3",
+            next_attempt_at,
+            error,
+            id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to reschedule notification".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn mark_dead(&self, id: &str, error: &str) -> Result<()> {
+        query!(
+            "UPDATE notification_outbox SET status = 'Dead', last_error = $1 WHERE id = $2",
+            error,
+            id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to update notification".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn dead_letters(
+        &self,
+        user_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<DeadLetter>> {
+        query_as!(
+            DeadLetter,
+            r#"SELECT
+                id AS "id!",
+                user_id AS "user_id!",
+                title AS "title!",
+                body AS "body!",
+                data AS "data!",
+                attempts AS "attempts!",
+                last_error,
+                created_at AS "created_at!"
+            FROM notification_dead_letters
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2 OFFSET $3"#,
+            user_id,
+            limit,
+            offset,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get dead letters".into(), 500, e)
+        })
+    }
+}
@@ -0,0 +1,104 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::phone::PhoneNormalizer;
+use log::warn;
+use sqlx::{query, query_scalar};
+
+#[derive(Debug, Default)]
+pub(crate) struct PhoneNormalizationReport {
+    pub(crate) normalized: usize,
+    pub(crate) conflicts: usize,
+}
+
+/// Users loaded at once while normalizing.
+const NORMALIZE_BATCH_SIZE: i64 = 1000;
+
+impl PostgresRepository {
+    /// Rewrites the phones of existing users to E.164. Invalid phones and
+    /// phones whose normalized form is taken by another user are left as
+    /// they are and recorded in `phone_conflicts`.
+    ///
+    /// It's a one-off job run with `webrtc-signal-server normalize-phones`,
+    /// phones are normalized when they are stored since.
+    pub(crate) async fn normalize_phones(
+        &self,
+        normalizer: &PhoneNormalizer,
+    ) -> Result<PhoneNormalizationReport> {
+        let mut report = PhoneNormalizationReport::default();
+        let mut after = String::new();
+        loop {
+            let users = query!(
+                r#"SELECT id, phone FROM users
+                WHERE phone !~ '^\+[1-9][0-9]{6,14}$'
+                AND id NOT IN (SELECT user_id FROM phone_conflicts)
+                AND id > $1
+                ORDER BY id
+                LIMIT $2"#,
+                after,
+                NORMALIZE_BATCH_SIZE,
+            )
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| Error::wrap("failed to get users".into(), 500, e))?;
+            let Some(last) = users.last() else {
+                return Ok(report);
+            };
+            after = last.id.clone();
+            for user in users {
+                let normalized = normalizer.normalize(&user.phone).ok();
+                let conflicting_user_id = match &normalized {
+                    Some(normalized) => query_scalar!(
+                        "SELECT id FROM users WHERE phone = $1 AND id != $2",
+                        normalized,
+                        user.id,
+                    )
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(|e| {
+                        Error::wrap("failed to get user".into(), 500, e)
+                    })?,
+                    None => None,
+                };
+                match (&normalized, &conflicting_user_id) {
+                    (Some(normalized), None) => {
+                        query!(
+                            "UPDATE users SET phone = $1, updated_at = now() WHERE id = $2",
+                            normalized,
+                            user.id,
+                        )
+                        .execute(&self.pool)
+                        .await
+                        .map_err(|e| {
+                            Error::wrap("failed to update phone".into(), 500, e)
+                        })?;
+                        report.normalized += 1;
+                    }
+                    _ => {
+                        warn!(
+                            "could not normalize phone {} of user {}(normalized: {:?}, taken by: {:?})",
+                            user.phone, user.id, normalized, conflicting_user_id
+                        );
+                        query!(
+                            "INSERT INTO phone_conflicts (user_id, phone, normalized, conflicting_user_id)
+                            VALUES ($1, $2, $3, $4)",
+                            user.id,
+                            user.phone,
+                            normalized,
+                            conflicting_user_id,
+                        )
+                        .execute(&self.pool)
+                        .await
+                        .map_err(|e| {
+                            Error::wrap(
+                                "failed to record phone conflict".into(),
+                                500,
+                                e,
+                            )
+                        })?;
+                        report.conflicts += 1;
+                    }
+                }
+            }
+        }
+    }
+}
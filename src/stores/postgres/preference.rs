@@ -0,0 +1,175 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::preference::{
+    DoNotDisturb, NotificationKind, NotificationPreferences,
+    NotificationSettings, PeerNotificationSettings,
+};
+use sqlx::{query, query_as, query_scalar};
+
+impl NotificationPreferences for PostgresRepository {
+    async fn notification_settings(
+        &self,
+        user_id: &str,
+    ) -> Result<NotificationSettings> {
+        Ok(query!(
+            "SELECT muted, dnd_start, dnd_end, time_zone, dnd_allow_favorites, locale, hide_previews
+            FROM notification_settings WHERE user_id = $1",
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get notification settings".into(), 500, e)
+        })?
+        .map(|record| NotificationSettings {
+            muted: record.muted,
+            do_not_disturb: record.dnd_start.zip(record.dnd_end).map(
+                |(start, end)| DoNotDisturb {
+                    start,
+                    end,
+                    time_zone: record.time_zone,
+                },
+            ),
+            dnd_allow_favorites: record.dnd_allow_favorites,
+            locale: record.locale,
+            hide_previews: record.hide_previews,
+        })
+        .unwrap_or_default())
+    }
+
+    async fn update_notification_settings(
+        &self,
+        user_id: &str,
+        settings: &NotificationSettings,
+    ) -> Result<()> {
+        let dnd = settings.do_not_disturb.as_ref();
+        let time_zone = dnd.map(|d| d.time_zone.as_str()).unwrap_or("UTC");
+        if !query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM pg_timezone_names WHERE name = $1) AS "exists!""#,
+            time_zone,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to check time zone".into(), 500, e)
+        })? {
+            return Err(Error::new(
+                format!("unknown time zone: {}", time_zone),
+                422,
+            ));
+        }
+        query!(
+            "INSERT INTO notification_settings (user_id, muted, dnd_start, dnd_end, time_zone, dnd_allow_favorites, locale, hide_previews)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (user_id) DO UPDATE
+            SET muted = $2, dnd_start = $3, dnd_end = $4, time_zone = $5, dnd_allow_favorites = $6, locale = $7, hide_previews = $8",
+            user_id,
+            settings.muted,
+            dnd.map(|d| d.start),
+            dnd.map(|d| d.end),
+            time_zone,
+            settings.dnd_allow_favorites,
+            settings.locale,
+            settings.hide_previews,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap(
+                "failed to update notification settings".into(),
+                500,
+                e,
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn peer_notification_settings(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<PeerNotificationSettings>> {
+        query_as!(
+            PeerNotificationSettings,
+            "SELECT peer_id, muted_until, favorite
+            FROM peer_notification_settings WHERE user_id = $1",
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get notification settings".into(), 500, e)
+        })
+    }
+
+    async fn update_peer_notification_settings(
+        &self,
+        user_id: &str,
+        settings: &PeerNotificationSettings,
+    ) -> Result<()> {
+        query!(
+            "INSERT INTO peer_notification_settings (user_id, peer_id, muted_until, favorite)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, peer_id) DO UPDATE
+            SET muted_until = $3, favorite = $4",
+            user_id,
+            settings.peer_id,
+            settings.muted_until,
+            settings.favorite,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap(
+                "failed to update notification settings".into(),
+                500,
+                e,
+            )
+        })?;
+        Ok(())
+    }
+
+    async fn should_notify(
+        &self,
+        user_id: &str,
+        from: &str,
+        kind: NotificationKind,
+    ) -> Result<bool> {
+        query_scalar!(
+            r#"
+            WITH local AS (
+                SELECT
+                    s.muted,
+                    s.dnd_start,
+                    s.dnd_end,
+                    s.dnd_allow_favorites,
+                    (now() AT TIME ZONE s.time_zone)::TIME AS now
+                FROM notification_settings AS s
+                WHERE s.user_id = $1
+            )
+            SELECT
+                NOT COALESCE(l.muted, false)
+                AND NOT COALESCE(p.muted_until > now(), false)
+                AND (
+                    l.dnd_start IS NULL
+                    OR l.dnd_end IS NULL
+                    OR NOT CASE
+                        WHEN l.dnd_start <= l.dnd_end THEN l.now >= l.dnd_start AND l.now < l.dnd_end
+                        ELSE l.now >= l.dnd_start OR l.now < l.dnd_end
+                    END
+                    OR ($3 AND l.dnd_allow_favorites AND COALESCE(p.favorite, false))
+                ) AS "notify!"
+            FROM (SELECT 1) AS one
+                LEFT JOIN local AS l ON true
+                LEFT JOIN peer_notification_settings AS p ON p.user_id = $1 AND p.peer_id = $2
+            "#,
+            user_id,
+            from,
+            kind == NotificationKind::Call,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to check notification settings".into(), 500, e)
+        })
+    }
+}
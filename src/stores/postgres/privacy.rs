@@ -0,0 +1,201 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::privacy::{
+    Contact, ContactPolicy, MessageRequest, PrivacySettings, PrivacyStore,
+};
+use crate::core::repository::Repository;
+use sqlx::{query, query_as, query_scalar};
+
+fn contact_policy(policy: &str) -> Result<ContactPolicy> {
+    match policy {
+        "FriendsOnly" => Ok(ContactPolicy::FriendsOnly),
+        "Anyone" => Ok(ContactPolicy::Anyone),
+        "MessageRequests" => Ok(ContactPolicy::MessageRequests),
+        _ => Err(Error::new(
+            format!("invalid contact policy: {}", policy),
+            500,
+        )),
+    }
+}
+
+fn contact_policy_name(policy: ContactPolicy) -> &'static str {
+    match policy {
+        ContactPolicy::FriendsOnly => "FriendsOnly",
+        ContactPolicy::Anyone => "Anyone",
+        ContactPolicy::MessageRequests => "MessageRequests",
+    }
+}
+
+impl PrivacyStore for PostgresRepository {
+    async fn privacy_settings(&self, user_id: &str) -> Result<PrivacySettings> {
+        let Some(record) = query!(
+            "SELECT contact_policy, discoverable_by_phone
+            FROM privacy_settings WHERE user_id = $1",
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get privacy settings".into(), 500, e)
+        })?
+        else {
+            return Ok(PrivacySettings::default());
+        };
+        Ok(PrivacySettings {
+            contact_policy: contact_policy(&record.contact_policy)?,
+            discoverable_by_phone: record.discoverable_by_phone,
+        })
+    }
+
+    async fn update_privacy_settings(
+        &self,
+        user_id: &str,
+        settings: &PrivacySettings,
+    ) -> Result<()> {
+        query!(
+            "INSERT INTO privacy_settings (user_id, contact_policy, discoverable_by_phone)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE
+            SET contact_policy = $2, discoverable_by_phone = $3",
+            user_id,
+            contact_policy_name(settings.contact_policy),
+            settings.discoverable_by_phone,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to update privacy settings".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn contact(&self, from: &str, to: &str) -> Result<Contact> {
+        let friends = self.is_friend(from, to).await?;
+        // replies and call answers go back the way the conversation came,
+        // which unfriending forgets along with the requests
+        let record = query!(
+            r#"
+            SELECT
+                EXISTS(
+                    SELECT 1 FROM message_requests
+                    WHERE (user_id = $2 AND peer_id = $1 AND status = 'Accepted')
+                        OR (user_id = $1 AND peer_id = $2)
+                ) AS "conversing!",
+                (SELECT contact_policy FROM privacy_settings WHERE user_id = $2) AS policy
+            "#,
+            from,
+            to,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to check contact policy".into(), 500, e)
+        })?;
+        let policy = match record.policy {
+            Some(policy) => contact_policy(&policy)?,
+            None => ContactPolicy::default(),
+        };
+        Ok(policy.contact(friends, record.conversing))
+    }
+
+    async fn add_message_request(&self, from: &str, to: &str) -> Result<()> {
+        query!(
+            "INSERT INTO message_requests (user_id, peer_id) VALUES ($1, $2)
+            ON CONFLICT DO NOTHING",
+            to,
+            from,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to add message request".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn message_requests(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<MessageRequest>> {
+        query_as!(
+            MessageRequest,
+            r#"
+            SELECT
+                r.peer_id,
+                u.phone AS peer_phone,
+                u.display_name AS peer_display_name,
+                m.unread_count AS "unread_count!",
+                m.latest_mime_type,
+                m.latest_content,
+                r.created_at AS requested_at
+            FROM message_requests AS r
+                JOIN users AS u ON u.id = r.peer_id
+                CROSS JOIN LATERAL (
+                    SELECT
+                        COUNT(*) FILTER (WHERE NOT has_read) AS unread_count,
+                        (ARRAY_AGG(mime_type ORDER BY id DESC))[1] AS latest_mime_type,
+                        (ARRAY_AGG(content ORDER BY id DESC))[1] AS latest_content
+                    FROM messages
+                    WHERE "from" = r.peer_id AND "to" = r.user_id
+                ) AS m
+            WHERE r.user_id = $1 AND r.status = 'Pending'
+            ORDER BY r.created_at DESC
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get message requests".into(), 500, e)
+        })
+    }
+
+    async fn accept_message_request(
+        &self,
+        user_id: &str,
+        peer_id: &str,
+    ) -> Result<bool> {
+        Ok(query!(
+            "UPDATE message_requests SET status = 'Accepted'
+            WHERE user_id = $1 AND peer_id = $2 AND status = 'Pending'",
+            user_id,
+            peer_id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to accept message request".into(), 500, e)
+        })?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn decline_message_request(
+        &self,
+        user_id: &str,
+        peer_id: &str,
+    ) -> Result<bool> {
+        query_scalar!(
+            r#"
+            WITH
+                declined AS (
+                    DELETE FROM message_requests
+                    WHERE user_id = $1 AND peer_id = $2 AND status = 'Pending'
+                    RETURNING peer_id
+                ),
+                messages AS (
+                    DELETE FROM messages
+                    WHERE "from" = $2 AND "to" = $1 AND EXISTS (SELECT 1 FROM declined)
+                )
+            SELECT EXISTS (SELECT 1 FROM declined) AS "declined!"
+            "#,
+            user_id,
+            peer_id,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to decline message request".into(), 500, e)
+        })
+    }
+}
@@ -0,0 +1,73 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::ratelimit::{RateLimitPolicy, RateLimitStore};
+use chrono::{DateTime, Utc};
+use sqlx::{query, query_scalar};
+
+impl RateLimitStore for PostgresRepository {
+    async fn locked_until(
+        &self,
+        keys: &[String],
+    ) -> Result<Option<DateTime<Utc>>> {
+        query_scalar!(
+            "SELECT MAX(locked_until) FROM rate_limits WHERE key = ANY($1) AND locked_until > now()",
+            keys,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to check rate limits".into(), 500, e))
+    }
+
+    async fn record_attempt(
+        &self,
+        key: &str,
+        policy: &RateLimitPolicy,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let now = Utc::now();
+        let record = query!(
+            "INSERT INTO rate_limits (key, attempts, window_start) VALUES ($1, 1, $2)
+            ON CONFLICT (key) DO UPDATE
+            SET
+                attempts = CASE WHEN rate_limits.window_start < $3 THEN 1 ELSE rate_limits.attempts + 1 END,
+                window_start = CASE WHEN rate_limits.window_start < $3 THEN $2 ELSE rate_limits.window_start END,
+                lockouts = CASE WHEN rate_limits.locked_until < $4 THEN 0 ELSE rate_limits.lockouts END
+            RETURNING attempts, lockouts",
+            key,
+            now,
+            now - policy.window,
+            // lockouts are forgotten once the longest one would be over
+            now - policy.max_lockout,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to record attempt".into(), 500, e)
+        })?;
+        if record.attempts < policy.max_attempts {
+            return Ok(None);
+        }
+        let until = now + policy.lockout(record.lockouts);
+        query!(
+            "UPDATE rate_limits
+            SET attempts = 0, window_start = $2, lockouts = lockouts + 1, locked_until = $3
+            WHERE key = $1",
+            key,
+            now,
+            until,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to lock out".into(), 500, e))?;
+        Ok(Some(until))
+    }
+
+    async fn reset(&self, keys: &[String]) -> Result<()> {
+        query!("DELETE FROM rate_limits WHERE key = ANY($1)", keys)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| {
+                Error::wrap("failed to reset rate limits".into(), 500, e)
+            })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::session::{
+    LoginSession, LoginSessionStore, TokenLifetimes, WsTicketStore,
+};
+use chrono::{Duration, Utc};
+use log::warn;
+use sqlx::{query, query_as};
+
+impl LoginSessionStore for PostgresRepository {
+    async fn bind_token(
+        &self,
+        session_id: &str,
+        token_hash: &str,
+    ) -> Result<()> {
+        query!(
+            "UPDATE login_sessions SET token_hash = $1 WHERE id = $2",
+            token_hash,
+            session_id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to update login session".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn issue_refresh_token(
+        &self,
+        session_id: &str,
+        refresh_token_hash: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        query!(
+            "INSERT INTO refresh_tokens (token_hash, session_id, expires_at) VALUES ($1, $2, $3)",
+            refresh_token_hash,
+            session_id,
+            Utc::now() + ttl,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to issue refresh token".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        key: &str,
+        lifetimes: TokenLifetimes,
+    ) -> Result<String> {
+        let map_err =
+            |e| Error::wrap("failed to rotate refresh token".into(), 500, e);
+        let mut tx = self.pool.begin().await.map_err(map_err)?;
+        let record = query!(
+            "SELECT session_id, expires_at, used_at FROM refresh_tokens WHERE token_hash = $1 FOR UPDATE",
+            refresh_token_hash,
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(map_err)?
+        .ok_or_else(|| Error::new("invalid refresh token".into(), 401))?;
+        if record.used_at.is_some() {
+            // the refresh tokens of the session go along
+            query!(
+                "DELETE FROM login_sessions WHERE id = $1",
+                record.session_id,
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(map_err)?;
+            tx.commit().await.map_err(map_err)?;
+            warn!(
+                "refresh token reused, revoked login session {}",
+                record.session_id
+            );
+            return Err(Error::new("invalid refresh token".into(), 401));
+        }
+        let now = Utc::now();
+        if record.expires_at <= now {
+            return Err(Error::new("refresh token expired".into(), 401));
+        }
+        query!(
+            "UPDATE refresh_tokens SET used_at = $1 WHERE token_hash = $2",
+            now,
+            refresh_token_hash,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(map_err)?;
+        query!(
+            "INSERT INTO refresh_tokens (token_hash, session_id, expires_at) VALUES ($1, $2, $3)",
+            new_refresh_token_hash,
+            record.session_id,
+            now + lifetimes.refresh,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(map_err)?;
+        query!(
+            "UPDATE login_sessions
+            SET key = $1, key_expires_at = $2, token_hash = NULL, last_used_at = $3
+            WHERE id = $4",
+            key,
+            now + lifetimes.access,
+            now,
+            record.session_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(map_err)?;
+        tx.commit().await.map_err(map_err)?;
+        Ok(record.session_id)
+    }
+
+    async fn login_sessions(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+    ) -> Result<Vec<LoginSession>> {
+        query_as!(
+            LoginSession,
+            r#"SELECT
+                id,
+                device_name,
+                platform,
+                ip,
+                created_at,
+                last_used_at,
+                COALESCE(token_hash = $2, false) AS "current!"
+            FROM login_sessions
+            WHERE user_id = $1
+            ORDER BY last_used_at DESC"#,
+            user_id,
+            token_hash,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get login sessions".into(), 500, e))
+    }
+
+    async fn revoke_login_session(
+        &self,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<()> {
+        let deleted = query!(
+            "DELETE FROM login_sessions WHERE id = $1 AND user_id = $2",
+            session_id,
+            user_id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to revoke login session".into(), 500, e)
+        })?
+        .rows_affected();
+        if deleted == 0 {
+            return Err(Error::new(
+                format!("login session not found(id: {})", session_id),
+                404,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn revoke_current_login_session(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+    ) -> Result<()> {
+        query!(
+            "DELETE FROM login_sessions WHERE user_id = $1 AND token_hash = $2",
+            user_id,
+            token_hash,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to revoke login session".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn revoke_other_login_sessions(
+        &self,
+        user_id: &str,
+        token_hash: &str,
+    ) -> Result<u64> {
+        Ok(query!(
+            "DELETE FROM login_sessions WHERE user_id = $1 AND token_hash IS DISTINCT FROM $2",
+            user_id,
+            token_hash,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to revoke login sessions".into(), 500, e)
+        })?
+        .rows_affected())
+    }
+}
+
+impl WsTicketStore for PostgresRepository {
+    async fn issue_ws_ticket(
+        &self,
+        user_id: &str,
+        ticket_hash: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        // tickets that were never redeemed are swept out here
+        query!("DELETE FROM ws_tickets WHERE expires_at < now()")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| {
+                Error::wrap("failed to issue ws ticket".into(), 500, e)
+            })?;
+        query!(
+            "INSERT INTO ws_tickets (ticket_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+            ticket_hash,
+            user_id,
+            Utc::now() + ttl,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to issue ws ticket".into(), 500, e))?;
+        Ok(())
+    }
+
+    async fn redeem_ws_ticket(
+        &self,
+        ticket_hash: &str,
+    ) -> Result<Option<String>> {
+        Ok(query!(
+            "DELETE FROM ws_tickets WHERE ticket_hash = $1 RETURNING user_id, expires_at",
+            ticket_hash,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to redeem ws ticket".into(), 500, e))?
+        .filter(|r| r.expires_at > Utc::now())
+        .map(|r| r.user_id))
+    }
+}
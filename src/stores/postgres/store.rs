@@ -1,25 +1,96 @@
 use super::PostgresRepository;
 use crate::core::error::{Error, Result};
+use crate::core::outbox::EnqueueNotification;
 use crate::core::repository::{
-    ChatMessage, FriendRequest, FriendRequestStatus, InsertChatMessage,
-    Repository, Session, User, UserType,
+    ChatMessage, FriendCursor, FriendRequest, FriendRequestStatus, FriendSort,
+    FriendSuggestion, InsertChatMessage, Page, ProfileUpdate, Repository,
+    Session, User, UserType,
 };
+use chrono::{DateTime, Duration, Utc};
 use sqlx::{query, query_as, query_scalar, types::Uuid};
 
+fn friend_sort_name(sort: FriendSort) -> &'static str {
+    match sort {
+        FriendSort::Name => "Name",
+        FriendSort::Recent => "Recent",
+    }
+}
+
+fn cursor_parts(
+    cursor: Option<&FriendCursor>,
+) -> (Option<&str>, Option<DateTime<Utc>>, Option<&str>) {
+    match cursor {
+        Some(FriendCursor::Name { name, id }) => (Some(name), None, Some(id)),
+        Some(FriendCursor::Recent { at, id }) => (None, Some(*at), Some(id)),
+        None => (None, None, None),
+    }
+}
+
+fn cursor(
+    sort: FriendSort,
+    name: &str,
+    at: DateTime<Utc>,
+    id: &str,
+) -> FriendCursor {
+    match sort {
+        FriendSort::Name => FriendCursor::Name {
+            name: name.to_owned(),
+            id: id.to_owned(),
+        },
+        FriendSort::Recent => FriendCursor::Recent {
+            at,
+            id: id.to_owned(),
+        },
+    }
+}
+
+/// Drops the extra row fetched to tell whether there is another page and
+/// returns the cursor of that page.
+fn next_cursor<T>(
+    records: &mut Vec<T>,
+    limit: i64,
+    cursor: impl Fn(&T) -> FriendCursor,
+) -> Option<FriendCursor> {
+    if records.len() as i64 <= limit {
+        return None;
+    }
+    records.truncate(limit as usize);
+    records.last().map(cursor)
+}
+
 impl Repository for PostgresRepository {
-    async fn add_friend_request(&self, from: &str, to: &str) -> Result<String> {
-        Ok(query!(
+    async fn add_friend_request(
+        &self,
+        from: &str,
+        to: &str,
+        push: Option<&EnqueueNotification>,
+    ) -> Result<String> {
+        let mut tx = self.pool.begin().await.map_err(|e| {
+            Error::wrap("failed to begin transaction".into(), 500, e)
+        })?;
+        let id = query!(
             r#"INSERT INTO friend_requests (id, "from", "to", status) VALUES ($1, $2, $3, 'Pending') 
-	    ON CONFLICT ("from", "to") DO UPDATE SET status = 'Pending'
+	    ON CONFLICT ("from", "to") DO UPDATE SET status = 'Pending', created_at = CURRENT_TIMESTAMP
 	    RETURNING id
 	    "#,
             Uuid::new_v4().to_string(),
             from,
             to,
         )
-	.fetch_one(&self.pool)
+	.fetch_one(&mut *tx)
 	.await
-	.map_err(|e| Error::wrap("failed to insert friend request".into(), 500, e))?.id)
+	.map_err(|e| Error::wrap("failed to insert friend request".into(), 500, e))?.id;
+        if let Some(push) = push {
+            let mut push = push.clone();
+            if let Some(data) = push.data.as_object_mut() {
+                data.insert("id".into(), id.clone().into());
+            }
+            self.enqueue_with(&mut *tx, &push).await?;
+        }
+        tx.commit().await.map_err(|e| {
+            Error::wrap("failed to commit transaction".into(), 500, e)
+        })?;
+        Ok(id)
     }
     async fn get_friend_request(&self, id: &str) -> Result<FriendRequest> {
         if let Some(record) = query!(
@@ -62,17 +133,29 @@ impl Repository for PostgresRepository {
             404,
         ))
     }
-    async fn accept_friend_request(&self, id: &str) -> Result<()> {
+    async fn accept_friend_request(
+        &self,
+        id: &str,
+        push: Option<&EnqueueNotification>,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await.map_err(|e| {
+            Error::wrap("failed to begin transaction".into(), 500, e)
+        })?;
         query!(
             "UPDATE friend_requests SET status = 'Accepted' WHERE id = $1",
             id,
         )
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await
         .map_err(|e| {
             Error::wrap("failed to update friend request".into(), 500, e)
         })?;
-        Ok(())
+        if let Some(push) = push {
+            self.enqueue_with(&mut *tx, push).await?;
+        }
+        tx.commit().await.map_err(|e| {
+            Error::wrap("failed to commit transaction".into(), 500, e)
+        })
     }
     async fn reject_friend_request(&self, id: &str) -> Result<()> {
         query!(
@@ -89,76 +172,428 @@ impl Repository for PostgresRepository {
     async fn pending_friend_requests(
         &self,
         to: &str,
+        sort: FriendSort,
+        after: Option<&FriendCursor>,
+        limit: i64,
+    ) -> Result<Page<FriendRequest>> {
+        let (after_name, after_at, after_id) = cursor_parts(after);
+        let mut records = query!(
+            r#"
+            SELECT
+                r.id,
+                r."from",
+                r."to",
+                u.phone,
+                lower(COALESCE(u.display_name, u.phone)) AS "name!",
+                r.created_at AT TIME ZONE 'UTC' AS "created_at!"
+            FROM friend_requests AS r JOIN users AS u ON r."from" = u.id
+            WHERE r.status = 'Pending' AND r."to" = $1
+                AND CASE $2
+                    WHEN 'Name' THEN $3::VARCHAR IS NULL
+                        OR (lower(COALESCE(u.display_name, u.phone)), r.id) > ($3, $5)
+                    ELSE $4::TIMESTAMPTZ IS NULL
+                        OR (r.created_at AT TIME ZONE 'UTC', r.id) < ($4, $5)
+                END
+            ORDER BY
+                CASE WHEN $2 = 'Name' THEN lower(COALESCE(u.display_name, u.phone)) END,
+                CASE WHEN $2 = 'Name' THEN r.id END,
+                r.created_at DESC,
+                r.id DESC
+            LIMIT $6
+            "#,
+            to,
+            friend_sort_name(sort),
+            after_name,
+            after_at,
+            after_id,
+            limit + 1,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get friend requests".into(), 500, e)
+        })?;
+        let next = next_cursor(&mut records, limit, |record| {
+            cursor(sort, &record.name, record.created_at, &record.id)
+        });
+        Ok(Page {
+            items: records
+                .into_iter()
+                .map(|record| FriendRequest {
+                    id: record.id,
+                    from: record.from,
+                    to: record.to,
+                    status: FriendRequestStatus::Pending,
+                    phone: record.phone,
+                })
+                .collect(),
+            next,
+        })
+    }
+
+    async fn count_pending_friend_requests(&self, to: &str) -> Result<i64> {
+        query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM friend_requests
+            WHERE "to" = $1 AND status = 'Pending'"#,
+            to,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to count friend requests".into(), 500, e)
+        })
+    }
+
+    async fn outgoing_friend_requests(
+        &self,
+        from: &str,
     ) -> Result<Vec<FriendRequest>> {
         Ok(query!(
-		r#"SELECT r.id, r."from", r."to", u.phone FROM friend_requests AS r JOIN users AS u ON r."from" = u.id WHERE status = 'Pending' AND r."to" = $1"#,
-		to,
-	)
-	.fetch_all(&self.pool)
-	.await
-	.map_err(|e| Error::wrap("failed to get friend requests".into(), 500, e))?
-	.into_iter()
-	.map(|record| {
-		FriendRequest { id: record.id, from: record.from, to: record.to, status: FriendRequestStatus::Pending, phone: record.phone }
-	})
-	.collect())
+            r#"SELECT r.id, r."from", r."to", u.phone
+            FROM friend_requests AS r JOIN users AS u ON r."to" = u.id
+            WHERE r.status = 'Pending' AND r."from" = $1
+            ORDER BY r.created_at DESC"#,
+            from,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get friend requests".into(), 500, e)
+        })?
+        .into_iter()
+        .map(|record| FriendRequest {
+            id: record.id,
+            from: record.from,
+            to: record.to,
+            status: FriendRequestStatus::Pending,
+            phone: record.phone,
+        })
+        .collect())
+    }
+
+    async fn cancel_friend_request(
+        &self,
+        from: &str,
+        id: &str,
+    ) -> Result<Option<String>> {
+        query_scalar!(
+            r#"DELETE FROM friend_requests
+            WHERE id = $1 AND "from" = $2 AND status = 'Pending'
+            RETURNING "to""#,
+            id,
+            from,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to cancel friend request".into(), 500, e)
+        })
+    }
+
+    async fn expire_friend_requests(
+        &self,
+        ttl: Duration,
+        limit: i64,
+    ) -> Result<Vec<FriendRequest>> {
+        // created_at is a TIMESTAMP written as CURRENT_TIMESTAMP in the
+        // session's time zone, so it's compared with the session's
+        // LOCALTIMESTAMP rather than with now() in UTC
+        Ok(query!(
+            r#"
+            WITH expired AS (
+                DELETE FROM friend_requests
+                WHERE id IN (
+                    SELECT id FROM friend_requests
+                    WHERE status = 'Pending' AND created_at < LOCALTIMESTAMP - make_interval(secs => $1)
+                    LIMIT $2
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING id, "from", "to"
+            )
+            SELECT e.id, e."from", e."to", u.phone
+            FROM expired AS e JOIN users AS u ON e."from" = u.id
+            "#,
+            ttl.num_seconds() as f64,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to expire friend requests".into(), 500, e)
+        })?
+        .into_iter()
+        .map(|record| FriendRequest {
+            id: record.id,
+            from: record.from,
+            to: record.to,
+            status: FriendRequestStatus::Pending,
+            phone: record.phone,
+        })
+        .collect())
+    }
+
+    async fn unfriend(&self, user_id: &str, friend_id: &str) -> Result<bool> {
+        // the metadata and the conversation they had only go if the
+        // friendship did
+        query_scalar!(
+            r#"
+            WITH
+                deleted AS (
+                    DELETE FROM friend_requests
+                    WHERE (("from" = $1 AND "to" = $2) OR ("from" = $2 AND "to" = $1))
+                        AND EXISTS (
+                            SELECT 1 FROM friend_requests
+                            WHERE status = 'Accepted'
+                                AND (("from" = $1 AND "to" = $2) OR ("from" = $2 AND "to" = $1))
+                        )
+                    RETURNING status
+                ),
+                metadata AS (
+                    DELETE FROM friend_metadata
+                    WHERE ((owner_id = $1 AND friend_id = $2) OR (owner_id = $2 AND friend_id = $1))
+                        AND EXISTS (SELECT 1 FROM deleted)
+                ),
+                favorites AS (
+                    UPDATE peer_notification_settings SET favorite = false
+                    WHERE ((user_id = $1 AND peer_id = $2) OR (user_id = $2 AND peer_id = $1))
+                        AND EXISTS (SELECT 1 FROM deleted)
+                ),
+                conversation AS (
+                    DELETE FROM message_requests
+                    WHERE ((user_id = $1 AND peer_id = $2) OR (user_id = $2 AND peer_id = $1))
+                        AND EXISTS (SELECT 1 FROM deleted)
+                )
+            SELECT EXISTS (SELECT 1 FROM deleted) AS "unfriended!"
+            "#,
+            user_id,
+            friend_id,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to unfriend".into(), 500, e))
     }
 
     async fn friends(&self, user_id: &str) -> Result<Vec<User>> {
         Ok(query!(
             r#"
             SELECT
-                f.id,
-                f.phone,
-                f.avatar
-            FROM
-                (SELECT 
-                    CASE 
-                        WHEN u.fid = $1 THEN u.tid
-                        ELSE u.fid
-                    END AS id,
-                    CASE 
-                        WHEN u.fid = $1 THEN u.tphone
-                        ELSE u.fphone
-                    END AS phone,
-                    u.favatar AS avatar
-                FROM (
-                    SELECT
-                        f.id AS fid,
-                        f.phone AS fphone,
-                        f.avatar AS favatar,
-                        t.id AS tid,
-                        t.phone AS tphone
-                    FROM
-                        users AS f
-                        JOIN friend_requests AS fr ON fr."from" = f.id
-                        JOIN users AS t ON fr."to" = t.id
-                    WHERE fr.status = 'Accepted' AND (fr."from" = $1 OR fr."to" = $1)
-                ) AS u
-            ) AS f
-                LEFT JOIN messages AS m ON f.id = m."from" AND m."to" = $1 AND has_read = false
-            GROUP BY  f.id, f.phone, f.avatar
+                u.id,
+                u.phone,
+                u.avatar,
+                COALESCE(m.nickname, u.display_name) AS display_name,
+                u.bio,
+                u.updated_at
+            FROM friend_requests AS fr
+            JOIN users AS u
+                ON u.id = CASE WHEN fr."from" = $1 THEN fr."to" ELSE fr."from" END
+            LEFT JOIN friend_metadata AS m ON m.owner_id = $1 AND m.friend_id = u.id
+            WHERE fr.status = 'Accepted' AND (fr."from" = $1 OR fr."to" = $1)
             "#,
             user_id,
         )
-       .fetch_all(&self.pool)
+        .fetch_all(&self.pool)
         .await
         .map_err(|e| {
             Error::wrap("failed to get friend requests".into(), 500, e)
         })?
         .into_iter()
         .map(|record| User {
-            id: record.id.unwrap(),
-            phone: record.phone.unwrap(),
+            id: record.id,
+            phone: record.phone,
             avatar: record.avatar,
+            display_name: record.display_name,
+            bio: record.bio,
+            updated_at: record.updated_at,
             typ: Some(UserType::Friend),
         })
         .collect())
     }
 
+    async fn friends_page(
+        &self,
+        user_id: &str,
+        label: Option<&str>,
+        sort: FriendSort,
+        after: Option<&FriendCursor>,
+        limit: i64,
+    ) -> Result<Page<User>> {
+        let (after_name, after_at, after_id) = cursor_parts(after);
+        // the latest message each way is a lookup in the index
+        let mut records = query!(
+            r#"
+            WITH friends AS (
+                SELECT
+                    u.id,
+                    u.phone,
+                    u.avatar,
+                    COALESCE(m.nickname, u.display_name) AS display_name,
+                    u.bio,
+                    u.updated_at,
+                    lower(COALESCE(m.nickname, u.display_name, u.phone)) AS name,
+                    GREATEST(
+                        fr.created_at AT TIME ZONE 'UTC',
+                        (SELECT max(sent_at) FROM messages WHERE "from" = $1 AND "to" = u.id),
+                        (SELECT max(sent_at) FROM messages WHERE "from" = u.id AND "to" = $1)
+                    ) AS interacted_at
+                FROM friend_requests AS fr
+                    JOIN users AS u
+                        ON u.id = CASE WHEN fr."from" = $1 THEN fr."to" ELSE fr."from" END
+                    LEFT JOIN friend_metadata AS m ON m.owner_id = $1 AND m.friend_id = u.id
+                WHERE fr.status = 'Accepted' AND (fr."from" = $1 OR fr."to" = $1)
+                    AND ($2::VARCHAR IS NULL OR $2 = ANY(m.labels))
+            )
+            SELECT
+                id AS "id!",
+                phone AS "phone!",
+                avatar,
+                display_name,
+                bio,
+                updated_at AS "updated_at!",
+                name AS "name!",
+                interacted_at AS "interacted_at!"
+            FROM friends
+            WHERE CASE $3
+                WHEN 'Name' THEN $4::VARCHAR IS NULL OR (name, id) > ($4, $6)
+                ELSE $5::TIMESTAMPTZ IS NULL OR (interacted_at, id) < ($5, $6)
+            END
+            ORDER BY
+                CASE WHEN $3 = 'Name' THEN name END,
+                CASE WHEN $3 = 'Name' THEN id END,
+                interacted_at DESC,
+                id DESC
+            LIMIT $7
+            "#,
+            user_id,
+            label,
+            friend_sort_name(sort),
+            after_name,
+            after_at,
+            after_id,
+            limit + 1,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get friends".into(), 500, e))?;
+        let next = next_cursor(&mut records, limit, |record| {
+            cursor(sort, &record.name, record.interacted_at, &record.id)
+        });
+        Ok(Page {
+            items: records
+                .into_iter()
+                .map(|record| User {
+                    id: record.id,
+                    phone: record.phone,
+                    avatar: record.avatar,
+                    display_name: record.display_name,
+                    bio: record.bio,
+                    updated_at: record.updated_at,
+                    typ: Some(UserType::Friend),
+                })
+                .collect(),
+            next,
+        })
+    }
+
+    async fn count_friends(
+        &self,
+        user_id: &str,
+        label: Option<&str>,
+    ) -> Result<i64> {
+        query_scalar!(
+            r#"
+            SELECT count(*) AS "count!"
+            FROM friend_requests AS fr
+                LEFT JOIN friend_metadata AS m
+                    ON m.owner_id = $1
+                        AND m.friend_id = CASE WHEN fr."from" = $1 THEN fr."to" ELSE fr."from" END
+            WHERE fr.status = 'Accepted' AND (fr."from" = $1 OR fr."to" = $1)
+                AND ($2::VARCHAR IS NULL OR $2 = ANY(m.labels))
+            "#,
+            user_id,
+            label,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to count friends".into(), 500, e))
+    }
+
+    async fn friend_suggestions(
+        &self,
+        user_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<FriendSuggestion>> {
+        // Everything is narrowed down to ids first, the users are only
+        // looked up for the page.
+        Ok(query!(
+            r#"
+            WITH
+                friends AS (
+                    SELECT "to" AS id FROM friend_requests WHERE "from" = $1 AND status = 'Accepted'
+                    UNION
+                    SELECT "from" FROM friend_requests WHERE "to" = $1 AND status = 'Accepted'
+                ),
+                excluded AS (
+                    SELECT "to" AS id FROM friend_requests WHERE "from" = $1
+                    UNION ALL
+                    SELECT "from" FROM friend_requests WHERE "to" = $1
+                    UNION ALL
+                    SELECT blocked_id FROM user_blocks WHERE blocker_id = $1
+                    UNION ALL
+                    SELECT blocker_id FROM user_blocks WHERE blocked_id = $1
+                ),
+                candidates AS (
+                    SELECT r."to" AS id
+                    FROM friends AS f
+                        JOIN friend_requests AS r ON r."from" = f.id AND r.status = 'Accepted'
+                    UNION ALL
+                    SELECT r."from"
+                    FROM friends AS f
+                        JOIN friend_requests AS r ON r."to" = f.id AND r.status = 'Accepted'
+                ),
+                ranked AS (
+                    SELECT c.id, count(*) AS mutual_friends
+                    FROM candidates AS c
+                    WHERE c.id <> $1
+                        AND NOT EXISTS (SELECT 1 FROM excluded AS e WHERE e.id = c.id)
+                        AND NOT EXISTS (
+                            SELECT 1 FROM privacy_settings AS p
+                            WHERE p.user_id = c.id AND NOT p.discoverable_by_phone
+                        )
+                    GROUP BY c.id
+                    ORDER BY mutual_friends DESC, c.id
+                    LIMIT $2 OFFSET $3
+                )
+            SELECT
+                u.id,
+                u.display_name,
+                u.avatar,
+                r.mutual_friends AS "mutual_friends!"
+            FROM ranked AS r JOIN users AS u ON u.id = r.id
+            ORDER BY r.mutual_friends DESC, r.id
+            "#,
+            user_id,
+            limit,
+            offset,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get friend suggestions".into(), 500, e)
+        })?
+        .into_iter()
+        .map(|record| FriendSuggestion {
+            id: record.id,
+            display_name: record.display_name,
+            avatar: record.avatar,
+            mutual_friends: record.mutual_friends,
+        })
+        .collect())
+    }
+
     async fn is_friend(&self, user_id: &str, friend_id: &str) -> Result<bool> {
         Ok(query!(
-		r#"SELECT EXISTS(SELECT 1 FROM friend_requests WHERE status = 'Accepted' AND ("from" = $1 AND "to" = $2) OR ("from" = $2 AND "to" = $1))"#,
+		r#"SELECT EXISTS(SELECT 1 FROM friend_requests WHERE status = 'Accepted' AND (("from" = $1 AND "to" = $2) OR ("from" = $2 AND "to" = $1)))"#,
 		user_id,
 		friend_id,
 	)
@@ -177,6 +612,9 @@ impl Repository for PostgresRepository {
             u.id AS id,
             u.phone AS phone,
             u.avatar AS avatar,
+            u.display_name,
+            u.bio,
+            u.updated_at,
             CASE 
                 WHEN f.status = 'Pending' THEN 'Requested'
                 WHEN t.status = 'Pending' THEN 'Requesting'
@@ -187,7 +625,15 @@ impl Repository for PostgresRepository {
         FROM users AS u
         LEFT JOIN friend_requests AS f ON u.id = f."from" AND f."to" = $1
         LEFT JOIN friend_requests AS t ON u.id = t."to" AND t."from" = $1
-        WHERE u.phone = $2"#, 
+        LEFT JOIN privacy_settings AS p ON p.user_id = u.id
+        WHERE u.phone = $2
+            AND (
+                COALESCE(p.discoverable_by_phone, true)
+                OR u.id = $1 OR f.id IS NOT NULL OR t.id IS NOT NULL
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM user_blocks AS b WHERE b.blocker_id = u.id AND b.blocked_id = $1
+            )"#, 
         user_id,
         phone)
         .fetch_optional(&self.pool)
@@ -198,6 +644,9 @@ impl Repository for PostgresRepository {
                 id: record.id,
                 phone: record.phone,
                 avatar: record.avatar,
+                display_name: record.display_name,
+                bio: record.bio,
+                updated_at: record.updated_at,
                 typ: match record.typ.unwrap().as_ref() {
                     "Requesting" => Some(UserType::Requesting),
                     "Requested" => Some(UserType::Requested),
@@ -281,8 +730,12 @@ impl Repository for PostgresRepository {
     async fn insert_chat_message(
         &self,
         create: &InsertChatMessage,
+        push: Option<&EnqueueNotification>,
     ) -> Result<ChatMessage> {
-        query_as!(ChatMessage,
+        let mut tx = self.pool.begin().await.map_err(|e| {
+            Error::wrap("failed to begin transaction".into(), 500, e)
+        })?;
+        let message = query_as!(ChatMessage,
             r#"INSERT INTO messages (id, "from", "to", mime_type, content) VALUES ($1, $2, $3, $4, $5) RETURNING *"#,
             self.id_generator.lock().await.generate().to_string(),
             &create.from,
@@ -290,9 +743,16 @@ impl Repository for PostgresRepository {
             &create.mime_type,
             &create.content,
         )
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| Error::wrap("failed to insert message".into(), 500, e))
+        .map_err(|e| Error::wrap("failed to insert message".into(), 500, e))?;
+        if let Some(push) = push {
+            self.enqueue_with(&mut *tx, push).await?;
+        }
+        tx.commit().await.map_err(|e| {
+            Error::wrap("failed to commit transaction".into(), 500, e)
+        })?;
+        Ok(message)
     }
 
     async fn get_avatar(&self, self_id: &str) -> Result<Option<String>> {
@@ -312,7 +772,7 @@ impl Repository for PostgresRepository {
         upload_id: &str,
     ) -> Result<()> {
         query!(
-            "UPDATE users SET avatar = $1 WHERE id = $2",
+            "UPDATE users SET avatar = $1, updated_at = now() WHERE id = $2",
             upload_id,
             self_id,
         )
@@ -327,6 +787,10 @@ impl Repository for PostgresRepository {
             SELECT DISTINCT ON (peer_ids.peer_id, users.phone)
                 peer_ids.peer_id AS peer_id,
                 users.phone AS peer_phone,
+                users.avatar AS peer_avatar,
+                COALESCE(friend_metadata.nickname, users.display_name) AS peer_display_name,
+                users.bio AS peer_bio,
+                users.updated_at AS peer_updated_at,
                 SUM(CASE WHEN messages.has_read = false AND messages."from" = peer_ids.peer_id THEN 1 ELSE 0 END) OVER (PARTITION BY peer_ids.peer_id) AS unread_count,
                 FIRST_VALUE(messages.mime_type) OVER (PARTITION BY peer_ids.peer_id ORDER BY messages.id DESC) AS latest_mime_type,
                 FIRST_VALUE(messages.content) OVER (PARTITION BY peer_ids.peer_id ORDER BY messages.id DESC) AS latest_content 
@@ -336,7 +800,13 @@ impl Repository for PostgresRepository {
                 FROM messages
                 WHERE "from" = $1 OR "to" = $1) AS peer_ids
                 JOIN users ON peer_ids.peer_id = users.id
+                LEFT JOIN friend_metadata ON friend_metadata.owner_id = $1 AND friend_metadata.friend_id = peer_ids.peer_id
                 JOIN messages ON (peer_ids.peer_id = messages."from" AND messages."to" = $1) OR (peer_ids.peer_id = messages."to" AND messages."from" = $1)
+            -- pending message requests are listed apart
+            WHERE NOT EXISTS (
+                SELECT 1 FROM message_requests
+                WHERE user_id = $1 AND peer_id = peer_ids.peer_id AND status = 'Pending'
+            )
             "#, user_id).fetch_all(&self.pool)
             .await
             .map_err(|e| Error::wrap("failed to get sessions".into(), 500, e))?
@@ -344,6 +814,10 @@ impl Repository for PostgresRepository {
             .map(|record| Session {
                 peer_id: record.peer_id.unwrap(),
                 peer_phone: record.peer_phone,
+                peer_avatar: record.peer_avatar,
+                peer_display_name: record.peer_display_name,
+                peer_bio: record.peer_bio,
+                peer_updated_at: record.peer_updated_at,
                 unread_count: record.unread_count.unwrap(),
                 latest_mime_type: record.latest_mime_type,
                 latest_content: record.latest_content,
@@ -363,16 +837,52 @@ impl Repository for PostgresRepository {
 
     async fn get_user(&self, id: &str) -> Result<User> {
         query!(
-            "SELECT id, phone, avatar as typ FROM users WHERE id = $1",
+            "SELECT id, phone, avatar, display_name, bio, updated_at FROM users WHERE id = $1",
             id
         )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get user".into(), 500, e))?
+        .ok_or_else(|| Error::new("user not found".into(), 404))
+        .map(|record| User {
+            id: record.id,
+            phone: record.phone,
+            avatar: record.avatar,
+            display_name: record.display_name,
+            bio: record.bio,
+            updated_at: record.updated_at,
+            typ: None,
+        })
+    }
+
+    async fn update_profile(
+        &self,
+        id: &str,
+        update: &ProfileUpdate,
+    ) -> Result<User> {
+        query!(
+            "UPDATE users SET
+                display_name = CASE WHEN $2 THEN $3 ELSE display_name END,
+                bio = CASE WHEN $4 THEN $5 ELSE bio END,
+                updated_at = now()
+            WHERE id = $1
+            RETURNING id, phone, avatar, display_name, bio, updated_at",
+            id,
+            update.display_name.is_some(),
+            update.display_name.clone().flatten(),
+            update.bio.is_some(),
+            update.bio.clone().flatten(),
+        )
         .fetch_one(&self.pool)
         .await
-        .map_err(|e| Error::wrap("failed to get user".into(), 500, e))
+        .map_err(|e| Error::wrap("failed to update profile".into(), 500, e))
         .map(|record| User {
             id: record.id,
             phone: record.phone,
-            avatar: record.typ,
+            avatar: record.avatar,
+            display_name: record.display_name,
+            bio: record.bio,
+            updated_at: record.updated_at,
             typ: None,
         })
     }
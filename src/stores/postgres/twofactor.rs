@@ -0,0 +1,262 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::twofactor::{
+    TwoFactor, TwoFactorChallenge, TwoFactorStatus, TwoFactorStore,
+};
+use chrono::{Duration, Utc};
+use sqlx::{query, query_as, query_scalar};
+
+impl TwoFactorStore for PostgresRepository {
+    async fn two_factor(&self, user_id: &str) -> Result<Option<TwoFactor>> {
+        query_as!(
+            TwoFactor,
+            "SELECT secret, enabled FROM two_factor WHERE user_id = $1",
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get 2fa".into(), 500, e))
+    }
+
+    async fn two_factor_user(&self, phone: &str) -> Result<Option<String>> {
+        query_scalar!(
+            "SELECT u.id FROM users AS u JOIN two_factor AS t ON t.user_id = u.id
+            WHERE u.phone = $1 AND t.enabled",
+            phone,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get 2fa".into(), 500, e))
+    }
+
+    async fn two_factor_status(
+        &self,
+        user_id: &str,
+    ) -> Result<TwoFactorStatus> {
+        query_as!(
+            TwoFactorStatus,
+            r#"SELECT
+                EXISTS(SELECT 1 FROM two_factor WHERE user_id = $1 AND enabled) AS "enabled!",
+                (SELECT COUNT(*) FROM two_factor_recovery_codes WHERE user_id = $1 AND used_at IS NULL) AS "recovery_codes_left!""#,
+            user_id,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to get 2fa".into(), 500, e))
+    }
+
+    async fn save_pending_two_factor(
+        &self,
+        user_id: &str,
+        secret: &str,
+    ) -> Result<()> {
+        query!(
+            "INSERT INTO two_factor (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = $2, last_used_step = NULL, created_at = now()
+            WHERE NOT two_factor.enabled
+            RETURNING user_id",
+            user_id,
+            secret,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to save 2fa".into(), 500, e))?
+        .ok_or_else(|| Error::new("2fa is already enabled".into(), 409))?;
+        Ok(())
+    }
+
+    async fn enable_two_factor(
+        &self,
+        user_id: &str,
+        recovery_code_hashes: &[String],
+    ) -> Result<()> {
+        let map_err = |e| Error::wrap("failed to enable 2fa".into(), 500, e);
+        let mut tx = self.pool.begin().await.map_err(map_err)?;
+        query!(
+            "UPDATE two_factor SET enabled = true WHERE user_id = $1 RETURNING user_id",
+            user_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(map_err)?
+        .ok_or_else(|| Error::new("2fa enrollment not started".into(), 404))?;
+        replace_recovery_codes(&mut tx, user_id, recovery_code_hashes)
+            .await
+            .map_err(map_err)?;
+        tx.commit().await.map_err(map_err)?;
+        Ok(())
+    }
+
+    async fn disable_two_factor(&self, user_id: &str) -> Result<()> {
+        let map_err = |e| Error::wrap("failed to disable 2fa".into(), 500, e);
+        let mut tx = self.pool.begin().await.map_err(map_err)?;
+        query!("DELETE FROM two_factor WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(map_err)?;
+        query!(
+            "DELETE FROM two_factor_recovery_codes WHERE user_id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(map_err)?;
+        tx.commit().await.map_err(map_err)?;
+        Ok(())
+    }
+
+    async fn use_totp_step(&self, user_id: &str, step: i64) -> Result<bool> {
+        Ok(query!(
+            "UPDATE two_factor SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)",
+            user_id,
+            step,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::wrap("failed to check 2fa code".into(), 500, e))?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn unused_recovery_codes(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<String>> {
+        query_scalar!(
+            "SELECT code_hash FROM two_factor_recovery_codes WHERE user_id = $1 AND used_at IS NULL",
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to get recovery codes".into(), 500, e)
+        })
+    }
+
+    async fn use_recovery_code(
+        &self,
+        user_id: &str,
+        code_hash: &str,
+    ) -> Result<bool> {
+        Ok(query!(
+            "UPDATE two_factor_recovery_codes SET used_at = now()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+            user_id,
+            code_hash,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to check recovery code".into(), 500, e)
+        })?
+        .rows_affected()
+            > 0)
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        user_id: &str,
+        code_hashes: &[String],
+    ) -> Result<()> {
+        let map_err =
+            |e| Error::wrap("failed to replace recovery codes".into(), 500, e);
+        let mut tx = self.pool.begin().await.map_err(map_err)?;
+        replace_recovery_codes(&mut tx, user_id, code_hashes)
+            .await
+            .map_err(map_err)?;
+        tx.commit().await.map_err(map_err)?;
+        Ok(())
+    }
+
+    async fn create_two_factor_challenge(
+        &self,
+        challenge_hash: &str,
+        challenge: &TwoFactorChallenge,
+        ttl: Duration,
+    ) -> Result<()> {
+        query!(
+            "INSERT INTO two_factor_challenges (challenge_hash, user_id, device_name, platform, expires_at)
+            VALUES ($1, $2, $3, $4, $5)",
+            challenge_hash,
+            challenge.user_id,
+            challenge.device_name,
+            challenge.platform,
+            Utc::now() + ttl,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to create 2fa challenge".into(), 500, e)
+        })?;
+        Ok(())
+    }
+
+    async fn attempt_two_factor_challenge(
+        &self,
+        challenge_hash: &str,
+        max_attempts: i32,
+    ) -> Result<TwoFactorChallenge> {
+        let record = query!(
+            "UPDATE two_factor_challenges AS c SET attempts = attempts + 1
+            FROM users AS u
+            WHERE c.challenge_hash = $1 AND c.expires_at > now() AND u.id = c.user_id
+            RETURNING c.user_id, u.phone, c.device_name, c.platform, c.attempts",
+            challenge_hash,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to check 2fa challenge".into(), 500, e)
+        })?
+        .ok_or_else(|| Error::new("invalid 2fa challenge".into(), 401))?;
+        if record.attempts > max_attempts {
+            return Err(Error::new("too many 2fa attempts".into(), 429));
+        }
+        Ok(TwoFactorChallenge {
+            user_id: record.user_id,
+            phone: record.phone,
+            device_name: record.device_name,
+            platform: record.platform,
+        })
+    }
+
+    async fn remove_two_factor_challenge(
+        &self,
+        challenge_hash: &str,
+    ) -> Result<()> {
+        // expired challenges go along
+        query!(
+            "DELETE FROM two_factor_challenges WHERE challenge_hash = $1 OR expires_at < now()",
+            challenge_hash,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to remove 2fa challenge".into(), 500, e)
+        })?;
+        Ok(())
+    }
+}
+
+async fn replace_recovery_codes(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: &str,
+    code_hashes: &[String],
+) -> sqlx::Result<()> {
+    query!(
+        "DELETE FROM two_factor_recovery_codes WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    query!(
+        "INSERT INTO two_factor_recovery_codes (user_id, code_hash) SELECT $1, * FROM UNNEST($2::VARCHAR[])",
+        user_id,
+        code_hashes,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use super::PostgresRepository;
+use crate::core::error::{Error, Result};
+use crate::core::verification::{VerificationPurpose, VerificationStore};
+use chrono::{Duration, Utc};
+use sqlx::query;
+
+impl VerificationStore for PostgresRepository {
+    async fn save_code(
+        &self,
+        phone: &str,
+        purpose: VerificationPurpose,
+        code_hash: &str,
+        ttl: Duration,
+        cooldown: Duration,
+    ) -> Result<()> {
+        let now = Utc::now();
+        query!(
+            "INSERT INTO phone_verifications (phone, purpose, code_hash, sent_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (phone, purpose) DO UPDATE
+            SET code_hash = $3, attempts = 0, sent_at = $4, expires_at = $5
+            WHERE phone_verifications.sent_at <= $6
+            RETURNING phone",
+            phone,
+            purpose.as_str(),
+            code_hash,
+            now,
+            now + ttl,
+            now - cooldown,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to save verification code".into(), 500, e)
+        })?
+        .ok_or_else(|| {
+            Error::new("verification code was sent recently".into(), 429)
+        })?;
+        Ok(())
+    }
+
+    async fn consume_code(
+        &self,
+        phone: &str,
+        purpose: VerificationPurpose,
+        code_hash: &str,
+        max_attempts: i32,
+    ) -> Result<()> {
+        let record = query!(
+            "UPDATE phone_verifications SET attempts = attempts + 1
+            WHERE phone = $1 AND purpose = $2
+            RETURNING code_hash, attempts, expires_at",
+            phone,
+            purpose.as_str(),
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to check verification code".into(), 500, e)
+        })?
+        .ok_or_else(|| {
+            Error::new("no verification code was requested".into(), 422)
+        })?;
+        if record.attempts > max_attempts {
+            return Err(Error::new(
+                "too many verification attempts".into(),
+                429,
+            ));
+        }
+        if record.expires_at <= Utc::now() {
+            return Err(Error::new("verification code expired".into(), 422));
+        }
+        if record.code_hash != code_hash {
+            return Err(Error::new("invalid verification code".into(), 422));
+        }
+        // the code may have been consumed concurrently
+        query!(
+            "DELETE FROM phone_verifications WHERE phone = $1 AND purpose = $2 AND code_hash = $3 RETURNING phone",
+            phone,
+            purpose.as_str(),
+            code_hash,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            Error::wrap("failed to consume verification code".into(), 500, e)
+        })?
+        .ok_or_else(|| Error::new("invalid verification code".into(), 422))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+/// Seconds each code is valid for.
+const PERIOD: i64 = 30;
+const DIGITS: u32 = 6;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A shared secret for time-based one-time passwords (RFC 6238) with the
+/// parameters authenticator apps assume: SHA-1, 6 digits, 30 seconds.
+#[derive(Debug, Clone)]
+pub(crate) struct Totp {
+    secret: Vec<u8>,
+}
+
+impl Totp {
+    pub(crate) fn generate() -> Self {
+        let mut secret = vec![0; 20];
+        OsRng.fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    /// Parses a secret in the unpadded base32 form `encoded` returns.
+    pub(crate) fn from_encoded(encoded: &str) -> Option<Self> {
+        let mut secret = Vec::with_capacity(encoded.len() * 5 / 8);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in encoded.bytes() {
+            let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+            buffer = (buffer << 5) | value;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                secret.push((buffer >> bits) as u8);
+            }
+        }
+        Some(Self { secret })
+    }
+
+    pub(crate) fn encoded(&self) -> String {
+        let mut encoded =
+            String::with_capacity((self.secret.len() * 8).div_ceil(5));
+        let (mut buffer, mut bits) = (0u32, 0);
+        for byte in &self.secret {
+            buffer = (buffer << 8) | *byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(
+                    BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char,
+                );
+            }
+        }
+        if bits > 0 {
+            encoded.push(
+                BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char,
+            );
+        }
+        encoded
+    }
+
+    /// URI for authenticator apps to scan, usually shown as a QR code.
+    pub(crate) fn uri(&self, issuer: &str, account: &str) -> String {
+        format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={PERIOD}",
+            issuer = percent_encode(issuer),
+            account = percent_encode(account),
+            secret = self.encoded(),
+        )
+    }
+
+    /// The time step `unix_time` falls in.
+    pub(crate) fn step(unix_time: i64) -> i64 {
+        unix_time.div_euclid(PERIOD)
+    }
+
+    pub(crate) fn code(&self, step: i64) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret)
+            .expect("hmac takes keys of any size");
+        mac.update(&step.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+        let offset = (digest[digest.len() - 1] & 0xf) as usize;
+        let binary = u32::from_be_bytes([
+            digest[offset] & 0x7f,
+            digest[offset + 1],
+            digest[offset + 2],
+            digest[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(DIGITS),
+            width = DIGITS as usize
+        )
+    }
+
+    /// Finds the step `code` was made for, allowing for one step of clock
+    /// drift either way. Every step is compared in constant time.
+    pub(crate) fn verify(&self, code: &str, unix_time: i64) -> Option<i64> {
+        let step = Self::step(unix_time);
+        (step - 1..=step + 1).fold(None, |found, s| {
+            let matches = self.code(s).as_bytes().ct_eq(code.as_bytes());
+            if bool::from(matches) {
+                Some(s)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+/// A single-use code to get past 2FA without the authenticator, like
+/// "k3j9x-2mf8q".
+pub(crate) fn generate_recovery_code() -> String {
+    let mut bytes = [0; 10];
+    OsRng.fill_bytes(&mut bytes);
+    let code: String = bytes
+        .iter()
+        .map(|b| {
+            BASE32_ALPHABET[(b & 31) as usize].to_ascii_lowercase() as char
+        })
+        .collect();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Identifies a login waiting for its second factor, 256 random bits in
+/// hex.
+pub(crate) fn generate_challenge_id() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let totp = Totp {
+            secret: b"12345678901234567890".to_vec(),
+        };
+        assert_eq!(totp.encoded(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        // the RFC lists 8 digit codes, these are their last 6 digits
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(totp.code(Totp::step(time)), code);
+        }
+        assert_eq!(totp.verify("287082", 59 + PERIOD), Some(1));
+        assert_eq!(totp.verify("287082", 59 + 2 * PERIOD), None);
+    }
+
+    #[test]
+    fn test_encoding_round_trip() {
+        let totp = Totp::generate();
+        let decoded = Totp::from_encoded(&totp.encoded()).unwrap();
+        assert_eq!(decoded.secret, totp.secret);
+        assert!(Totp::from_encoded("not base32!").is_none());
+        assert!(totp
+            .uri("Signal", "+8613812345678")
+            .starts_with("otpauth://totp/Signal:%2B8613812345678?secret="));
+    }
+
+    #[test]
+    fn test_challenge_ids_are_random() {
+        let id = generate_challenge_id();
+        assert_eq!(id.len(), 64);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(id, generate_challenge_id());
+    }
+}
@@ -1,9 +1,19 @@
 use actix_web::{
+    dev::ServiceRequest,
     error::{ErrorInternalServerError, ErrorUnauthorized},
     FromRequest,
 };
+use sha2::{Digest, Sha256};
 use std::future::ready;
 
+/// Lowercase hex SHA-256 digest of `s`.
+pub(crate) fn sha256_hex(s: &str) -> String {
+    Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 #[derive(Debug)]
 pub(crate) struct UserID(pub(crate) String);
 
@@ -29,3 +39,73 @@ impl FromRequest for UserID {
         )
     }
 }
+
+/// The token the caller authenticated with.
+#[derive(Debug)]
+pub(crate) struct AuthToken(pub(crate) String);
+
+impl FromRequest for AuthToken {
+    type Error = actix_web::Error;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    #[inline]
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        ready(
+            req.headers()
+                .get("X-Auth-Token")
+                .ok_or(ErrorUnauthorized("no auth token header"))
+                .and_then(|s| {
+                    s.to_str()
+                        .map_err(ErrorInternalServerError)
+                        .map(|s| AuthToken(s.to_owned()))
+                }),
+        )
+    }
+}
+
+/// Query parameters whose values are kept out of the access log.
+const SECRET_PARAMS: [&str; 4] =
+    ["auth_token", "token", "refresh_token", "ticket"];
+
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name) => {
+                format!("{}=[REDACTED]", name)
+            }
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The request line for the access log, with secrets in the query redacted.
+pub(crate) fn redacted_request_line(req: &ServiceRequest) -> String {
+    let query = req.query_string();
+    format!(
+        "{} {}{}{} {:?}",
+        req.method(),
+        req.path(),
+        if query.is_empty() { "" } else { "?" },
+        redact_query(query),
+        req.version(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact_query;
+
+    #[test]
+    fn test_redact_query() {
+        assert_eq!(
+            redact_query("ticket=abc&limit=10&auth_token=x.y.z"),
+            "ticket=[REDACTED]&limit=10&auth_token=[REDACTED]"
+        );
+        assert_eq!(redact_query("q=ticket"), "q=ticket");
+    }
+}
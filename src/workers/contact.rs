@@ -0,0 +1,57 @@
+use crate::core::{contact::ContactStore, error::Result};
+use actix_web::rt::time::sleep;
+use chrono::{Duration, Utc};
+use log::{error, info};
+use std::time::Duration as StdDuration;
+use uuid::Uuid;
+
+/// Rotates the salt of the contact sync every `rotation`. A salt stays
+/// valid for another rotation, so that clients which fetched it just before
+/// can still sync.
+#[derive(Debug, Clone)]
+pub(crate) struct ContactSaltWorker<C> {
+    store: C,
+    rotation: Duration,
+    poll_interval: StdDuration,
+}
+
+impl<C> ContactSaltWorker<C>
+where
+    C: ContactStore,
+{
+    pub(crate) fn new(store: C, rotation: Duration) -> Self {
+        Self {
+            store,
+            rotation,
+            poll_interval: StdDuration::from_secs(60),
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        loop {
+            if let Err(e) = self.rotate().await {
+                error!("failed to rotate contact salt: {}", e);
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Drops the expired salts and adds a new one if the current one is due.
+    /// Returns whether it added one.
+    pub(crate) async fn rotate(&self) -> Result<bool> {
+        self.store.expire_contact_salts().await?;
+        if let Some(salt) = self.store.current_contact_salt().await? {
+            if salt.created_at > Utc::now() - self.rotation {
+                return Ok(false);
+            }
+        }
+        let salt =
+            format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let salt = self
+            .store
+            .add_contact_salt(&salt, self.rotation * 2)
+            .await?;
+        info!("rotated contact salt to {}", salt.id);
+        Ok(true)
+    }
+}
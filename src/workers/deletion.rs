@@ -0,0 +1,95 @@
+use crate::core::{
+    deletion::AccountDeletionStore,
+    error::Result,
+    message::{Message, SystemMessage},
+    notifier::Notifier,
+    repository::AddrStore,
+};
+use actix_web::rt::time::sleep;
+use log::{error, info, warn};
+use std::io::ErrorKind;
+use std::time::Duration as StdDuration;
+
+/// Deletes the accounts whose grace period is over: their rows, their
+/// uploaded files and data exports, their push token and their live
+/// connection.
+#[derive(Debug, Clone)]
+pub(crate) struct AccountDeletionWorker<D, N, S> {
+    store: D,
+    notifier: N,
+    addrs: S,
+    batch_size: i64,
+    poll_interval: StdDuration,
+}
+
+impl<D, N, S> AccountDeletionWorker<D, N, S>
+where
+    D: AccountDeletionStore,
+    N: Notifier,
+    S: AddrStore,
+{
+    pub(crate) fn new(store: D, notifier: N, addrs: S) -> Self {
+        Self {
+            store,
+            notifier,
+            addrs,
+            batch_size: 16,
+            poll_interval: StdDuration::from_secs(60),
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        loop {
+            match self.delete_due().await {
+                Ok(n) if n as i64 == self.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => error!("failed to delete accounts: {}", e),
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Deletes one batch of due accounts and returns how many went. An
+    /// account that fails is left for the next batch, the rest go ahead.
+    pub(crate) async fn delete_due(&self) -> Result<usize> {
+        let user_ids =
+            self.store.due_account_deletions(self.batch_size).await?;
+        let mut deleted = 0;
+        for user_id in &user_ids {
+            match self.delete(user_id).await {
+                Ok(()) => {
+                    info!("deleted account {}", user_id);
+                    deleted += 1;
+                }
+                Err(e) => error!("failed to delete account {}: {}", user_id, e),
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn delete(&self, user_id: &str) -> Result<()> {
+        if let Some(token) = self.notifier.get_token(user_id).await? {
+            self.notifier.remove_token(&token).await?;
+        }
+        let files = self.store.delete_account(user_id).await?;
+        for file in files {
+            match actix_web::rt::task::spawn_blocking(move || {
+                std::fs::remove_file(&file).map_err(|e| (file, e))
+            })
+            .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err((_, e))) if e.kind() == ErrorKind::NotFound => {}
+                Ok(Err((file, e))) => {
+                    warn!("failed to remove file {}: {}", file, e)
+                }
+                Err(e) => warn!("failed to remove file: {}", e),
+            }
+        }
+        if let Some(addr) = self.addrs.get_addr(user_id).await? {
+            addr.do_send(Message::System(SystemMessage::AccountDeleted));
+            self.addrs.remove_addr(user_id).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use crate::core::{
+    error::{Error, Result},
+    export::{ClaimedDataExport, DataExportStore, UserData},
+};
+use actix_web::rt::{task::spawn_blocking, time::sleep};
+use chrono::Duration;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration as StdDuration;
+use zip::{
+    result::ZipResult, write::FileOptions, CompressionMethod, ZipWriter,
+};
+
+/// Builds the archives of the requested data exports and removes them once
+/// they expire.
+#[derive(Debug, Clone)]
+pub(crate) struct DataExportWorker<X> {
+    store: X,
+    dir: PathBuf,
+    ttl: Duration,
+    lease: Duration,
+    poll_interval: StdDuration,
+}
+
+impl<X> DataExportWorker<X>
+where
+    X: DataExportStore,
+{
+    /// Archives are written into `dir` and kept for `ttl`.
+    pub(crate) fn new(store: X, dir: PathBuf, ttl: Duration) -> Self {
+        Self {
+            store,
+            dir,
+            ttl,
+            lease: Duration::minutes(30),
+            poll_interval: StdDuration::from_secs(5),
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        loop {
+            match self.export_next().await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => error!("failed to export user data: {}", e),
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Removes the expired archives and builds the archive of the oldest
+    /// requested export. Returns whether there was one.
+    pub(crate) async fn export_next(&self) -> Result<bool> {
+        for archive in self.store.expire_data_exports().await? {
+            match spawn_blocking(move || fs::remove_file(archive)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) if e.kind() == ErrorKind::NotFound => {}
+                Ok(Err(e)) => warn!("failed to remove data export: {}", e),
+                Err(e) => warn!("failed to remove data export: {}", e),
+            }
+        }
+        let Some(export) = self.store.claim_data_export(self.lease).await?
+        else {
+            return Ok(false);
+        };
+        match self.export(&export).await {
+            Ok(archive) => {
+                self.store
+                    .complete_data_export(&export.id, &archive, self.ttl)
+                    .await?;
+                info!("exported data of user {}", export.user_id);
+            }
+            Err(e) => {
+                error!("failed to export data of {}: {}", export.user_id, e);
+                self.store
+                    .fail_data_export(&export.id, &e.to_string(), self.ttl)
+                    .await?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn export(&self, export: &ClaimedDataExport) -> Result<String> {
+        let data = self.store.user_data(&export.user_id).await?;
+        let path = self.dir.join(format!("{}.zip", export.id));
+        let archive = path.to_string_lossy().into_owned();
+        spawn_blocking(move || write_archive(&path, &data))
+            .await
+            .map_err(|e| {
+                Error::wrap("failed to write data export".into(), 500, e)
+            })?
+            .map_err(|e| {
+                Error::wrap("failed to write data export".into(), 500, e)
+            })?;
+        Ok(archive)
+    }
+}
+
+fn write_json<T: Serialize>(
+    zip: &mut ZipWriter<File>,
+    name: &str,
+    value: &T,
+    options: FileOptions,
+) -> ZipResult<()> {
+    zip.start_file(name, options)?;
+    serde_json::to_writer_pretty(&mut *zip, value).map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// Writes the archive next to `path` first and moves it into place when it
+/// is complete, so that a half written archive is never handed out.
+fn write_archive(path: &Path, data: &UserData) -> ZipResult<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("zip.part");
+    let mut zip = ZipWriter::new(File::create(&partial)?);
+    let options =
+        FileOptions::default().compression_method(CompressionMethod::Deflated);
+    write_json(&mut zip, "profile.json", &data.profile, options)?;
+    write_json(&mut zip, "friends.json", &data.friends, options)?;
+    write_json(
+        &mut zip,
+        "friend_requests.json",
+        &data.friend_requests,
+        options,
+    )?;
+    write_json(&mut zip, "messages.json", &data.messages, options)?;
+    write_json(&mut zip, "uploads.json", &data.uploads, options)?;
+    for upload in &data.uploads {
+        let mut file = match File::open(&upload.filepath) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!("uploaded file {} is missing", upload.filepath);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // the id keeps files with the same name apart
+        let filename = upload.filename.replace(['/', '\\'], "_");
+        zip.start_file(format!("uploads/{}_{}", upload.id, filename), options)?;
+        io::copy(&mut file, &mut zip)?;
+    }
+    zip.finish()?.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::export::{DataExportStatus, ExportedProfile, ExportedUpload},
+        handlers::tests::test_pool,
+        stores::postgres::PostgresRepository,
+    };
+    use chrono::Utc;
+    use serde_json::Value;
+    use sqlx::query;
+    use std::io::Read;
+    use uuid::Uuid;
+    use zip::ZipArchive;
+
+    fn upload(dir: &Path, filename: &str) -> ExportedUpload {
+        let id = Uuid::new_v4().to_string();
+        ExportedUpload {
+            filepath: dir.join(&id).to_string_lossy().into_owned(),
+            id,
+            filename: filename.into(),
+            mime_type: "text/plain".into(),
+            uploaded_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_write_archive() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let kept = upload(&dir, "../notes.txt");
+        fs::write(&kept.filepath, "hello").unwrap();
+        let missing = upload(&dir, "gone.txt");
+        let data = UserData {
+            profile: ExportedProfile {
+                id: "1".into(),
+                phone: "+8613812345678".into(),
+                avatar: None,
+                display_name: Some("Alice".into()),
+                bio: None,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            },
+            friends: Vec::new(),
+            friend_requests: Vec::new(),
+            messages: Vec::new(),
+            uploads: vec![kept.clone(), missing],
+        };
+        let path = dir.join("exports").join("1.zip");
+
+        write_archive(&path, &data).unwrap();
+        assert!(!path.with_extension("zip.part").exists());
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut names: Vec<_> = zip.file_names().map(str::to_owned).collect();
+        names.sort();
+        // a missing upload is skipped, the name can't climb out of uploads/
+        assert_eq!(
+            names,
+            [
+                "friend_requests.json".to_owned(),
+                "friends.json".to_owned(),
+                "messages.json".to_owned(),
+                "profile.json".to_owned(),
+                "uploads.json".to_owned(),
+                format!("uploads/{}_.._notes.txt", kept.id),
+            ]
+        );
+        let profile: Value =
+            serde_json::from_reader(zip.by_name("profile.json").unwrap())
+                .unwrap();
+        assert_eq!(profile["phone"], "+8613812345678");
+        assert_eq!(profile["display_name"], "Alice");
+        let uploads: Value =
+            serde_json::from_reader(zip.by_name("uploads.json").unwrap())
+                .unwrap();
+        assert_eq!(uploads.as_array().unwrap().len(), 2);
+        assert!(uploads[0].get("filepath").is_none());
+        let mut content = String::new();
+        zip.by_name(&format!("uploads/{}_.._notes.txt", kept.id))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "hello");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_claim_lease_and_expiry() {
+        let Some(pool) = test_pool().await else {
+            return;
+        };
+        let store = PostgresRepository::new(pool.clone());
+        let user_id = Uuid::new_v4().to_string();
+        let lease = Duration::minutes(30);
+        let requested = store.request_data_export(&user_id).await.unwrap();
+        // ahead of anything else queued in the database
+        query!(
+            "UPDATE data_exports SET requested_at = 'epoch' WHERE id = $1",
+            requested.id,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let claimed = store.claim_data_export(lease).await.unwrap().unwrap();
+        assert_eq!(claimed.id, requested.id);
+        assert_eq!(claimed.user_id, user_id);
+        // while it runs, asking again gives the same export
+        let again = store.request_data_export(&user_id).await.unwrap();
+        assert_eq!(again.id, requested.id);
+        assert_eq!(again.status, DataExportStatus::Running);
+
+        // the worker is assumed dead once the lease is over
+        query!(
+            "UPDATE data_exports SET claimed_at = now() - INTERVAL '1 hour' WHERE id = $1",
+            requested.id,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let reclaimed = store.claim_data_export(lease).await.unwrap().unwrap();
+        assert_eq!(reclaimed.id, requested.id);
+
+        store
+            .fail_data_export(&requested.id, "boom", Duration::days(7))
+            .await
+            .unwrap();
+        let failed = store
+            .data_export(&user_id, &requested.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(failed.status, DataExportStatus::Failed);
+        assert!(failed.expires_at.unwrap() > Utc::now() + Duration::days(6));
+        let next = store.request_data_export(&user_id).await.unwrap();
+        assert_ne!(next.id, requested.id);
+
+        query!(
+            "UPDATE data_exports SET expires_at = now() WHERE id = $1",
+            requested.id,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        store.expire_data_exports().await.unwrap();
+        assert!(store
+            .data_export(&user_id, &requested.id)
+            .await
+            .unwrap()
+            .is_none());
+        query!("DELETE FROM data_exports WHERE user_id = $1", user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
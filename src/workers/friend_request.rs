@@ -0,0 +1,70 @@
+use crate::core::{
+    error::Result,
+    message::{Message, SystemMessage},
+    repository::{AddrStore, Repository},
+};
+use actix_web::rt::time::sleep;
+use chrono::Duration;
+use log::{error, info};
+use std::time::Duration as StdDuration;
+
+/// Removes the friend requests that went unanswered for longer than `ttl`
+/// and tells both sides if they are online.
+#[derive(Debug, Clone)]
+pub(crate) struct FriendRequestExpiryWorker<R, S> {
+    repo: R,
+    addrs: S,
+    ttl: Duration,
+    batch_size: i64,
+    poll_interval: StdDuration,
+}
+
+impl<R, S> FriendRequestExpiryWorker<R, S>
+where
+    R: Repository,
+    S: AddrStore,
+{
+    pub(crate) fn new(repo: R, addrs: S, ttl: Duration) -> Self {
+        Self {
+            repo,
+            addrs,
+            ttl,
+            batch_size: 100,
+            poll_interval: StdDuration::from_secs(10 * 60),
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        loop {
+            match self.expire().await {
+                Ok(n) if n as i64 == self.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => error!("failed to expire friend requests: {}", e),
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Expires one batch of friend requests and returns its size.
+    pub(crate) async fn expire(&self) -> Result<usize> {
+        let requests = self
+            .repo
+            .expire_friend_requests(self.ttl, self.batch_size)
+            .await?;
+        for request in &requests {
+            for user_id in [&request.from, &request.to] {
+                if let Some(addr) = self.addrs.get_addr(user_id).await? {
+                    addr.do_send(Message::System(
+                        SystemMessage::FriendRequestExpired {
+                            id: request.id.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+        if !requests.is_empty() {
+            info!("expired {} friend requests", requests.len());
+        }
+        Ok(requests.len())
+    }
+}
@@ -0,0 +1,5 @@
+pub(crate) mod contact;
+pub(crate) mod deletion;
+pub(crate) mod export;
+pub(crate) mod friend_request;
+pub(crate) mod outbox;
@@ -0,0 +1,154 @@
+use crate::core::{
+    error::Result,
+    notifier::{Notifier, PushNotification, INVALID_TOKEN_STATUS},
+    outbox::{NotificationOutbox, OutboxNotification},
+};
+use actix_web::rt::time::sleep;
+use chrono::{Duration, Utc};
+use log::{error, info, warn};
+use std::time::Duration as StdDuration;
+
+/// Delivers the notifications written to the outbox through the notifier.
+///
+/// Transient failures are retried with exponential backoff until
+/// `max_attempts` is reached, permanent failures go to the dead letters
+/// right away.
+#[derive(Debug, Clone)]
+pub(crate) struct OutboxWorker<O, N> {
+    outbox: O,
+    notifier: N,
+    batch_size: i64,
+    max_attempts: i32,
+    base_backoff: Duration,
+    max_backoff: Duration,
+    lease: Duration,
+    poll_interval: StdDuration,
+}
+
+impl<O, N> OutboxWorker<O, N>
+where
+    O: NotificationOutbox,
+    N: Notifier,
+{
+    pub(crate) fn new(outbox: O, notifier: N) -> Self {
+        Self {
+            outbox,
+            notifier,
+            batch_size: 32,
+            max_attempts: 8,
+            base_backoff: Duration::seconds(5),
+            max_backoff: Duration::minutes(30),
+            lease: Duration::minutes(1),
+            poll_interval: StdDuration::from_secs(1),
+        }
+    }
+
+    pub(crate) async fn run(self) {
+        loop {
+            match self.deliver_due().await {
+                // keep draining while there is a backlog
+                Ok(n) if n as i64 == self.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => error!("failed to deliver notifications: {}", e),
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Delivers one batch of due notifications and returns its size.
+    pub(crate) async fn deliver_due(&self) -> Result<usize> {
+        let expired = self.outbox.discard_expired().await?;
+        if expired > 0 {
+            info!("discarded {} expired notifications", expired);
+        }
+        let notifications = self
+            .outbox
+            .claim_due(self.batch_size, self.lease)
+            .await?;
+        for notification in &notifications {
+            // one failure must not hold up the rest of the batch until the
+            // lease runs out
+            if let Err(e) = self.deliver(notification).await {
+                error!(
+                    "failed to deliver notification {}: {}",
+                    notification.id, e
+                );
+                if let Err(e) = self.retry(notification, &e.to_string()).await {
+                    error!(
+                        "failed to reschedule notification {}: {}",
+                        notification.id, e
+                    );
+                }
+            }
+        }
+        Ok(notifications.len())
+    }
+
+    async fn deliver(&self, notification: &OutboxNotification) -> Result<()> {
+        let token = match self.notifier.get_token(&notification.user_id).await
+        {
+            Ok(Some(token)) => token,
+            Ok(None) => return self.outbox.remove(&notification.id).await,
+            Err(e) => return self.retry(notification, &e.to_string()).await,
+        };
+        match self
+            .notifier
+            .send_notification(
+                &token,
+                &PushNotification {
+                    title: notification.title.clone(),
+                    body: notification.body.clone(),
+                    collapse_key: notification.collapse_key.clone(),
+                    thread_id: notification.thread_id.clone(),
+                },
+                &notification.data,
+            )
+            .await
+        {
+            Ok(()) => self.outbox.remove(&notification.id).await,
+            Err(e) if e.status_code == INVALID_TOKEN_STATUS => {
+                info!(
+                    "removed invalid push token of user {}: {}",
+                    notification.user_id, e
+                );
+                self.outbox.remove(&notification.id).await
+            }
+            Err(e) if e.is_transient() => {
+                self.retry(notification, &e.to_string()).await
+            }
+            Err(e) => {
+                error!(
+                    "failed to push notification {}: {}",
+                    notification.id, e
+                );
+                self.outbox.mark_dead(&notification.id, &e.to_string()).await
+            }
+        }
+    }
+
+    async fn retry(
+        &self,
+        notification: &OutboxNotification,
+        reason: &str,
+    ) -> Result<()> {
+        if notification.attempts >= self.max_attempts {
+            warn!(
+                "giving up on notification {} after {} attempts: {}",
+                notification.id, notification.attempts, reason
+            );
+            return self.outbox.mark_dead(&notification.id, reason).await;
+        }
+        let next_attempt_at = Utc::now() + self.backoff(notification.attempts);
+        if next_attempt_at >= notification.expires_at {
+            return self.outbox.remove(&notification.id).await;
+        }
+        self.outbox
+            .reschedule(&notification.id, next_attempt_at, reason)
+            .await
+    }
+
+    fn backoff(&self, attempts: i32) -> Duration {
+        let factor = 1 << (attempts - 1).clamp(0, 16);
+        (self.base_backoff * factor).min(self.max_backoff)
+    }
+}
@@ -1,11 +1,12 @@
 use actix::{ActorContext, Handler};
 use actix_web::{
-    error::{ErrorForbidden, ErrorInternalServerError},
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
+    http::header::SEC_WEBSOCKET_PROTOCOL,
     web::Data,
     web::{Payload, Query},
     Error, HttpRequest, HttpResponse,
 };
-use log::error;
+use log::{error, warn};
 use serde::Deserialize;
 
 use actix::{Actor, Addr, AsyncContext, StreamHandler};
@@ -20,11 +21,13 @@ use serde_json::{from_str, to_string};
 
 use crate::{
     core::{
-        message::Message,
+        message::{Message, SystemMessage},
         notifier::Notifier,
         repository::{AddrStore, Repository},
+        session::WsTicketStore,
     },
     stores::addr::AddrMap,
+    utils::sha256_hex,
 };
 
 pub struct WS;
@@ -65,36 +68,101 @@ impl Handler<Message> for WS {
                 error!("failed to serialize message: {}", e);
             }
         }
+        if let Message::System(SystemMessage::AccountDeleted) = msg {
+            ctx.close(Some(ws::CloseCode::Policy.into()));
+            ctx.stop();
+        }
     }
 }
 
+/// Subprotocol the server speaks, echoed back on the upgrade.
+pub(crate) const PROTOCOL: &str = "chat";
+
+/// Browsers can't set headers on the upgrade request, so they may offer the
+/// token as a subprotocol: `new WebSocket(url, ["chat", "auth." + token])`.
+const AUTH_PROTOCOL_PREFIX: &str = "auth.";
+
+/// Whether the token is still accepted in the `auth_token` query parameter.
+/// Tokens in URLs end up in logs and proxies, the parameter is deprecated
+/// in favor of the header, the subprotocol and tickets and will go away once
+/// clients stopped using it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QueryTokenAuth(pub(crate) bool);
+
 #[derive(Deserialize)]
 pub(crate) struct Index {
-    pub(crate) auth_token: String,
+    /// One-time ticket from `POST /apis/v1/ws_tickets`.
+    pub(crate) ticket: Option<String>,
+    /// Deprecated, see `QueryTokenAuth`.
+    pub(crate) auth_token: Option<String>,
+}
+
+fn auth_token(req: &HttpRequest) -> Option<&str> {
+    if let Some(token) = req.headers().get("X-Auth-Token") {
+        return token.to_str().ok();
+    }
+    req.headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|p| p.trim().strip_prefix(AUTH_PROTOCOL_PREFIX))
 }
 
-pub(crate) async fn index<R, H, T, F, N, S>(
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn index<R, H, T, W, F, N, S>(
     req: HttpRequest,
     stream: Payload,
     addrs: Data<AddrMap>,
     auth_service: Data<AuthService<R, H, T>>,
+    tickets: Data<W>,
     friends_stores: Data<F>,
     notifier: Data<N>,
-    Query(Index { auth_token }): Query<Index>,
+    query_token_auth: Data<QueryTokenAuth>,
+    Query(Index {
+        ticket,
+        auth_token: query_token,
+    }): Query<Index>,
 ) -> Result<HttpResponse, Error>
 where
     R: AuthRepository + Clone + 'static,
     H: Hasher + Clone + 'static,
     T: TokenManager + Clone + 'static,
+    W: WsTicketStore,
     F: Repository + Clone + Unpin + 'static,
     N: Notifier + Clone + Unpin + 'static,
     S: AddrStore + Clone + Unpin + 'static,
 {
-    let user_id = auth_service
-        .verify_token(&auth_token)
-        .await
-        .map_err(ErrorForbidden)?;
-    let (addr, resp) = ws::start_with_addr(WS::new(), &req, stream)
+    let token = match (auth_token(&req), query_token.as_deref()) {
+        (Some(token), _) => Some(token),
+        (None, Some(token)) if ticket.is_none() => {
+            if !query_token_auth.0 {
+                return Err(ErrorUnauthorized(
+                    "auth_token query parameter is no longer supported",
+                ));
+            }
+            warn!(
+                "websocket authenticated by the deprecated auth_token query parameter from {}",
+                req.connection_info().peer_addr().unwrap_or("unknown")
+            );
+            Some(token)
+        }
+        _ => None,
+    };
+    let user_id = match (ticket, token) {
+        (Some(ticket), _) => tickets
+            .redeem_ws_ticket(&sha256_hex(&ticket))
+            .await
+            .map_err(ErrorInternalServerError)?
+            .ok_or(ErrorForbidden("invalid ws ticket"))?,
+        (None, Some(token)) => auth_service
+            .verify_token(token)
+            .await
+            .map_err(ErrorForbidden)?,
+        (None, None) => return Err(ErrorUnauthorized("no auth token")),
+    };
+    let (addr, resp) = ws::WsResponseBuilder::new(WS::new(), &req, stream)
+        .protocols(&[PROTOCOL])
+        .start_with_addr()
         .map_err(ErrorInternalServerError)?;
     addrs
         .add_addr(&user_id, addr.recipient())